@@ -82,14 +82,17 @@ fn build_startup(main_window: &gtk::ApplicationWindow, meff: Rc<RefCell<Model>>)
     let name_entry_join = gtk::Entry::new();
     let port_entry_join = gtk::Entry::new();
     let ip_entry_join = gtk::Entry::new();
+    let invite_entry_join = gtk::Entry::new();
 
     let name_box_join = create_entry_with_label("Name         ", name_entry_join.clone());
     let port_box_join = create_entry_with_label("Port            ", port_entry_join.clone());
     let ip_box_join = create_entry_with_label("IP Address", ip_entry_join.clone());
+    let invite_box_join = create_entry_with_label("Invite        ", invite_entry_join.clone());
 
     v_box_join.pack_start(&name_box_join, true, true, 0);
     v_box_join.pack_start(&port_box_join, true, true, 0);
     v_box_join.pack_start(&ip_box_join, true, true, 0);
+    v_box_join.pack_start(&invite_box_join, true, true, 0);
 
     stack.add_titled(&v_box_create, "create", "Create network");
     stack.add_titled(&v_box_join, "join", "Join network");
@@ -111,7 +114,7 @@ fn build_startup(main_window: &gtk::ApplicationWindow, meff: Rc<RefCell<Model>>)
             set_entry_border(&port, &port_entry_create);
 
             if !name.is_empty() && !port.is_empty() {
-                match meff.borrow_mut().start(name, port, None) {
+                match meff.borrow_mut().start(name, port, None, None) {
                     Ok(_) => { startup_window.destroy(); }
                     Err(e) => {
                         display_message(&e);
@@ -122,6 +125,7 @@ fn build_startup(main_window: &gtk::ApplicationWindow, meff: Rc<RefCell<Model>>)
             let name = name_entry_join.get_text().unwrap().as_str().to_string();
             let port = port_entry_join.get_text().unwrap().as_str().to_string();
             let ip = ip_entry_join.get_text().unwrap().as_str().to_string();
+            let invite = invite_entry_join.get_text().unwrap().as_str().trim().to_string();
 
             set_entry_border(&name, &name_entry_join);
             set_entry_border(&port, &port_entry_join);
@@ -129,7 +133,8 @@ fn build_startup(main_window: &gtk::ApplicationWindow, meff: Rc<RefCell<Model>>)
 
             if !name.is_empty() && !port.is_empty() && !ip.is_empty() {
                 let addr = verify_ip(&ip);
-                match meff.borrow_mut().start(name, port, addr) {
+                let invite = if invite.is_empty() { None } else { Some(invite) };
+                match meff.borrow_mut().start(name, port, addr, invite) {
                     Ok(_) => { startup_window.destroy(); }
                     Err(e) => {
                         display_message(&e);
@@ -334,16 +339,99 @@ fn show_status(meff: Rc<RefCell<Model>>) {
     status_window.show_all();
 }
 
+fn show_catalog(meff: Rc<RefCell<Model>>) {
+    let catalog_window = gtk::Window::new(gtk::WindowType::Toplevel);
+    catalog_window.set_position(WindowPosition::Center);
+    catalog_window.set_size_request(500, 500);
 
-fn build_ui(application: &gtk::Application, meff: Rc<RefCell<Model>>, receiver: Receiver<(String, FileStatus)>) {
+    let header = gtk::HeaderBar::new();
+    header.set_title(Some("Browse"));
+    catalog_window.set_titlebar(Some(&header));
+
+    let search_entry = gtk::SearchEntry::new();
+    search_entry.set_margin_top(10);
+    let list_box = gtk::ListBox::new();
+
+    let scrolled_window = gtk::ScrolledWindow::new(gtk::NONE_ADJUSTMENT, gtk::NONE_ADJUSTMENT);
+    gtk::WidgetExt::set_widget_name(&scrolled_window, "scrollview");
+    scrolled_window.add(&list_box);
+
+    fill_catalog_list(&list_box, "", meff.clone());
+    let list_box_clone = list_box.clone();
+    search_entry.connect_search_changed(move |entry| {
+        let query = entry.get_text().unwrap().as_str().to_string();
+        fill_catalog_list(&list_box_clone, &query, meff.clone());
+    });
+
+    let close_button = gtk::Button::new_with_label("Close");
+    close_button.set_halign(gtk::Align::Center);
+    close_button.set_valign(gtk::Align::End);
+
+    close_button.connect_clicked(clone!(@weak catalog_window => move |_| {
+        catalog_window.destroy();
+    }));
+
+    let v_box = gtk::Box::new(gtk::Orientation::Vertical, 10);
+    v_box.pack_start(&search_entry, false, true, 0);
+    v_box.pack_start(&scrolled_window, true, true, 0);
+    v_box.pack_start(&close_button, false, true, 20);
+
+    catalog_window.add(&v_box);
+    catalog_window.show_all();
+}
+
+fn fill_catalog_list(list_box: &gtk::ListBox, query: &str, meff: Rc<RefCell<Model>>) {
+    for element in list_box.get_children() {
+        list_box.remove(&element);
+    }
+    let entries = meff.borrow_mut().search(query);
+    for entry in entries {
+        let row = gtk::ListBoxRow::new();
+        row.set_selectable(false);
+        let h_box = gtk::Box::new(gtk::Orientation::Horizontal, 5);
+
+        let text = match &entry.metadata.artist {
+            Some(artist) => format!("{} - {}", entry.title, artist),
+            None => entry.title.clone(),
+        };
+        let label = gtk::Label::new(Some(&text));
+        label.set_halign(gtk::Align::Start);
+        let download_button = gtk::Button::new_with_label("Download");
+        let stream_button = gtk::Button::new_with_label("Stream");
+
+        let title_download = entry.title.clone();
+        let meff_download = meff.clone();
+        download_button.connect_clicked(move |_| {
+            meff_download.borrow_mut().download(title_download.clone());
+        });
+        let title_stream = entry.title.clone();
+        let meff_stream = meff.clone();
+        stream_button.connect_clicked(move |_| {
+            meff_stream.borrow_mut().stream(title_stream.clone());
+        });
+
+        h_box.pack_start(&label, true, true, 0);
+        h_box.pack_end(&stream_button, false, false, 0);
+        h_box.pack_end(&download_button, false, false, 0);
+        row.add(&h_box);
+        row.show_all();
+        list_box.add(&row);
+    }
+}
+
+
+fn build_ui(application: &gtk::Application, meff: Rc<RefCell<Model>>, receiver: Receiver<(String, FileStatus)>, left_receiver: Receiver<()>) {
     let main_window = ApplicationWindow::new(application);
+    let application_left = application.clone();
     let meff_clone = Rc::clone(&meff);
     let meff_clone_l = Rc::clone(&meff);
     let meff_clone_play = Rc::clone(&meff);
     let meff_clone_pause = Rc::clone(&meff);
     let meff_clone_stop = Rc::clone(&meff);
     let meff_clone_quit = Rc::clone(&meff);
+    let meff_clone_left = Rc::clone(&meff);
     let meff_clone_status = Rc::clone(&meff);
+    let meff_clone_browse = Rc::clone(&meff);
     let meff_clone_stream = Rc::clone(&meff);
     let meff_clone_download = Rc::clone(&meff);
 
@@ -369,8 +457,10 @@ fn build_ui(application: &gtk::Application, meff: Rc<RefCell<Model>>, receiver:
     let about = MenuItem::new_with_label("About");
     let quit = MenuItem::new_with_label("Quit");
     let status = MenuItem::new_with_label("Status");
+    let browse = MenuItem::new_with_label("Browse");
 
     menu.append(&status);
+    menu.append(&browse);
     menu.append(&about);
     menu.append(&quit);
     file.set_submenu(Some(&menu));
@@ -381,12 +471,23 @@ fn build_ui(application: &gtk::Application, meff: Rc<RefCell<Model>>, receiver:
         show_status(meff_status);
     });
 
+    browse.connect_activate(move |_| {
+        let meff_browse = Rc::clone(&meff_clone_browse);
+        show_catalog(meff_browse);
+    });
+
 
     quit.connect_activate(move |_| {
         let meff_quit = Rc::clone(&meff_clone_quit);
         meff_quit.borrow_mut().quit();
     });
 
+    left_receiver.attach(None, move |_| {
+        meff_clone_left.borrow_mut().shutdown();
+        application_left.quit();
+        glib::Continue(false)
+    });
+
     // `Primary` is `Ctrl` on Windows and Linux, and `command` on macOS
     // It isn't available directly through gdk::ModifierType, since it has
     // different values on different platforms.
@@ -408,6 +509,12 @@ fn build_ui(application: &gtk::Application, meff: Rc<RefCell<Model>>, receiver:
     let dialog = FileChooserDialog::new(Some("Open File"), Some(&main_window), FileChooserAction::Open);
     dialog.add_button("_Cancel", ResponseType::Cancel);
     dialog.add_button("_Open", ResponseType::Accept);
+    let audio_filter = gtk::FileFilter::new();
+    audio_filter.set_name(Some("Audio files"));
+    for pattern in &["*.mp3", "*.flac", "*.ogg", "*.wav", "*.aac", "*.m4a"] {
+        audio_filter.add_pattern(pattern);
+    }
+    dialog.add_filter(&audio_filter);
 
     upload_button
         .connect_clicked(move |_| {
@@ -579,6 +686,8 @@ fn main() {
         let meff = Rc::new(RefCell::new(Model::new()));
         let (tx, rx) = glib::MainContext::channel(glib::PRIORITY_DEFAULT);
         meff.borrow_mut().set_sender(tx);
+        let (left_tx, left_rx) = glib::MainContext::channel(glib::PRIORITY_DEFAULT);
+        meff.borrow_mut().set_left_sender(left_tx);
         // The CSS "magic" happens here.
         let provider = gtk::CssProvider::new();
         provider
@@ -593,7 +702,7 @@ fn main() {
         );
 
         // We build the application UI.
-        build_ui(app, meff, rx);
+        build_ui(app, meff, rx, left_rx);
     });
 
     application.run(&args().collect::<Vec<_>>());
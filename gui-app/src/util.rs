@@ -1,23 +1,27 @@
-use meff::utils::{AppListener, FileStatus};
+use meff::utils::{AppListener, Config, FileInstructions, FileStatus, LookupResult, DEFAULT_DATA_DIR};
 use std::net::SocketAddr;
-use meff::interface::{Peer, MusicState, start, music_request, upload_music, music_control, delete_peer};
+use std::path::PathBuf;
+use meff::interface::{Peer, PeerHandle, CatalogEntry, SongMetadata, MusicState, start, music_request, upload_music, music_control, delete_peer, search};
 use meff::utils::FileInstructions::{REMOVE, GET};
 use glib::{Sender};
 use meff::interface::MusicState::{PAUSE, PLAY, STOP, CONTINUE};
-use std::collections::HashMap;
 use std::sync::{Mutex, Arc};
 
 //Music entertainment for friends application model
 #[derive(Clone)]
 pub struct Model {
     pub peer: Option<Arc<Mutex<Peer>>>,
+    /// Shared with the clone that listens to the peer, taken by `shutdown`
+    handle: Arc<Mutex<Option<PeerHandle>>>,
     pub sender: Option<Sender<(String, FileStatus)>>,
+    /// Tells the main loop that the peer left the network, it shuts the peer down then
+    left: Option<Sender<()>>,
     pub is_playing: Arc<Mutex<bool>>,
 }
 
 impl AppListener for Model {
     #[allow(unused_variables)]
-    fn notify_status(&self, files: Vec<String>, name: String) {
+    fn notify_status(&self, files: Vec<(String, SongMetadata)>, name: String) {
         println!("Received status");
     }
 
@@ -40,25 +44,82 @@ impl AppListener for Model {
         *self.is_playing.lock().unwrap() = false;
     }
 
+    fn stream_buffering(&mut self, title: String, buffering: bool) {
+        if buffering {
+            println!("Buffering {}", title);
+        }
+    }
+
+    fn delete_rejected(&mut self, title: String, reason: String) {
+        println!("Could not remove {}: {}", title, reason);
+    }
+
+    fn leave_progress(&mut self, handed_off: usize, total: usize) {
+        println!("Handed off {} of {} songs", handed_off, total);
+    }
+
+    fn left_network(&mut self, result: Result<(), String>) {
+        match result {
+            Ok(_) => {
+                println!("You left the network.");
+                if let Some(left) = &self.left {
+                    if left.send(()).is_err() {
+                        println!("Could not tell the main loop that the network was left");
+                    }
+                }
+            }
+            Err(e) => println!("Could not leave the network: {}", e),
+        }
+    }
+
+    fn file_lookup_finished(&mut self, title: String, _instr: FileInstructions, result: LookupResult) {
+        match result {
+            LookupResult::FOUND => println!("Found {} in the network.", title),
+            LookupResult::MISSING => println!("{} is not in the network.", title),
+            LookupResult::TIMEOUT => println!("Looking up {} timed out.", title),
+        }
+    }
+
 }
 
 impl Model {
     pub fn new() -> Model {
-        Model {peer: None, sender: None, is_playing: Arc::new(Mutex::new(false))}
+        Model {peer: None, handle: Arc::new(Mutex::new(None)), sender: None, left: None, is_playing: Arc::new(Mutex::new(false))}
     }
 
     pub fn set_sender(&mut self, sender: Sender<(String, FileStatus)>) {
         self.sender = Some(sender);
     }
 
-    pub fn start(&mut self, name: String, port: String, ip: Option<SocketAddr>) -> Result<(), String> {
+    pub fn set_left_sender(&mut self, left: Sender<()>) {
+        self.left = Some(left);
+    }
+
+    pub fn start(&mut self, name: String, port: String, ip: Option<SocketAddr>, invite: Option<String>) -> Result<(), String> {
         let clone = Box::new(self.clone());
-        let peer = start(clone, name, port, ip);
-        if peer.is_err() {
-            return Err(peer.err().unwrap());
+        let config = Config {
+            data_dir: PathBuf::from(DEFAULT_DATA_DIR).join(&port),
+            invite,
+            ..Config::default()
+        };
+        match start(clone, name, port, ip, config) {
+            Ok(handle) => {
+                self.peer = Some(handle.peer());
+                *self.handle.lock().unwrap() = Some(handle);
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Stops the threads of the peer after it left the network
+    pub fn shutdown(&mut self) {
+        let handle = self.handle.lock().unwrap().take();
+        if let Some(handle) = handle {
+            if let Err(e) = handle.shutdown() {
+                eprintln!("Failed to shut the peer down: {}", e);
+            }
         }
-        self.peer = Some(peer.ok().unwrap());
-        Ok(())
     }
 
     pub fn push(&mut self, path: String, title: String) {
@@ -90,13 +151,23 @@ impl Model {
         drop(peer_unlock);
     }
 
-    pub fn status(&mut self) -> HashMap<String, SocketAddr> {
+    pub fn status(&mut self) -> Vec<(String, SocketAddr)> {
         let peer_unlock = self.peer.as_ref().unwrap().lock().unwrap();
         let peer_clone = peer_unlock.clone();
+        drop(peer_unlock);
 
-        let list = peer_clone.network_table;
+        peer_clone
+            .get_network()
+            .iter()
+            .map(|(id, addr)| (peer_clone.display_name(id), *addr))
+            .collect()
+    }
+
+    pub fn search(&mut self, query: &str) -> Vec<CatalogEntry> {
+        let peer_unlock = self.peer.as_ref().unwrap().lock().unwrap();
+        let entries = search(&peer_unlock, query);
         drop(peer_unlock);
-        list
+        entries
     }
 
     pub fn stream(&mut self, search: String) {
@@ -1,11 +1,56 @@
-use std::string::ToString;
+use crate::metadata::{read_format, read_format_file, AudioFormat};
 use std::fs;
+use std::path::Path;
+use std::string::ToString;
+
+/// Path in the download folder the song `name` is saved to, with the extension of its format.
+/// Path separators in `name` are replaced, so no song is saved outside of the folder.
+fn download_path(name: &str, format: Result<AudioFormat, String>) -> Result<String, String> {
+    let format = match format {
+        Ok(f) => f,
+        Err(e) => return Err(format!("Could not detect the format of {}: {}", name, e)),
+    };
+    let file_name: String = name
+        .chars()
+        .map(|c| if c == '/' || c == '\\' { '_' } else { c })
+        .collect();
+    Ok(format!("../file/{}.{}", file_name, format.extension()))
+}
 
 pub fn save_music_to_disk(music: Vec<u8>, name: &str) -> Result<(), String> {
     println!("{}", "save_music_to_disk".to_string());
-    let path = format!("../file/{}.mp3", name);
-    match fs::write(path ,music) {
-        Ok(_) =>  Ok(()),
+    let path = download_path(name, read_format(&music))?;
+    match fs::write(path, music) {
+        Ok(_) => Ok(()),
         Err(_e) => Err("could not save file to disk".to_string()),
     }
 }
+
+/// Moves a downloaded file from `path` into the download folder
+pub fn move_music_to_disk(path: &Path, name: &str) -> Result<(), String> {
+    let target = download_path(name, read_format_file(path))?;
+    if fs::rename(path, &target).is_ok() {
+        return Ok(());
+    }
+    match fs::copy(path, &target) {
+        Ok(_) => {
+            let _ = fs::remove_file(path);
+            Ok(())
+        }
+        Err(_e) => Err("could not save file to disk".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn download_path_stays_in_the_folder() {
+        let path = download_path("../song", Ok(AudioFormat::MP3)).unwrap();
+        assert_eq!(path, "../file/.._song.mp3");
+        let path = download_path("a\\b/c", Ok(AudioFormat::WAV)).unwrap();
+        assert_eq!(path, "../file/a_b_c.wav");
+        assert!(download_path("song", Err("unknown".to_string())).is_err());
+    }
+}
@@ -0,0 +1,222 @@
+use crate::database::{write_atomic, StorageBackend};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+
+/// Name of the index file that maps the keys of the database to the files in `SONG_DIR`
+const INDEX_FILE: &str = "index.json";
+/// Name of the directory inside the data directory that holds the song files
+const SONG_DIR: &str = "songs";
+
+struct Index {
+    files: HashMap<String, String>,
+    next_file: u64,
+}
+
+/// Stores every song in its own file inside a directory. An index maps the keys to the file
+/// names, so keys can contain characters that are not allowed in file names. A stored file is
+/// never written to again, a new value is written to a new file that is moved over the old one.
+pub struct FileBackend {
+    dir: PathBuf,
+    index: Mutex<Index>,
+}
+
+impl FileBackend {
+    /// Opens the store in `data_dir`, the directory is created if it does not exist yet.
+    pub fn open(data_dir: &Path) -> Result<FileBackend, String> {
+        if let Err(e) = fs::create_dir_all(data_dir.join(SONG_DIR)) {
+            return Err(format!(
+                "Could not create data directory {:?}: {:?}",
+                data_dir, e
+            ));
+        }
+        let mut files: HashMap<String, String> = HashMap::new();
+        let index_path = data_dir.join(INDEX_FILE);
+        if index_path.exists() {
+            files = match fs::read_to_string(&index_path) {
+                Ok(s) => match serde_json::from_str(&s) {
+                    Ok(i) => i,
+                    Err(e) => return Err(format!("Could not parse database index: {:?}", e)),
+                },
+                Err(e) => return Err(format!("Could not read database index: {:?}", e)),
+            };
+        }
+        files.retain(|key, file_name| {
+            let exists = data_dir.join(SONG_DIR).join(file_name.as_str()).exists();
+            if !exists {
+                error!(
+                    "File {} of {} is missing, dropping it from the index",
+                    file_name, key
+                );
+            }
+            exists
+        });
+        let next_file = files
+            .values()
+            .filter_map(|f| f.parse::<u64>().ok())
+            .max()
+            .map_or(0, |n| n + 1);
+        info!("Found {} songs in {:?}", files.len(), data_dir);
+        Ok(FileBackend {
+            dir: data_dir.to_path_buf(),
+            index: Mutex::new(Index { files, next_file }),
+        })
+    }
+
+    /// Returns the path of the file that holds `key`
+    pub fn path_of(&self, key: &str) -> Option<PathBuf> {
+        let index = self.lock_index();
+        index
+            .files
+            .get(key)
+            .map(|file_name| self.dir.join(SONG_DIR).join(file_name))
+    }
+
+    fn lock_index(&self) -> MutexGuard<'_, Index> {
+        match self.index.lock() {
+            Ok(i) => i,
+            Err(e) => e.into_inner(),
+        }
+    }
+
+    /// Returns the file name for `key`, a new one is assigned if `key` is not stored yet
+    fn file_name_for(index: &mut Index, key: &str) -> String {
+        match index.files.get(key) {
+            Some(f) => f.clone(),
+            None => {
+                let file_name = index.next_file.to_string();
+                index.next_file += 1;
+                file_name
+            }
+        }
+    }
+
+    /// Writes the index to a temporary file and moves it in place, so a crash never leaves a
+    /// half written index behind.
+    fn save_index(&self, index: &Index) -> Result<(), String> {
+        match serde_json::to_string(&index.files) {
+            Ok(json) => write_atomic(&self.dir.join(INDEX_FILE), json.as_bytes()),
+            Err(e) => Err(format!("Could not serialize database index: {:?}", e)),
+        }
+    }
+}
+
+impl StorageBackend for FileBackend {
+    fn put(&self, key: &str, value: &[u8]) -> Result<(), String> {
+        let mut index = self.lock_index();
+        let file_name = FileBackend::file_name_for(&mut index, key);
+        write_atomic(&self.dir.join(SONG_DIR).join(&file_name), value)?;
+        index.files.insert(key.to_string(), file_name);
+        self.save_index(&index)
+    }
+
+    fn get(&self, key: &str) -> Option<Vec<u8>> {
+        let path = self.path_of(key)?;
+        match fs::read(&path) {
+            Ok(value) => Some(value),
+            Err(e) => {
+                error!("Could not read {} from {:?}: {:?}", key, path, e);
+                None
+            }
+        }
+    }
+
+    fn delete(&self, key: &str) -> Result<(), String> {
+        let mut index = self.lock_index();
+        let file_name = match index.files.remove(key) {
+            Some(f) => f,
+            None => return Ok(()),
+        };
+        self.save_index(&index)?;
+        match fs::remove_file(self.dir.join(SONG_DIR).join(file_name)) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!("Could not delete {} from disk: {:?}", key, e)),
+        }
+    }
+
+    fn list(&self) -> Vec<String> {
+        self.lock_index().files.keys().cloned().collect()
+    }
+
+    fn size(&self, key: &str) -> Option<u64> {
+        let path = self.path_of(key)?;
+        fs::metadata(path).ok().map(|m| m.len())
+    }
+
+    fn get_range(&self, key: &str, offset: u64, length: u64) -> Option<Vec<u8>> {
+        let path = self.path_of(key)?;
+        let mut file = match File::open(&path) {
+            Ok(f) => f,
+            Err(e) => {
+                error!("Could not open {:?}: {:?}", path, e);
+                return None;
+            }
+        };
+        if let Err(e) = file.seek(SeekFrom::Start(offset)) {
+            error!("Could not seek in {:?}: {:?}", path, e);
+            return None;
+        }
+        let mut value = Vec::new();
+        match file.take(length).read_to_end(&mut value) {
+            Ok(_) => Some(value),
+            Err(e) => {
+                error!("Could not read {:?}: {:?}", path, e);
+                None
+            }
+        }
+    }
+
+    fn put_file(&self, key: &str, path: &Path) -> Result<(), String> {
+        let mut index = self.lock_index();
+        let file_name = FileBackend::file_name_for(&mut index, key);
+        let target = self.dir.join(SONG_DIR).join(&file_name);
+        if fs::rename(path, &target).is_err() {
+            // the file is on another file system, fall back to copying it next to the target
+            let tmp = target.with_extension("tmp");
+            if let Err(e) = fs::copy(path, &tmp).and_then(|_| fs::rename(&tmp, &target)) {
+                return Err(format!(
+                    "Could not move {:?} to {:?}: {:?}",
+                    path, target, e
+                ));
+            }
+            if let Err(e) = fs::remove_file(path) {
+                error!("Could not delete {:?}: {:?}", path, e);
+            }
+        }
+        index.files.insert(key.to_string(), file_name);
+        self.save_index(&index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::check_round_trip;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn round_trip() {
+        let dir = TempDir::new("filesystem");
+        check_round_trip(&FileBackend::open(dir.path()).unwrap());
+    }
+
+    #[test]
+    fn reopened_store_keeps_its_songs() {
+        let dir = TempDir::new("filesystem");
+        let backend = FileBackend::open(dir.path()).unwrap();
+        backend.put("a", b"song").unwrap();
+        let file = dir.path().join("upload");
+        fs::write(&file, b"moved").unwrap();
+        backend.put_file("b", &file).unwrap();
+        assert!(!file.exists());
+        drop(backend);
+
+        let backend = FileBackend::open(dir.path()).unwrap();
+        assert_eq!(backend.get("a"), Some(b"song".to_vec()));
+        assert_eq!(backend.get("b"), Some(b"moved".to_vec()));
+        backend.put("c", b"new").unwrap();
+        assert_eq!(backend.get("a"), Some(b"song".to_vec()));
+    }
+}
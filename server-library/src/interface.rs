@@ -1,17 +1,32 @@
 use crate::database::Database;
+pub use crate::metadata::{AudioFormat, Cover, SongMetadata};
 use crate::network;
+pub use crate::network::catalog::CatalogEntry;
+use crate::network::catalog::{self, SignedListing};
+use crate::network::departure::Departure;
+use crate::network::dht::{Lookup, RoutingTable};
+use crate::network::file_request::RequestTable;
+use crate::network::handshake::Hello;
+pub use crate::network::identity::PeerId;
 use crate::network::notification::Content;
+use crate::network::ownership::Ownership;
+use crate::network::rebalance::Rebalancer;
+use crate::network::secure::Session;
+use crate::network::tombstone::Tombstone;
+use crate::network::transfer::IncomingTransfer;
 use crate::network::{
-    push_music_to_database, send_delete_peer_request, send_play_request, send_read_request,
+    push_music_to_database, send_delegate_request, send_delete_peer_request, send_play_request,
+    send_read_request, StopSignal,
 };
-use crate::utils::{AppListener, FileInstructions};
+use crate::utils::{AppListener, Config, FileInstructions};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::io;
 use std::net::SocketAddr;
 use std::sync::mpsc::SyncSender;
 use std::sync::{Arc, Mutex};
-use std::time::SystemTime;
+use std::thread::JoinHandle;
+use std::time::Duration;
 
 #[derive(Clone, Deserialize, Serialize, Debug)]
 pub enum MusicState {
@@ -25,22 +40,105 @@ pub enum MusicState {
 pub struct Notification {
     pub content: Content,
     pub from: SocketAddr,
+    /// ID of the peer that signed the notification, `Some` for notifications received over
+    /// the network
+    #[serde(skip)]
+    pub signer: Option<PeerId>,
 }
 
 /// Represents a Peer in the network
 #[derive(Clone)]
 pub struct Peer {
     pub name: String,
+    /// The stable ID of the local peer, the public key of its identity
+    pub id: PeerId,
     pub ip_address: SocketAddr,
-    pub network_table: HashMap<String, SocketAddr>,
+    pub network_table: HashMap<PeerId, SocketAddr>,
+    /// The display names of the peers in the network table. A name is only metadata, peers
+    /// are told apart by their ID.
+    pub peer_names: HashMap<PeerId, String>,
     pub database: Database,
-    pub open_request_table: HashMap<SystemTime, FileInstructions>,
+    pub open_request_table: RequestTable,
     pub sender: SyncSender<Notification>,
-    pub redundancy_table: HashMap<SocketAddr, Vec<String>>,
+    /// The peers that hold a copy of each song, the local peer included
+    pub holders: HashMap<String, Vec<SocketAddr>>,
+    /// The peers that confirmed with `ReplicaStored` that they stored a copy of each song.
+    /// `holders` also lists peers a copy was only sent to.
+    pub(crate) confirmed_holders: HashMap<String, HashSet<SocketAddr>>,
+    /// Number of copies the network keeps of every song
+    pub replication_factor: usize,
+    /// Size in bytes a received song may have at most
+    pub max_song_size: u64,
+    pub(crate) hellos: HashMap<SocketAddr, Hello>,
+    pub(crate) transfers: HashMap<String, IncomingTransfer>,
+    /// The connections the chunks of a song are sent over, by receiver and song
+    pub(crate) chunk_sessions: HashMap<(SocketAddr, String), Session>,
+    /// The routing table, `Some` in DHT mode
+    pub(crate) routing: Option<RoutingTable>,
+    pub(crate) lookups: HashMap<u64, Lookup>,
+    /// Secret of the network, `None` until the peer was accepted into one
+    pub(crate) network_secret: Option<Vec<u8>>,
+    /// Who uploaded each song and who else may remove it
+    pub(crate) owners: HashMap<String, Ownership>,
+    /// The songs that were removed from the network
+    pub(crate) tombstones: HashMap<String, Tombstone>,
+    /// The copies that are moved to peers that joined the network
+    pub(crate) rebalancer: Rebalancer,
+    /// The songs the local peer hands off, `Some` while it leaves the network
+    pub(crate) departure: Option<Departure>,
+    /// The peers that announced that they leave the network
+    pub(crate) leaving: HashSet<SocketAddr>,
+    /// The songs every peer in the network holds, the source of the catalog
+    pub(crate) listings: HashMap<SocketAddr, SignedListing>,
+    /// The peers that proved that they belong to the network. In DHT mode not all of them are
+    /// in the network table.
+    pub(crate) members: HashSet<PeerId>,
+    /// Address of the peer that was asked to join its network, `None` once the peer joined
+    pub(crate) join_target: Option<SocketAddr>,
+    /// The nonces of the invite tokens the local peer accepted and when the tokens expire
+    pub(crate) redeemed_invites: HashMap<u64, u64>,
+}
+
+/// Handle of a running `Peer` that `start` returns. The library never exits the process, the
+/// application decides when to stop the peer with `shutdown`.
+pub struct PeerHandle {
+    peer: Arc<Mutex<Peer>>,
+    stop: StopSignal,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl PeerHandle {
+    pub(crate) fn new(
+        peer: Arc<Mutex<Peer>>,
+        stop: StopSignal,
+        threads: Vec<JoinHandle<()>>,
+    ) -> PeerHandle {
+        PeerHandle {
+            peer,
+            stop,
+            threads,
+        }
+    }
+
+    /// The local `Peer` in a `Mutex`
+    pub fn peer(&self) -> Arc<Mutex<Peer>> {
+        self.peer.clone()
+    }
+
+    /// Stops the listener, working, heartbeat and timer threads of the peer and saves its
+    /// unfinished transfers, so they are continued on the next start. The peer does not leave
+    /// the network, call `delete_peer` before to hand off its files.
+    /// # Returns
+    /// Error message as `String` on `Err` if a thread did not stop cleanly
+    pub fn shutdown(self) -> Result<(), String> {
+        network::shutdown(&self.peer, &self.stop, self.threads)
+    }
 }
 
 /// This function removes the Peer from the Network. Call it if you want to disconnect your
-/// application gracefully while redistributing your locally saved files to the network
+/// application gracefully while redistributing your locally saved files to the network. The
+/// application is told with `AppListener::left_network` once the other peers confirmed all files,
+/// it decides itself when to exit.
 /// # Paramteters
 /// - `peer` - The local `Peer`
 pub fn delete_peer(peer: &mut Peer) {
@@ -65,6 +163,15 @@ pub fn music_request(peer: &mut Peer, name: &str, instr: FileInstructions) {
     send_read_request(peer, name, instr)
 }
 
+/// Use this function to allow another peer to remove a song you uploaded.
+/// # Parameters
+/// - `peer` - The local `Peer`
+/// - `name` - The name of the file
+/// - `delegate` - The ID of the other peer, or a unique prefix of it
+pub fn delegate_removal(peer: &mut Peer, name: &str, delegate: &str) {
+    send_delegate_request(peer, name, delegate)
+}
+
 /// Use this function to upload a file to the network.
 /// # Parameters
 /// - `name` - The name of the file
@@ -80,25 +187,65 @@ pub fn upload_music(
     push_music_to_database(name, file_path, addr, peer)
 }
 
+/// Use this function to invite a friend to the network. The friend joins with the returned token.
+/// # Parameters
+/// - `peer` - The local `Peer`
+/// - `valid_for` - Time after which the token expires
+///
+/// # Returns
+/// `Result<String, String>` The invite token if `Ok`, Error message as `String` on `Err`
+pub fn create_invite(peer: &Peer, valid_for: Duration) -> Result<String, String> {
+    peer.create_invite(valid_for)
+}
+
+/// Use this function to search the songs of all peers in the network.
+/// # Parameters
+/// - `peer` - The local `Peer`
+/// - `query` - Part of the title, artist or album. Misspelled queries find similar songs, an empty
+///   query lists all songs.
+///
+/// # Returns
+/// `Vec<CatalogEntry>` The matching songs, the best matches first
+pub fn search(peer: &Peer, query: &str) -> Vec<CatalogEntry> {
+    catalog::search(peer, query)
+}
+
+/// Use this function to get the tags of a song the local peer holds, including its cover art.
+/// # Parameters
+/// - `peer` - The local `Peer`
+/// - `name` - The name of the file
+///
+/// # Returns
+/// `Option<SongMetadata>` The tags of the song, `None` if the local peer does not hold it
+pub fn song_metadata(peer: &Peer, name: &str) -> Option<SongMetadata> {
+    let db = peer.get_db();
+    let mut metadata = db.metadata(name)?;
+    metadata.cover = db.cover(name);
+    Some(metadata)
+}
+
 /// Use this function to connect to the network.
 /// # Parameters
 /// - `module` - A listener object that implements `AppListener` and `Sync` as a boxed value
 /// - `name` - The name by which you want to be represented in the network
 /// - `port` - The port you want to listen on
 /// - `ip` - An optional `SocketAddr`. Pass a value if you want to join a network on that
-///     `SocketAddr`. `None` if you want to start a fresh network.
+///   `SocketAddr`. `None` if you want to start a fresh network.
+/// - `config` - The `Config` of the library. Songs that were persisted in its data directory
+///   are loaded again.
 ///
 /// # Returns
-/// `Result<Arc<Mutex<Peer>>, String>`The local `Peer` in a `Mutex` if `Ok`,
+/// `Result<PeerHandle, String>` The handle of the running `Peer` if `Ok`,
 /// Error message as `String` on `Err`
 pub fn start(
     module: Box<dyn AppListener + Sync>,
     name: String,
     port: String,
     ip: Option<SocketAddr>,
-) -> Result<Arc<Mutex<Peer>>, String> {
+    config: Config,
+) -> Result<PeerHandle, String> {
     let clone = Arc::new(Mutex::new(module));
-    match network::startup(&name, &port, ip, clone) {
+    match network::startup(&name, &port, ip, clone, config) {
         Ok(p) => Ok(p),
         Err(e) => Err(e),
     }
@@ -0,0 +1,278 @@
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{Cursor, SeekFrom};
+use std::path::Path;
+use symphonia::core::codecs::{
+    CodecType, CODEC_TYPE_AAC, CODEC_TYPE_FLAC, CODEC_TYPE_MP3, CODEC_TYPE_PCM_F32LE,
+    CODEC_TYPE_PCM_S16LE, CODEC_TYPE_PCM_S24LE, CODEC_TYPE_PCM_S32LE, CODEC_TYPE_PCM_U8,
+    CODEC_TYPE_VORBIS,
+};
+use symphonia::core::formats::{FormatOptions, Track};
+use symphonia::core::io::{MediaSource, MediaSourceStream};
+use symphonia::core::meta::{MetadataOptions, MetadataRevision, StandardTagKey, Value};
+use symphonia::core::probe::{Hint, ProbeResult};
+use symphonia::core::units::TimeBase;
+
+/// The samples a WAV file can hold
+const WAV_CODECS: [CodecType; 5] = [
+    CODEC_TYPE_PCM_U8,
+    CODEC_TYPE_PCM_S16LE,
+    CODEC_TYPE_PCM_S24LE,
+    CODEC_TYPE_PCM_S32LE,
+    CODEC_TYPE_PCM_F32LE,
+];
+
+/// The formats songs can be stored in. All but AAC can be played.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum AudioFormat {
+    MP3,
+    FLAC,
+    VORBIS,
+    WAV,
+    /// AAC in an ADTS stream
+    AAC,
+    /// AAC in an MP4 container
+    M4A,
+}
+
+impl AudioFormat {
+    /// The extension downloaded songs of the format are saved with
+    pub fn extension(&self) -> &'static str {
+        match self {
+            AudioFormat::MP3 => "mp3",
+            AudioFormat::FLAC => "flac",
+            AudioFormat::VORBIS => "ogg",
+            AudioFormat::WAV => "wav",
+            AudioFormat::AAC => "aac",
+            AudioFormat::M4A => "m4a",
+        }
+    }
+
+    /// Checks if the player can decode songs of the format, songs of other formats are not
+    /// uploaded
+    pub fn is_playable(&self) -> bool {
+        !matches!(self, AudioFormat::AAC | AudioFormat::M4A)
+    }
+}
+
+/// The tags of a song and what is known about its audio stream. Every peer reads them from the
+/// bytes it stores, so all copies of a song carry the same metadata.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct SongMetadata {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub track: Option<u32>,
+    pub year: Option<u32>,
+    pub genre: Option<String>,
+    /// Length of the song in seconds
+    pub duration: Option<u64>,
+    /// Average bitrate in kbit/s
+    pub bitrate: Option<u32>,
+    #[serde(default)]
+    pub format: Option<AudioFormat>,
+    /// The embedded cover art, it is left out when the metadata is sent to other peers
+    #[serde(default)]
+    pub cover: Option<Cover>,
+}
+
+/// An image embedded in a song
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Cover {
+    /// The MIME type of the image, e.g. `image/jpeg`
+    pub media_type: String,
+    pub data: Vec<u8>,
+}
+
+impl SongMetadata {
+    /// The metadata without the cover art, which is too large to send along with every listing
+    pub fn without_cover(&self) -> SongMetadata {
+        SongMetadata {
+            cover: None,
+            ..self.clone()
+        }
+    }
+
+    /// Takes over the tags of `revision` that are not known yet
+    fn add_revision(&mut self, revision: &MetadataRevision) {
+        for tag in revision.tags() {
+            let text = match &tag.value {
+                Value::String(s) => s.trim().to_string(),
+                Value::UnsignedInt(n) => n.to_string(),
+                Value::SignedInt(n) => n.to_string(),
+                _ => continue,
+            };
+            if text.is_empty() {
+                continue;
+            }
+            match tag.std_key {
+                Some(StandardTagKey::TrackTitle) => fill(&mut self.title, text),
+                Some(StandardTagKey::Artist) => fill(&mut self.artist, text),
+                Some(StandardTagKey::AlbumArtist) if self.artist.is_none() => {
+                    fill(&mut self.artist, text)
+                }
+                Some(StandardTagKey::Album) => fill(&mut self.album, text),
+                Some(StandardTagKey::Genre) => fill(&mut self.genre, text),
+                // "3/12" is the third of twelve tracks
+                Some(StandardTagKey::TrackNumber) => fill(&mut self.track, leading_number(&text)),
+                // dates are given as "1969", "1969-09-26" or similar
+                Some(StandardTagKey::Date)
+                | Some(StandardTagKey::ReleaseDate)
+                | Some(StandardTagKey::OriginalDate) => {
+                    let year = leading_number(&text).filter(|y| *y > 999);
+                    fill(&mut self.year, year)
+                }
+                _ => {}
+            }
+        }
+        if self.cover.is_none() {
+            self.cover = revision.visuals().first().map(|visual| Cover {
+                media_type: visual.media_type.clone(),
+                data: visual.data.to_vec(),
+            });
+        }
+    }
+}
+
+/// Sets `field` to `value` if it is not set yet
+fn fill<T, V: Into<Option<T>>>(field: &mut Option<T>, value: V) {
+    if field.is_none() {
+        *field = value.into();
+    }
+}
+
+/// The number at the start of `text`
+fn leading_number(text: &str) -> Option<u32> {
+    let digits: String = text.chars().take_while(|c| c.is_ascii_digit()).collect();
+    digits.parse().ok()
+}
+
+/// Reads the tags, the length and the format of the song `data`
+/// # Returns
+/// The metadata, or the reason on `Err` if `data` is no supported audio file
+pub fn read_metadata(data: &[u8]) -> Result<SongMetadata, String> {
+    let source = Box::new(Cursor::new(data.to_vec()));
+    probe(source, data.len() as u64)
+}
+
+/// Reads the tags, the length and the format of the song in the file at `path`
+/// # Returns
+/// The metadata, or the reason on `Err` if the file is no supported audio file
+pub fn read_metadata_file(path: &Path) -> Result<SongMetadata, String> {
+    let (file, size) = open_file(path)?;
+    probe(Box::new(file), size)
+}
+
+/// Detects the format of the song `data` without reading its tags
+/// # Returns
+/// The format, or the reason on `Err` if `data` is no supported audio file
+pub fn read_format(data: &[u8]) -> Result<AudioFormat, String> {
+    let (_, _, format) = open(Box::new(Cursor::new(data.to_vec())))?;
+    Ok(format)
+}
+
+/// Detects the format of the song in the file at `path` without reading its tags
+/// # Returns
+/// The format, or the reason on `Err` if the file is no supported audio file
+pub fn read_format_file(path: &Path) -> Result<AudioFormat, String> {
+    let (file, _) = open_file(path)?;
+    let (_, _, format) = open(Box::new(file))?;
+    Ok(format)
+}
+
+fn open_file(path: &Path) -> Result<(File, u64), String> {
+    let file = match File::open(path) {
+        Ok(f) => f,
+        Err(e) => return Err(format!("Could not open {:?}: {:?}", path, e)),
+    };
+    match file.metadata() {
+        Ok(m) => Ok((file, m.len())),
+        Err(e) => Err(format!("Could not read {:?}: {:?}", path, e)),
+    }
+}
+
+/// Detects the container of `source` and the format of its audio track
+fn open(mut source: Box<dyn MediaSource>) -> Result<(ProbeResult, Track, AudioFormat), String> {
+    let mp4 = is_mp4(&mut *source)?;
+    let stream = MediaSourceStream::new(source, Default::default());
+    let probed = match symphonia::default::get_probe().format(
+        &Hint::new(),
+        stream,
+        &FormatOptions::default(),
+        &MetadataOptions::default(),
+    ) {
+        Ok(p) => p,
+        Err(e) => return Err(format!("Not a supported audio file: {}", e)),
+    };
+    let track = match probed.format.default_track() {
+        Some(t) => t.clone(),
+        None => return Err("The file contains no audio track".to_string()),
+    };
+    let format = match track.codec_params.codec {
+        CODEC_TYPE_MP3 => AudioFormat::MP3,
+        CODEC_TYPE_FLAC => AudioFormat::FLAC,
+        CODEC_TYPE_VORBIS => AudioFormat::VORBIS,
+        CODEC_TYPE_AAC if mp4 => AudioFormat::M4A,
+        CODEC_TYPE_AAC => AudioFormat::AAC,
+        codec if WAV_CODECS.contains(&codec) => AudioFormat::WAV,
+        codec => return Err(format!("Not a supported audio codec: {:?}", codec)),
+    };
+    Ok((probed, track, format))
+}
+
+/// Checks if `source` starts with the `ftyp` box of an MP4 container. AAC is stored in MP4
+/// containers or as a bare ADTS stream, which the codec alone does not tell apart.
+fn is_mp4(source: &mut dyn MediaSource) -> Result<bool, String> {
+    let mut head = [0u8; 8];
+    let mp4 = source.read_exact(&mut head).is_ok() && &head[4..] == b"ftyp";
+    match source.seek(SeekFrom::Start(0)) {
+        Ok(_) => Ok(mp4),
+        Err(e) => Err(format!("Could not read the file: {:?}", e)),
+    }
+}
+
+/// Reads the tags in front of the audio stream and inside the container of `source`, `size`
+/// bytes long. The length is taken from the stream header or, if that does not state it, by
+/// adding up the length of all packets.
+fn probe(source: Box<dyn MediaSource>, size: u64) -> Result<SongMetadata, String> {
+    let (mut probed, track, format) = open(source)?;
+    let mut metadata = SongMetadata {
+        format: Some(format),
+        ..SongMetadata::default()
+    };
+    if let Some(revision) = probed.format.metadata().skip_to_latest() {
+        metadata.add_revision(revision);
+    }
+    if let Some(mut probed_metadata) = probed.metadata.get() {
+        if let Some(revision) = probed_metadata.skip_to_latest() {
+            metadata.add_revision(revision);
+        }
+    }
+    let params = &track.codec_params;
+    let time_base = params
+        .time_base
+        .or_else(|| params.sample_rate.map(|rate| TimeBase::new(1, rate)));
+    if let Some(time_base) = time_base {
+        let frames = match params.n_frames {
+            Some(n) => n,
+            None => {
+                let mut frames = 0;
+                while let Ok(packet) = probed.format.next_packet() {
+                    if packet.track_id() == track.id {
+                        frames += packet.dur();
+                    }
+                }
+                frames
+            }
+        };
+        let time = time_base.calc_time(frames);
+        metadata.duration = Some(time.seconds + if time.frac >= 0.5 { 1 } else { 0 });
+        let seconds = time.seconds as f64 + time.frac;
+        if seconds > 0.0 {
+            let cover = metadata.cover.as_ref().map_or(0, |c| c.data.len() as u64);
+            let bits = size.saturating_sub(cover) as f64 * 8.0;
+            metadata.bitrate = Some((bits / seconds / 1000.0).round() as u32);
+        }
+    }
+    Ok(metadata)
+}
@@ -0,0 +1,246 @@
+use crate::interface::Notification;
+use crate::network::identity::{sign, verify};
+use crate::network::transfer::CHUNK_SIZE;
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+
+/// Version of the wire protocol. It is the first byte of every frame and is advertised in the
+/// `Hello` of the join handshake. New variants of `Content` are appended at its end. A version
+/// that reorders, changes or removes existing variants or their fields raises
+/// `MIN_PROTOCOL_VERSION` to itself, because bincode encodes variants by their position and older
+/// peers would misread the frames.
+pub const PROTOCOL_VERSION: u8 = 1;
+/// The oldest protocol version this build can talk to. Frames of older versions are rejected.
+/// It only rises with a version that is not wire compatible with its predecessors.
+pub const MIN_PROTOCOL_VERSION: u8 = 1;
+/// Room in a frame for the envelope, the signature and the fields of a `Notification` besides a
+/// chunk of a song
+const FRAME_OVERHEAD: u32 = 64 * 1024;
+/// The largest payload a single frame may carry in bytes, a chunk of a song with its envelope
+pub const MAX_FRAME_SIZE: u32 = CHUNK_SIZE as u32 + FRAME_OVERHEAD;
+/// One byte protocol version followed by the payload length as big endian `u32`
+const HEADER_SIZE: usize = 5;
+
+/// Payload of a frame: the bincode encoded `Notification` signed by the sending peer
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    notification: Vec<u8>,
+    public_key: Vec<u8>,
+    signature: Vec<u8>,
+}
+
+/// Writes `notification` as one frame to `writer`. A frame consists of the protocol version,
+/// the length of the payload and the `Notification` signed with the identity registered for its
+/// `from` address.
+/// # Parameters:
+/// - `writer` - the stream the frame is written to
+/// - `notification` - the `Notification` to send
+pub fn write_notification<W: Write>(
+    writer: &mut W,
+    notification: &Notification,
+) -> Result<(), String> {
+    let notification_bytes = match bincode::serialize(notification) {
+        Ok(p) => p,
+        Err(e) => return Err(format!("Could not serialize notification: {:?}", e)),
+    };
+    let (public_key, signature) = sign(&notification.from, &notification_bytes)?;
+    let envelope = Envelope {
+        notification: notification_bytes,
+        public_key,
+        signature,
+    };
+    let payload = match bincode::serialize(&envelope) {
+        Ok(p) => p,
+        Err(e) => return Err(format!("Could not serialize notification: {:?}", e)),
+    };
+    if payload.len() > MAX_FRAME_SIZE as usize {
+        return Err(format!(
+            "Notification of {} bytes exceeds the maximum frame size",
+            payload.len()
+        ));
+    }
+    let mut frame = Vec::with_capacity(HEADER_SIZE + payload.len());
+    frame.push(PROTOCOL_VERSION);
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&payload);
+    if let Err(e) = writer.write_all(&frame) {
+        return Err(format!("Could not write frame: {:?}", e));
+    }
+    match writer.flush() {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Could not flush frame: {:?}", e)),
+    }
+}
+
+/// Reads one frame from `reader` and decodes the `Notification` it carries. Frames with an
+/// invalid signature are rejected, the `signer` of the returned `Notification` is set.
+/// # Parameters:
+/// - `reader` - the stream the frame is read from
+pub fn read_notification<R: Read>(reader: &mut R) -> Result<Notification, String> {
+    let mut header = [0u8; HEADER_SIZE];
+    if let Err(e) = reader.read_exact(&mut header) {
+        return Err(format!("Could not read frame header: {:?}", e));
+    }
+    let version = header[0];
+    if version < MIN_PROTOCOL_VERSION {
+        return Err(format!(
+            "Unsupported protocol version {}, expected at least {}",
+            version, MIN_PROTOCOL_VERSION
+        ));
+    }
+    let length = u32::from_be_bytes([header[1], header[2], header[3], header[4]]);
+    if length > MAX_FRAME_SIZE {
+        return Err(format!(
+            "Frame of {} bytes exceeds the maximum frame size",
+            length
+        ));
+    }
+    // The payload grows with the bytes that actually arrive instead of trusting the length up front
+    let mut payload = Vec::new();
+    match reader.take(u64::from(length)).read_to_end(&mut payload) {
+        Ok(read) if read == length as usize => {}
+        Ok(read) => {
+            return Err(format!(
+                "Frame payload ended after {} of {} bytes",
+                read, length
+            ))
+        }
+        Err(e) => return Err(format!("Could not read frame payload: {:?}", e)),
+    }
+    let envelope: Envelope = match bincode::deserialize(&payload) {
+        Ok(e) => e,
+        Err(e) => {
+            return Err(format!(
+                "Could not deserialize frame of protocol version {}: {:?}",
+                version, e
+            ))
+        }
+    };
+    let signer = verify(
+        &envelope.public_key,
+        &envelope.notification,
+        &envelope.signature,
+    )?;
+    let mut notification: Notification = match bincode::deserialize(&envelope.notification) {
+        Ok(n) => n,
+        Err(e) => {
+            return Err(format!(
+                "Could not deserialize notification of protocol version {}: {:?}",
+                version, e
+            ))
+        }
+    };
+    notification.signer = Some(signer);
+    Ok(notification)
+}
+
+/// Keeps the leading `items` that fit into one frame together, the others are left for later
+/// rounds of a gossip
+/// # Parameters:
+/// - `items` - the items to send, the most important first
+pub fn fit_in_frame<T: Serialize>(items: Vec<T>) -> Vec<T> {
+    let mut used = 0;
+    items
+        .into_iter()
+        .take_while(|item| {
+            used += bincode::serialized_size(item).unwrap_or(u64::MAX);
+            used <= CHUNK_SIZE
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::handshake::{Capability, Hello};
+    use crate::network::notification::Content;
+    use crate::testing::identity;
+
+    fn notification(port: u16) -> Notification {
+        let (_, from) = identity(port);
+        Notification {
+            content: Content::OrderSongRequest {
+                song_name: "song".to_string(),
+            },
+            from,
+            signer: None,
+        }
+    }
+
+    fn frame(notification: &Notification) -> Vec<u8> {
+        let mut frame = Vec::new();
+        write_notification(&mut frame, notification).unwrap();
+        frame
+    }
+
+    #[test]
+    fn round_trip_sets_signer() {
+        let frame = frame(&notification(47001));
+        let decoded = read_notification(&mut frame.as_slice()).unwrap();
+        assert!(decoded.signer.is_some());
+        match decoded.content {
+            Content::OrderSongRequest { song_name } => assert_eq!(song_name, "song"),
+            other => panic!("unexpected content {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reads_every_supported_version() {
+        let mut frame = frame(&notification(47002));
+        for version in MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION {
+            frame[0] = version;
+            assert!(read_notification(&mut frame.as_slice()).is_ok());
+        }
+    }
+
+    #[test]
+    fn rejects_older_versions() {
+        let mut frame = frame(&notification(47003));
+        frame[0] = MIN_PROTOCOL_VERSION - 1;
+        assert!(read_notification(&mut frame.as_slice()).is_err());
+    }
+
+    #[test]
+    fn rejects_oversized_frames() {
+        let mut frame = frame(&notification(47004));
+        frame[1..HEADER_SIZE].copy_from_slice(&(MAX_FRAME_SIZE + 1).to_be_bytes());
+        assert!(read_notification(&mut frame.as_slice()).is_err());
+    }
+
+    #[test]
+    fn rejects_truncated_frames() {
+        let mut frame = frame(&notification(47006));
+        frame[1..HEADER_SIZE].copy_from_slice(&MAX_FRAME_SIZE.to_be_bytes());
+        assert!(read_notification(&mut frame.as_slice()).is_err());
+    }
+
+    #[test]
+    fn gossip_fits_into_a_frame() {
+        let items = vec![vec![0u8; 1024]; 1024];
+        let fitting = fit_in_frame(items);
+        assert!(!fitting.is_empty() && fitting.len() < 1024);
+        assert!(bincode::serialized_size(&fitting).unwrap() <= CHUNK_SIZE + 8);
+    }
+
+    #[test]
+    fn rejects_tampered_frames() {
+        let mut frame = frame(&notification(47005));
+        let last = frame.len() - 1;
+        frame[last] ^= 0xff;
+        assert!(read_notification(&mut frame.as_slice()).is_err());
+    }
+
+    #[test]
+    fn unknown_capabilities_are_ignored() {
+        let newer: Vec<u8> = bincode::serialize(&(1u64, 7u32)).unwrap();
+        let capabilities: Vec<Capability> = bincode::deserialize(&newer).unwrap();
+        assert_eq!(capabilities, vec![Capability::Unknown]);
+        let mut other = Hello::local();
+        other.capabilities = capabilities;
+        assert!(Hello::local().common_capabilities(&other).is_empty());
+        assert_eq!(
+            Hello::local().common_capabilities(&Hello::local()),
+            Hello::local().capabilities
+        );
+    }
+}
@@ -0,0 +1,237 @@
+use crate::interface::{Notification, Peer};
+use crate::network::handshake::update_table_after_delete;
+use crate::network::music_exchange::send_holders_update;
+use crate::network::notification::{tcp_request_with_notification, Content};
+use crate::network::rebalance::PARALLEL_HANDOFFS;
+use crate::network::send_write_request;
+use crate::utils::AppListener;
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Time the other peers have to confirm the next song a leaving peer handed off to them
+const LEAVE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// The songs the local peer handed off while it leaves the network. Like the copies that are
+/// moved to new peers, at most `PARALLEL_HANDOFFS` of them are sent at the same time.
+#[derive(Clone)]
+pub struct Departure {
+    /// The peer each song was handed to, a song is removed once that peer confirmed it
+    pending: HashMap<String, SocketAddr>,
+    /// The songs of `pending` that were not sent yet
+    queue: VecDeque<String>,
+    total: usize,
+    /// When the leave started or the last song was confirmed
+    progress: Instant,
+}
+
+impl Departure {
+    /// Takes the next queued song and its target if fewer than `PARALLEL_HANDOFFS` songs wait
+    /// for their confirmation
+    fn next_to_send(&mut self) -> Option<(String, SocketAddr)> {
+        if self.pending.len().saturating_sub(self.queue.len()) >= PARALLEL_HANDOFFS {
+            return None;
+        }
+        let key = self.queue.pop_front()?;
+        let target = *self.pending.get(&key)?;
+        Some((key, target))
+    }
+}
+
+/// Starts to leave the network. Every song of the local peer is handed off to another peer that
+/// does not hold it yet, and the peer only leaves once all of them confirmed their copies.
+pub fn leave_network(peer: &mut Peer, listener: &mut Box<dyn AppListener + Sync>) {
+    if peer.departure.is_some() {
+        println!("The peer is already leaving the network");
+        return;
+    }
+    if peer.network_table.len() <= 1 {
+        listener.left_network(Ok(()));
+        return;
+    }
+    announce_leaving(peer, false);
+    peer.departure = Some(Departure {
+        pending: HashMap::new(),
+        queue: VecDeque::new(),
+        total: 0,
+        progress: Instant::now(),
+    });
+    let mut unplaced = Vec::new();
+    for song in peer.get_db().keys() {
+        if !hand_off(&song, peer) {
+            unplaced.push(song);
+        }
+    }
+    if !unplaced.is_empty() {
+        cancel_leave(
+            format!("No peer could take over {}", unplaced.join(", ")),
+            peer,
+            listener,
+        );
+        return;
+    }
+    let total = peer.departure.as_ref().map_or(0, |d| d.total);
+    println!("Handing off {} songs before leaving the network", total);
+    listener.leave_progress(0, total);
+    finish_if_done(peer, listener);
+}
+
+/// Queues the song `key` for a peer that takes it over after the local peer left. Songs that
+/// another remaining peer confirmed to hold do not need to be handed off. If every other peer is
+/// listed as a holder but none of them confirmed it, the song is handed to one of them.
+/// # Returns
+/// `false` if there is no peer that can take the song over
+pub fn hand_off(key: &str, peer: &mut Peer) -> bool {
+    if let Some(departure) = &peer.departure {
+        if departure.pending.contains_key(key) {
+            return true;
+        }
+    }
+    let own = peer.ip_address;
+    let holders = peer.holders.get(key).cloned().unwrap_or_default();
+    let live_holders: Vec<SocketAddr> = holders
+        .into_iter()
+        .filter(|h| {
+            *h != own && !peer.leaving.contains(h) && peer.network_table.values().any(|v| v == h)
+        })
+        .collect();
+    let target = match peer.replication_targets(key, 1).pop() {
+        Some(t) => t,
+        None => {
+            let confirmed = live_holders
+                .iter()
+                .any(|h| peer.is_confirmed_holder(key, h));
+            if confirmed {
+                return true;
+            }
+            match live_holders.first() {
+                Some(h) => *h,
+                None => return false,
+            }
+        }
+    };
+    if let Some(departure) = &mut peer.departure {
+        departure.pending.insert(key.to_string(), target);
+        departure.queue.push_back(key.to_string());
+        departure.total += 1;
+    }
+    send_next_handoffs(peer);
+    true
+}
+
+/// Sends the next queued songs as long as fewer than `PARALLEL_HANDOFFS` wait for their
+/// confirmation
+fn send_next_handoffs(peer: &mut Peer) {
+    let own = peer.ip_address;
+    loop {
+        let next = match &mut peer.departure {
+            Some(departure) => departure.next_to_send(),
+            None => None,
+        };
+        match next {
+            Some((key, target)) => send_write_request(target, own, key, peer),
+            None => return,
+        }
+    }
+}
+
+/// Records that the peer at `sender` stored the song `key` the local peer handed off to it
+pub fn handoff_confirmed(
+    key: &str,
+    hash: &str,
+    sender: SocketAddr,
+    peer: &mut Peer,
+    listener: &mut Box<dyn AppListener + Sync>,
+) {
+    if peer.get_db().hash_of(key).as_deref() != Some(hash) {
+        return;
+    }
+    let (handed_off, total) = match &mut peer.departure {
+        Some(departure) if departure.pending.get(key) == Some(&sender) => {
+            departure.pending.remove(key);
+            departure.queue.retain(|queued| queued != key);
+            departure.progress = Instant::now();
+            (departure.total - departure.pending.len(), departure.total)
+        }
+        _ => return,
+    };
+    let own = peer.ip_address;
+    peer.confirm_holder(key, sender);
+    let holders = peer.holders.get(key).cloned().unwrap_or_default();
+    for holder in &holders {
+        if *holder != own {
+            send_holders_update(*holder, own, key, holders.clone());
+        }
+    }
+    listener.leave_progress(handed_off, total);
+    send_next_handoffs(peer);
+    finish_if_done(peer, listener);
+}
+
+/// Cancels the leave if the other peers did not confirm another song within `LEAVE_TIMEOUT`
+pub fn check_leave_timeout(peer: &mut Peer, listener: &mut Box<dyn AppListener + Sync>) {
+    let unconfirmed: Vec<String> = match &peer.departure {
+        Some(departure) if departure.progress.elapsed() > LEAVE_TIMEOUT => {
+            departure.pending.keys().cloned().collect()
+        }
+        _ => return,
+    };
+    cancel_leave(
+        format!("{} were not confirmed in time", unconfirmed.join(", ")),
+        peer,
+        listener,
+    );
+}
+
+/// Takes note that the peer at `sender` leaves the network or stays after all. Peers that leave
+/// do not get new copies of songs.
+pub fn leaving_announced(cancelled: bool, sender: SocketAddr, peer: &mut Peer) {
+    if cancelled {
+        peer.leaving.remove(&sender);
+    } else if peer.id_of(&sender).is_some() {
+        peer.leaving.insert(sender);
+    }
+}
+
+/// Leaves the network once every song that was handed off is confirmed
+fn finish_if_done(peer: &mut Peer, listener: &mut Box<dyn AppListener + Sync>) {
+    match &peer.departure {
+        Some(departure) if departure.pending.is_empty() => {}
+        _ => return,
+    }
+    peer.departure = None;
+    let own = peer.ip_address;
+    for addr in peer.network_table.values() {
+        if *addr != own {
+            update_table_after_delete(*addr, own, &peer.id);
+        }
+    }
+    let id = peer.id.clone();
+    peer.network_table.retain(|k, _| *k == id);
+    peer.peer_names.retain(|k, _| *k == id);
+    peer.hellos.retain(|addr, _| *addr == own);
+    peer.leaving.clear();
+    listener.left_network(Ok(()));
+}
+
+/// Gives up leaving the network, the local peer stays a member and keeps its songs
+fn cancel_leave(reason: String, peer: &mut Peer, listener: &mut Box<dyn AppListener + Sync>) {
+    peer.departure = None;
+    announce_leaving(peer, true);
+    listener.left_network(Err(reason));
+}
+
+/// Tells all other peers that the local peer leaves the network or that it stays after all
+fn announce_leaving(peer: &Peer, cancelled: bool) {
+    let own = peer.ip_address;
+    for addr in peer.network_table.values() {
+        if *addr != own {
+            let not = Notification {
+                content: Content::Leaving { cancelled },
+                from: own,
+                signer: None,
+            };
+            tcp_request_with_notification(*addr, not);
+        }
+    }
+}
@@ -0,0 +1,525 @@
+use crate::interface::Peer;
+use crate::network::music_exchange::send_file_request;
+use crate::network::transfer::TransferKind;
+use crate::utils::LookupResult::{FOUND, MISSING, TIMEOUT};
+use crate::utils::{AppListener, FileInstructions};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Time a peer has to answer a lookup or to start sending a requested file
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// ID that ties the answers of other peers to the file lookup they belong to
+pub type RequestId = u64;
+
+/// A file lookup of the local peer
+#[derive(Clone, Debug)]
+pub struct OpenRequest {
+    pub song_name: String,
+    /// What is done with the song once it is received
+    pub instr: FileInstructions,
+    /// Asked peers that did not answer yet, answers of other peers are ignored
+    pub pending: HashSet<SocketAddr>,
+    /// Peers that answered they have the file and were not asked for it yet
+    pub candidates: VecDeque<SocketAddr>,
+    /// Peers the file was requested from
+    pub tried: Vec<SocketAddr>,
+    /// The peer the file is currently requested from
+    pub fetching: Option<SocketAddr>,
+    /// The lookup or the current download times out after this point in time
+    pub deadline: Instant,
+}
+
+/// The file lookups of the local peer that are not resolved yet
+#[derive(Clone, Default)]
+pub struct RequestTable {
+    requests: HashMap<RequestId, OpenRequest>,
+    /// Songs ordered outside of a lookup, e.g. to repair a missing copy, with the peer they were
+    /// ordered from and the time the order is given up
+    orders: HashMap<String, (SocketAddr, Instant)>,
+}
+
+impl RequestTable {
+    /// Opens a lookup of `song_name` that waits for the answers of the peers in `asked`
+    /// # Returns
+    /// The ID of the new request
+    pub fn open(
+        &mut self,
+        song_name: &str,
+        instr: FileInstructions,
+        asked: Vec<SocketAddr>,
+    ) -> RequestId {
+        let mut id = rand::random::<RequestId>();
+        while self.requests.contains_key(&id) {
+            id = rand::random::<RequestId>();
+        }
+        self.requests.insert(
+            id,
+            OpenRequest {
+                song_name: song_name.to_string(),
+                instr,
+                pending: asked.into_iter().collect(),
+                candidates: VecDeque::new(),
+                tried: Vec::new(),
+                fetching: None,
+                deadline: Instant::now() + REQUEST_TIMEOUT,
+            },
+        );
+        id
+    }
+
+    pub fn get(&self, id: RequestId) -> Option<&OpenRequest> {
+        self.requests.get(&id)
+    }
+
+    pub fn get_mut(&mut self, id: RequestId) -> Option<&mut OpenRequest> {
+        self.requests.get_mut(&id)
+    }
+
+    pub fn remove(&mut self, id: RequestId) -> Option<OpenRequest> {
+        self.requests.remove(&id)
+    }
+
+    /// Waits for the answers of the peers in `asked` to the request `id`, whose peers were only
+    /// known once a lookup finished
+    pub fn ask(&mut self, id: RequestId, asked: &[SocketAddr]) {
+        if let Some(request) = self.requests.get_mut(&id) {
+            request.pending.extend(asked.iter().cloned());
+            request.deadline = Instant::now() + REQUEST_TIMEOUT;
+        }
+    }
+
+    /// The request that downloads `song_name` from the peer at `source`
+    fn fetching(&self, song_name: &str, source: SocketAddr) -> Option<RequestId> {
+        self.requests
+            .iter()
+            .find(|(_, r)| r.song_name == song_name && r.fetching == Some(source))
+            .map(|(id, _)| *id)
+    }
+
+    /// Removes the requests that downloaded `song_name`
+    fn complete(&mut self, song_name: &str) -> Vec<OpenRequest> {
+        let done: Vec<RequestId> = self
+            .requests
+            .iter()
+            .filter(|(_, r)| r.song_name == song_name && r.fetching.is_some())
+            .map(|(id, _)| *id)
+            .collect();
+        done.iter()
+            .filter_map(|id| self.requests.remove(id))
+            .collect()
+    }
+
+    /// Takes note that `song_name` was ordered from the peer at `source` outside of a lookup
+    pub fn order(&mut self, song_name: &str, source: SocketAddr) {
+        self.orders.insert(
+            song_name.to_string(),
+            (source, Instant::now() + REQUEST_TIMEOUT),
+        );
+    }
+
+    /// Checks if the peer at `source` was asked to send `song_name` with the instruction `instr`
+    fn asked(&self, song_name: &str, instr: &FileInstructions, source: SocketAddr) -> bool {
+        let requested = self
+            .requests
+            .values()
+            .any(|r| r.song_name == song_name && r.instr == *instr && r.fetching == Some(source));
+        let ordered = *instr == FileInstructions::ORDER
+            && self
+                .orders
+                .get(song_name)
+                .is_some_and(|(s, deadline)| *s == source && *deadline > Instant::now());
+        requested || ordered
+    }
+
+    fn expired(&self) -> Vec<RequestId> {
+        let now = Instant::now();
+        self.requests
+            .iter()
+            .filter(|(_, r)| r.deadline <= now)
+            .map(|(id, _)| *id)
+            .collect()
+    }
+}
+
+/// Takes note that the peer at `sender` has the requested file. The file is requested from the
+/// first asked peer that answered, the others are asked if that fails. The listener is only told
+/// once the file was received or every peer failed.
+pub fn exist_file_response(
+    song_name: String,
+    id: RequestId,
+    sender: SocketAddr,
+    peer: &mut Peer,
+    listener: &mut Box<dyn AppListener + Sync>,
+) {
+    match peer.open_request_table.get_mut(id) {
+        Some(request) if request.song_name == song_name => {
+            // answers of peers that were not asked or already answered are ignored
+            if !request.pending.remove(&sender) {
+                return;
+            }
+            request.candidates.push_back(sender);
+            if request.fetching.is_some() {
+                return;
+            }
+        }
+        _ => {
+            info!("Did not find requested file");
+            return;
+        }
+    }
+    fetch_next(id, peer, listener);
+}
+
+/// Takes note that the peer at `sender` does not have the requested file. If the file was
+/// requested from that peer, the next peer that has it is asked. The request is resolved as
+/// missing once every asked peer answered and none could send the file.
+pub fn file_not_found(
+    song_name: String,
+    id: RequestId,
+    sender: SocketAddr,
+    peer: &mut Peer,
+    listener: &mut Box<dyn AppListener + Sync>,
+) {
+    match peer.open_request_table.get_mut(id) {
+        Some(request) if request.song_name == song_name => {
+            if request.fetching == Some(sender) {
+                error!(
+                    "{:?} could not send {}, asking the next peer",
+                    sender, song_name
+                );
+            } else {
+                if !request.pending.remove(&sender) {
+                    return;
+                }
+                info!("{:?} does not have {}", sender, song_name);
+                if request.fetching.is_some() {
+                    return;
+                }
+            }
+        }
+        _ => return,
+    }
+    fetch_next(id, peer, listener);
+}
+
+/// Asks the next peer for the file `song_name` after the peer at `source` sent a copy that could
+/// not be used
+pub fn fetch_failed(
+    song_name: &str,
+    source: SocketAddr,
+    peer: &mut Peer,
+    listener: &mut Box<dyn AppListener + Sync>,
+) {
+    if let Some(id) = peer.open_request_table.fetching(song_name, source) {
+        fetch_next(id, peer, listener);
+    }
+}
+
+/// Resolves the requests that received the file `song_name` as found
+pub fn fetch_completed(
+    song_name: &str,
+    peer: &mut Peer,
+    listener: &mut Box<dyn AppListener + Sync>,
+) {
+    for request in peer.open_request_table.complete(song_name) {
+        listener.file_lookup_finished(request.song_name, request.instr, FOUND);
+    }
+}
+
+/// Requests the file from the next peer that answered it has it. The request is resolved as
+/// missing if no such peer is left and every asked peer answered.
+fn fetch_next(id: RequestId, peer: &mut Peer, listener: &mut Box<dyn AppListener + Sync>) {
+    let own = peer.ip_address;
+    let request = match peer.open_request_table.get_mut(id) {
+        Some(r) => r,
+        None => return,
+    };
+    request.fetching = request.candidates.pop_front();
+    match request.fetching {
+        Some(target) => {
+            request.tried.push(target);
+            request.deadline = Instant::now() + REQUEST_TIMEOUT;
+            send_file_request(
+                target,
+                own,
+                &request.song_name,
+                request.instr.clone(),
+                Some(id),
+            );
+        }
+        None if request.pending.is_empty() => {
+            if let Some(request) = peer.open_request_table.remove(id) {
+                listener.file_lookup_finished(request.song_name, request.instr, MISSING);
+            }
+        }
+        // the peers that did not answer yet could still have it
+        None => {}
+    }
+}
+
+/// Checks if the offer of `key` from the peer at `sender` answers a request of the local peer.
+/// Files that were not asked for are rejected, redundant copies are checked when they are stored.
+pub fn offer_requested(key: &str, kind: &TransferKind, sender: SocketAddr, peer: &Peer) -> bool {
+    let instr = match kind {
+        TransferKind::Response(instr) => instr,
+        TransferKind::Redundant { .. } => return true,
+    };
+    if peer.open_request_table.asked(key, instr, sender) {
+        return true;
+    }
+    error!(
+        "Rejected the unrequested offer of {} from {:?}",
+        key, sender
+    );
+    false
+}
+
+/// Handles the requests whose deadline passed. A download that is still transferred gets more
+/// time, a peer that did not start to send the file is replaced by the next one. Lookups without
+/// such a peer are resolved as timed out.
+pub fn expire_requests(peer: &mut Peer, listener: &mut Box<dyn AppListener + Sync>) {
+    let now = Instant::now();
+    peer.open_request_table
+        .orders
+        .retain(|_, (_, deadline)| *deadline > now);
+    for id in peer.open_request_table.expired() {
+        let (song_name, fetching) = match peer.open_request_table.get(id) {
+            Some(request) => (request.song_name.clone(), request.fetching),
+            None => continue,
+        };
+        match fetching {
+            Some(source) => {
+                let transferring = peer
+                    .transfers
+                    .get(&song_name)
+                    .is_some_and(|t| t.source == source);
+                if transferring {
+                    if let Some(request) = peer.open_request_table.get_mut(id) {
+                        request.deadline = Instant::now() + REQUEST_TIMEOUT;
+                    }
+                    continue;
+                }
+                error!("{:?} did not send {} in time", source, song_name);
+                fetch_next(id, peer, listener);
+            }
+            None => {
+                if let Some(request) = peer.open_request_table.remove(id) {
+                    listener.file_lookup_finished(request.song_name, request.instr, TIMEOUT);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::Database;
+    use crate::metadata::SongMetadata;
+    use crate::network::identity::PeerId;
+    use crate::network::transfer::{file_offer, Manifest, StreamTarget};
+    use crate::utils::{Config, FileStatus, LookupResult};
+    use std::convert::TryFrom;
+    use std::sync::mpsc::sync_channel;
+    use std::sync::{Arc, Mutex};
+
+    /// Records the results of the lookups
+    struct Recorder(Arc<Mutex<Vec<LookupResult>>>);
+
+    impl AppListener for Recorder {
+        fn notify_status(&self, _: Vec<(String, SongMetadata)>, _: String) {}
+        fn local_database_changed(&mut self, _: String, _: FileStatus) {}
+        fn player_playing(&mut self, _: Option<String>) {}
+        fn player_stopped(&mut self) {}
+        fn stream_buffering(&mut self, _: String, _: bool) {}
+        fn delete_rejected(&mut self, _: String, _: String) {}
+        fn leave_progress(&mut self, _: usize, _: usize) {}
+        fn left_network(&mut self, _: Result<(), String>) {}
+        fn file_lookup_finished(&mut self, _: String, _: FileInstructions, result: LookupResult) {
+            self.0.lock().unwrap().push(result);
+        }
+    }
+
+    struct NullStream;
+
+    impl StreamTarget for NullStream {
+        fn free_space(&self) -> u64 {
+            u64::MAX
+        }
+        fn push(&self, _: &[u8]) {}
+        fn finish(&self) {}
+        fn is_closed(&self) -> bool {
+            false
+        }
+    }
+
+    fn setup() -> (
+        Peer,
+        Box<dyn AppListener + Sync>,
+        Arc<Mutex<Vec<LookupResult>>>,
+    ) {
+        let (sender, _) = sync_channel(16);
+        let peer = Peer::create(
+            SocketAddr::from(([127, 0, 0, 1], 1)),
+            PeerId::try_from(format!("{:064x}", 1)).unwrap(),
+            "test",
+            sender,
+            Database::new(),
+            &Config::default(),
+        );
+        let results = Arc::new(Mutex::new(Vec::new()));
+        let listener: Box<dyn AppListener + Sync> = Box::new(Recorder(results.clone()));
+        (peer, listener, results)
+    }
+
+    /// Address nobody listens on, requests to it fail right away
+    fn source(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn open_requests_get_unique_ids() {
+        let mut table = RequestTable::default();
+        let first = table.open("song", FileInstructions::GET, vec![source(9), source(10)]);
+        let second = table.open("song", FileInstructions::GET, vec![source(9), source(10)]);
+        assert_ne!(first, second);
+        assert_eq!(table.get(first).map(|r| r.pending.len()), Some(2));
+        assert!(table.expired().is_empty());
+        table.get_mut(first).unwrap().deadline = Instant::now();
+        assert_eq!(table.expired(), vec![first]);
+        assert!(table.remove(first).is_some());
+        assert!(table.get(first).is_none());
+    }
+
+    #[test]
+    fn only_downloading_requests_are_completed() {
+        let mut table = RequestTable::default();
+        let waiting = table.open("song", FileInstructions::GET, vec![source(9)]);
+        let downloading = table.open("song", FileInstructions::GET, vec![source(9)]);
+        table.get_mut(downloading).unwrap().fetching = Some(source(9));
+        assert_eq!(table.fetching("song", source(9)), Some(downloading));
+        assert_eq!(table.complete("song").len(), 1);
+        assert!(table.get(waiting).is_some());
+        assert!(table.get(downloading).is_none());
+    }
+
+    #[test]
+    fn finished_stream_resolves_its_request_once() {
+        let (mut peer, mut listener, results) = setup();
+        let id = peer
+            .open_request_table
+            .open("song", FileInstructions::PLAY, vec![source(9)]);
+        exist_file_response("song".to_string(), id, source(9), &mut peer, &mut listener);
+        assert_eq!(
+            peer.open_request_table.get(id).unwrap().fetching,
+            Some(source(9))
+        );
+        assert!(results.lock().unwrap().is_empty());
+        peer.open_request_table.get_mut(id).unwrap().deadline = Instant::now();
+
+        let manifest = Manifest {
+            size: 0,
+            hash: String::new(),
+            chunk_hashes: Vec::new(),
+        };
+        let stream: Arc<dyn StreamTarget> = Arc::new(NullStream);
+        let kind = TransferKind::Response(FileInstructions::PLAY);
+        let received = file_offer(
+            "song".to_string(),
+            kind,
+            manifest,
+            Some(stream),
+            source(9),
+            &mut peer,
+        );
+        let (transfer, path) = received.expect("the empty stream is complete");
+        assert!(path.is_none());
+        fetch_completed(&transfer.key, &mut peer, &mut listener);
+        expire_requests(&mut peer, &mut listener);
+
+        assert!(peer.open_request_table.get(id).is_none());
+        assert_eq!(*results.lock().unwrap(), vec![FOUND]);
+    }
+
+    #[test]
+    fn failed_download_is_reported_once() {
+        let (mut peer, mut listener, results) = setup();
+        let id = peer.open_request_table.open(
+            "song",
+            FileInstructions::GET,
+            vec![source(9), source(10)],
+        );
+        exist_file_response("song".to_string(), id, source(9), &mut peer, &mut listener);
+        exist_file_response("song".to_string(), id, source(10), &mut peer, &mut listener);
+        fetch_failed("song", source(9), &mut peer, &mut listener);
+        assert_eq!(
+            peer.open_request_table.get(id).unwrap().fetching,
+            Some(source(10))
+        );
+        file_not_found("song".to_string(), id, source(10), &mut peer, &mut listener);
+        expire_requests(&mut peer, &mut listener);
+
+        assert!(peer.open_request_table.get(id).is_none());
+        assert_eq!(*results.lock().unwrap(), vec![MISSING]);
+    }
+
+    #[test]
+    fn answers_of_unasked_peers_are_ignored() {
+        let (mut peer, mut listener, results) = setup();
+        let id = peer
+            .open_request_table
+            .open("song", FileInstructions::GET, Vec::new());
+        exist_file_response("song".to_string(), id, source(9), &mut peer, &mut listener);
+        file_not_found("song".to_string(), id, source(9), &mut peer, &mut listener);
+        assert_eq!(peer.open_request_table.get(id).unwrap().fetching, None);
+        assert!(results.lock().unwrap().is_empty());
+
+        peer.open_request_table.ask(id, &[source(9), source(10)]);
+        file_not_found("song".to_string(), id, source(9), &mut peer, &mut listener);
+        file_not_found("song".to_string(), id, source(9), &mut peer, &mut listener);
+        assert!(results.lock().unwrap().is_empty());
+        file_not_found("song".to_string(), id, source(10), &mut peer, &mut listener);
+        assert!(peer.open_request_table.get(id).is_none());
+        assert_eq!(*results.lock().unwrap(), vec![MISSING]);
+    }
+
+    #[test]
+    fn only_requested_offers_are_accepted() {
+        let (mut peer, mut listener, _) = setup();
+        let play = TransferKind::Response(FileInstructions::PLAY);
+        let order = TransferKind::Response(FileInstructions::ORDER);
+        assert!(!offer_requested("song", &play, source(9), &peer));
+        assert!(!offer_requested("song", &order, source(9), &peer));
+
+        let id = peer.open_request_table.open(
+            "song",
+            FileInstructions::PLAY,
+            vec![source(9), source(10)],
+        );
+        exist_file_response("song".to_string(), id, source(9), &mut peer, &mut listener);
+        assert!(offer_requested("song", &play, source(9), &peer));
+        assert!(!offer_requested("song", &play, source(10), &peer));
+        assert!(!offer_requested("other", &play, source(9), &peer));
+        assert!(!offer_requested("song", &order, source(9), &peer));
+
+        peer.open_request_table.order("song", source(10));
+        assert!(offer_requested("song", &order, source(10), &peer));
+        let redundant = TransferKind::Redundant {
+            from: "peer".to_string(),
+        };
+        assert!(offer_requested("song", &redundant, source(11), &peer));
+    }
+
+    #[test]
+    fn unanswered_lookup_times_out() {
+        let (mut peer, mut listener, results) = setup();
+        let id = peer
+            .open_request_table
+            .open("song", FileInstructions::GET, vec![source(9)]);
+        peer.open_request_table.get_mut(id).unwrap().deadline = Instant::now();
+        expire_requests(&mut peer, &mut listener);
+        assert_eq!(*results.lock().unwrap(), vec![TIMEOUT]);
+    }
+}
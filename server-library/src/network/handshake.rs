@@ -1,4 +1,7 @@
 use crate::interface::*;
+use crate::network::codec::{MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+use crate::network::identity::PeerId;
+use crate::network::invite::membership_proof;
 use crate::network::notification::*;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
@@ -6,23 +9,94 @@ use std::net::SocketAddr;
 use std::str::FromStr;
 use std::string::ToString;
 
+/// Optional features of the protocol. Two peers only use a feature if both advertise it.
+/// Capabilities of newer builds that the local build does not know are read as `Unknown`.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Copy, Debug)]
+pub enum Capability {
+    #[serde(other)]
+    Unknown,
+}
+
+/// The capabilities this build supports, the first version of the protocol has none
+const LOCAL_CAPABILITIES: &[Capability] = &[];
+
+/// Protocol version and capabilities a peer advertises when it joins a network.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub struct Hello {
+    pub version: u8,
+    pub min_version: u8,
+    pub capabilities: Vec<Capability>,
+    /// Bytes of songs the peer can still take over when it joins, `None` if it has no limit.
+    /// Songs are only moved to a new peer as long as it has room for them.
+    pub capacity: Option<u64>,
+}
+
+impl Hello {
+    /// The `Hello` of the local build
+    pub fn local() -> Hello {
+        Hello {
+            version: PROTOCOL_VERSION,
+            min_version: MIN_PROTOCOL_VERSION,
+            capabilities: LOCAL_CAPABILITIES.to_vec(),
+            capacity: None,
+        }
+    }
+
+    /// Checks if a peer that advertised `self` can talk to the local build. Returns the reason
+    /// for the rejection on `Err`.
+    pub fn check_compatible(&self) -> Result<(), String> {
+        if self.version < MIN_PROTOCOL_VERSION {
+            return Err(format!(
+                "Protocol version {} is too old, the network needs at least version {}",
+                self.version, MIN_PROTOCOL_VERSION
+            ));
+        }
+        if self.min_version > PROTOCOL_VERSION {
+            return Err(format!(
+                "Protocol version {} is too old for the joining peer, it needs at least version {}",
+                PROTOCOL_VERSION, self.min_version
+            ));
+        }
+        Ok(())
+    }
+
+    /// Returns the capabilities both `self` and `other` support
+    pub fn common_capabilities(&self, other: &Hello) -> Vec<Capability> {
+        self.capabilities
+            .iter()
+            .filter(|c| **c != Capability::Unknown && other.capabilities.contains(c))
+            .cloned()
+            .collect()
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct NetworkInfo {
+    id: PeerId,
     name: String,
     address: String,
+    #[serde(default)]
+    hello: Option<Hello>,
 }
 
-pub fn json_string_to_network_table(json_string: String) -> HashMap<String, SocketAddr> {
+/// A peer of a network table that was sent by another peer
+pub struct PeerInfo {
+    pub id: PeerId,
+    pub name: String,
+    pub addr: SocketAddr,
+    pub hello: Option<Hello>,
+}
+
+pub fn json_string_to_network_table(json_string: String) -> Vec<PeerInfo> {
     let info_array: Vec<NetworkInfo> = match serde_json::from_str(json_string.as_str()) {
         Ok(val) => val,
         Err(_e) => {
             println!("no parcing hashmap");
-            return HashMap::new();
+            return Vec::new();
         }
     };
-    let mut hashmap = HashMap::new();
+    let mut peers = Vec::new();
     for info in info_array {
-        let key = info.name;
         let addr = match SocketAddr::from_str(info.address.as_str()) {
             Ok(a) => a,
             Err(e) => {
@@ -30,19 +104,30 @@ pub fn json_string_to_network_table(json_string: String) -> HashMap<String, Sock
                 continue;
             }
         };
-        hashmap.insert(key, addr);
+        peers.push(PeerInfo {
+            id: info.id,
+            name: info.name,
+            addr,
+            hello: info.hello,
+        });
     }
-    hashmap
+    peers
 }
 
+/// Serializes the network table together with the name and the `Hello` of every peer that is
+/// known.
 pub fn network_table_to_json(
-    network_table: &HashMap<String, SocketAddr>,
+    network_table: &HashMap<PeerId, SocketAddr>,
+    peer_names: &HashMap<PeerId, String>,
+    hellos: &HashMap<SocketAddr, Hello>,
 ) -> Result<String, String> {
     let mut array = vec![];
     for (key, address) in network_table {
         array.push(NetworkInfo {
-            name: key.clone(),
+            id: key.clone(),
+            name: peer_names.get(key).cloned().unwrap_or_default(),
             address: address.clone().to_string(),
+            hello: hellos.get(address).cloned(),
         });
     }
     match serde_json::to_string(&array) {
@@ -57,19 +142,27 @@ pub fn network_table_to_json(
     }
 }
 
+/// Sends the network table to the peer at `target`, which joined with a valid invite. The table
+/// carries the network secret itself, because every member needs it to prove its membership and
+/// to create invites, so an invite grants full membership. The secret is only protected by the
+/// encryption of the connection, and a member can not be excluded again without starting a new
+/// network.
 pub fn send_network_table_request(target: SocketAddr, peer: &Peer) {
-    let value = match network_table_to_json(&peer.network_table) {
+    let value = match network_table_to_json(&peer.network_table, &peer.peer_names, &peer.hellos) {
         Ok(v) => v,
         Err(e) => {
             error!("{}", e);
             return;
         }
     };
+    let secret = peer.network_secret.clone().unwrap_or_default();
     let not = Notification {
         content: Content::SendNetworkTable {
             value: value.into_bytes(),
+            secret,
         },
         from: peer.ip_address,
+        signer: None,
     };
 
     tcp_request_with_notification(target, not);
@@ -78,9 +171,12 @@ pub fn send_network_table_request(target: SocketAddr, peer: &Peer) {
 pub fn send_network_update_table_request(
     target: SocketAddr,
     from: SocketAddr,
-    hashmap: &HashMap<String, SocketAddr>,
+    proof: &str,
+    hashmap: &HashMap<PeerId, SocketAddr>,
+    peer_names: &HashMap<PeerId, String>,
+    hellos: &HashMap<SocketAddr, Hello>,
 ) {
-    let value = match network_table_to_json(hashmap) {
+    let value = match network_table_to_json(hashmap, peer_names, hellos) {
         Ok(v) => v,
         Err(e) => {
             error!("{}", e);
@@ -90,55 +186,88 @@ pub fn send_network_update_table_request(
     let not = Notification {
         content: Content::SendNetworkUpdateTable {
             value: value.into_bytes(),
+            proof: proof.to_string(),
         },
         from,
-    };
-
-    tcp_request_with_notification(target, not);
-}
-
-pub fn send_change_name_request(target: SocketAddr, from: SocketAddr, name: &str) {
-    let not = Notification {
-        content: Content::ChangePeerName {
-            value: name.to_string(),
-        },
-        from,
+        signer: None,
     };
 
     tcp_request_with_notification(target, not);
 }
 
 pub fn send_table_to_all_peers(peer: &Peer) {
-    let mut hashmap: HashMap<String, SocketAddr> = HashMap::new();
-    hashmap.insert(peer.name.to_string(), peer.ip_address);
+    let mut hashmap: HashMap<PeerId, SocketAddr> = HashMap::new();
+    hashmap.insert(peer.id.clone(), peer.ip_address);
+    let proof = match &peer.network_secret {
+        Some(secret) => membership_proof(secret, peer.id.as_str()),
+        None => String::new(),
+    };
 
     let network_table = peer.network_table.clone();
     for (key, value) in network_table {
         // just update all other peers
-        if key != peer.name {
-            send_network_update_table_request(value, peer.ip_address, &hashmap);
+        if key != peer.id {
+            send_network_update_table_request(
+                value,
+                peer.ip_address,
+                &proof,
+                &hashmap,
+                &peer.peer_names,
+                &peer.hellos,
+            );
         }
     }
 }
 
-/// Request to get hashmap table
-pub fn send_table_request(target: SocketAddr, from: SocketAddr, name: &str) {
+/// Request to get hashmap table, the request advertises the `Hello` of the local build and
+/// presents the invite token of the network
+/// # Returns
+/// Error message as `String` on `Err` if there is no peer at `target`
+pub fn send_table_request(
+    target: SocketAddr,
+    from: SocketAddr,
+    name: &str,
+    invite: Option<String>,
+) -> Result<(), String> {
     let not = Notification {
         content: Content::RequestForTable {
             value: name.to_string(),
+            hello: Hello::local(),
+            invite,
         },
         from,
+        signer: None,
     };
 
-    tcp_request_with_notification(target, not);
+    match try_tcp_request(target, &not) {
+        Ok(_) => Ok(()),
+        Err(_e) => Err(format!(
+            "There is no existing network containing this IP {:?}\nPlease check the IP-Address you want to join",
+            target
+        )),
+    }
 }
 
-pub fn update_table_after_delete(target: SocketAddr, from: SocketAddr, name: &str) {
+/// Tells a joining peer that it can not join the network
+pub fn send_join_rejected(target: SocketAddr, from: SocketAddr, reason: &str) {
     let not = Notification {
-        content: Content::DeleteFromNetwork {
-            name: name.to_string(),
+        content: Content::JoinRejected {
+            reason: reason.to_string(),
+            hello: Hello::local(),
         },
         from,
+        signer: None,
+    };
+
+    tcp_request_with_notification(target, not);
+}
+
+/// Tells the peer at `target` that the local peer `id` leaves the network
+pub fn update_table_after_delete(target: SocketAddr, from: SocketAddr, id: &PeerId) {
+    let not = Notification {
+        content: Content::DeleteFromNetwork { id: id.clone() },
+        from,
+        signer: None,
     };
 
     tcp_request_with_notification(target, not);
@@ -1,91 +1,136 @@
 use crate::interface::Notification;
+use crate::network::file_request::RequestId;
 use crate::network::notification::{tcp_request_with_notification, Content};
+use crate::network::ownership::Ownership;
+use crate::network::tombstone::Tombstone;
 use crate::utils::FileInstructions;
 use std::net::SocketAddr;
-use std::time::SystemTime;
-use std::thread;
 
 /// Sends a request to the other peers to check if they have the wanted file
-pub fn read_file_exist(target: SocketAddr, from: SocketAddr, name: &str, id: SystemTime) {
+pub fn read_file_exist(target: SocketAddr, from: SocketAddr, name: &str, id: RequestId) {
     let not = Notification {
         content: Content::ExistFile {
             song_name: name.to_string(),
             id,
         },
         from,
+        signer: None,
     };
 
     tcp_request_with_notification(target, not);
 }
 
 /// Sends a response (to ExistFile Request) to let one peer know to have a requested file
-pub fn send_exist_response(target: SocketAddr, from: SocketAddr, name: &str, id: SystemTime) {
+pub fn send_exist_response(target: SocketAddr, from: SocketAddr, name: &str, id: RequestId) {
     let not = Notification {
         content: Content::ExistFileResponse {
             song_name: name.to_string(),
             id,
         },
         from,
+        signer: None,
+    };
+
+    tcp_request_with_notification(target, not);
+}
+
+/// Sends a response (to ExistFile Request) to let one peer know that the requested file is not
+/// stored on the local peer
+pub fn send_file_not_found(target: SocketAddr, from: SocketAddr, name: &str, id: RequestId) {
+    let not = Notification {
+        content: Content::FileNotFound {
+            song_name: name.to_string(),
+            id,
+        },
+        from,
+        signer: None,
     };
 
     tcp_request_with_notification(target, not);
 }
 
 /// Sends a request (as a response of ExistFileResponse Request) to get a certain file
-pub fn send_file_request(target: SocketAddr, from: SocketAddr, name: &str, instr: FileInstructions) {
+/// `id` is the open request the file is requested for, if any
+pub fn send_file_request(
+    target: SocketAddr,
+    from: SocketAddr,
+    name: &str,
+    instr: FileInstructions,
+    id: Option<RequestId>,
+) {
     let not = Notification {
         content: Content::GetFile {
             instr,
             key: name.to_string(),
+            id,
         },
         from,
+        signer: None,
+    };
+
+    tcp_request_with_notification(target, not);
+}
+
+pub fn song_order_request(target: SocketAddr, from: SocketAddr, song_name: String) {
+    let not = Notification {
+        content: Content::OrderSongRequest { song_name },
+        from,
+        signer: None,
     };
 
     tcp_request_with_notification(target, not);
 }
 
-/// Sends a response to a GetFile Request containing the music data
-pub fn send_get_file_reponse(
+/// Tells a holder of `key` which peers hold a copy of it
+pub fn send_holders_update(
     target: SocketAddr,
     from: SocketAddr,
     key: &str,
-    value: Vec<u8>,
-    instr: FileInstructions,
+    holders: Vec<SocketAddr>,
 ) {
     let not = Notification {
-        content: Content::GetFileResponse {
-            instr,
+        content: Content::HoldersUpdate {
             key: key.to_string(),
-            value,
+            holders,
         },
         from,
+        signer: None,
     };
 
-    if let Err(_e) = thread::Builder::new()
-        .name("send_get_file_reponse_thread".to_string())
-        .spawn(move || {
-            tcp_request_with_notification(target, not);
-        }) {
-        error!("could not spwan request_thread");
-    }
+    tcp_request_with_notification(target, not);
 }
 
-pub fn song_order_request(target: SocketAddr, from: SocketAddr, song_name: String) {
+/// Sends a request to delete redundant file
+pub fn delete_redundant_song_request(target: SocketAddr, from: SocketAddr, tombstone: Tombstone) {
     let not = Notification {
-        content: Content::OrderSongRequest { song_name },
+        content: Content::DeleteFileRequest { tombstone },
         from,
+        signer: None,
     };
 
     tcp_request_with_notification(target, not);
 }
 
-/// Sends a request to delete redundant file
-pub fn delete_redundant_song_request(target: SocketAddr, from: SocketAddr, song_name: &str) {
+/// Tells the peer that asked to delete `song_name` why the local peer did not delete it
+pub fn send_delete_rejected(target: SocketAddr, from: SocketAddr, song_name: &str, reason: &str) {
     let not = Notification {
-        content: Content::DeleteFileRequest {
+        content: Content::DeleteRejected {
             song_name: song_name.to_string(),
+            reason: reason.to_string(),
         },
         from,
+        signer: None,
+    };
+
+    tcp_request_with_notification(target, not);
+}
+
+/// Sends the new ownership record of a song to another peer
+pub fn send_ownership_update(target: SocketAddr, from: SocketAddr, ownership: Ownership) {
+    let not = Notification {
+        content: Content::OwnershipUpdate { ownership },
+        from,
+        signer: None,
     };
 
     tcp_request_with_notification(target, not);
@@ -0,0 +1,80 @@
+use crate::network::identity::{sign, verify_signed_by, PeerId};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Records who uploaded a copy of a song and who else may remove it from the network. The record
+/// is signed by the owner, so every peer can pass it on with the song but none can change it.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Ownership {
+    pub title: String,
+    /// Content hash of the copy the record belongs to, another upload with the same title has its
+    /// own record
+    pub hash: String,
+    pub owner: PeerId,
+    /// Peers the owner allowed to remove the song
+    pub delegates: Vec<PeerId>,
+    /// Milliseconds since the epoch when the owner signed the record, a newer record replaces
+    /// an older one
+    pub issued: u64,
+    signature: Vec<u8>,
+}
+
+impl Ownership {
+    /// Creates the record of the copy of the song `title` with the content hash `hash` of `owner`,
+    /// signed with the identity of the local peer at `addr`
+    pub fn create(
+        title: &str,
+        hash: &str,
+        owner: PeerId,
+        delegates: Vec<PeerId>,
+        addr: &SocketAddr,
+    ) -> Result<Ownership, String> {
+        let mut ownership = Ownership {
+            title: title.to_string(),
+            hash: hash.to_string(),
+            owner,
+            delegates,
+            issued: unix_millis(),
+            signature: Vec::new(),
+        };
+        let (_, signature) = sign(addr, &ownership.signed_bytes()?)?;
+        ownership.signature = signature;
+        Ok(ownership)
+    }
+
+    fn signed_bytes(&self) -> Result<Vec<u8>, String> {
+        let claims = (
+            &self.title,
+            &self.hash,
+            &self.owner,
+            &self.delegates,
+            self.issued,
+        );
+        match bincode::serialize(&claims) {
+            Ok(b) => Ok(b),
+            Err(e) => Err(format!(
+                "Could not serialize the ownership of {}: {:?}",
+                self.title, e
+            )),
+        }
+    }
+
+    /// Checks that the record was signed by its owner
+    pub fn verify(&self) -> Result<(), String> {
+        verify_signed_by(&self.owner, &self.signed_bytes()?, &self.signature)
+    }
+
+    /// Checks if the peer `id` may remove the song from the network
+    pub fn may_remove(&self, id: &PeerId) -> bool {
+        self.owner == *id || self.delegates.contains(id)
+    }
+}
+
+/// Milliseconds since the epoch, the time ownership records and tombstones are compared by
+pub(crate) fn unix_millis() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_millis() as u64,
+        Err(_e) => 0,
+    }
+}
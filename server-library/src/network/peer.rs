@@ -1,35 +1,94 @@
 use crate::database::Database;
 use crate::interface::Notification;
 use crate::interface::Peer;
+use crate::network::dht::{node_id, RoutingTable};
+use crate::network::file_request::RequestTable;
 use crate::network::get_own_ip_address;
-use crate::utils::FileInstructions;
-use std::collections::HashMap;
+use crate::network::handshake::{Capability, Hello};
+use crate::network::identity::{Identity, PeerId};
+use crate::network::invite::{load_secret, mint_invite, save_secret};
+use crate::network::ownership::Ownership;
+use crate::network::placement::HashRing;
+use crate::network::rebalance::Rebalancer;
+use crate::network::secure::allow_plaintext;
+use crate::network::tombstone::Tombstone;
+use crate::network::transfer::load_transfers;
+use crate::utils::{Config, RoutingMode};
+use std::collections::{HashMap, HashSet};
 use std::net::SocketAddr;
 use std::string::ToString;
 use std::sync::mpsc::SyncSender;
-use std::time::SystemTime;
+use std::time::Duration;
 
 impl Peer {
     /// Creates a new `Peer`
     /// # Arguments:
     /// * `ip_address` - `SocketAddr` that represents the own network address
+    /// * `id` - the stable ID of the Peer
     /// * `own_name` - String that denotes the name of the Peer
-    /// * `network_table` - HashMap that contains the addresses of the other Peers in the network
+    /// * `sender` - channel to the thread that handles the notifications of the Peer
+    /// * `database` - the local `Database`, the holders table and unfinished transfers are
+    ///   restored from it
+    /// * `config` - the replication factor, routing mode, capacity and song size limit of the
+    ///   Peer are taken from it
     pub fn create(
         ip_address: SocketAddr,
+        id: PeerId,
         onw_name: &str,
-        network_table: HashMap<String, SocketAddr>,
-        open_request_table: HashMap<SystemTime, FileInstructions>,
         sender: SyncSender<Notification>,
+        database: Database,
+        config: &Config,
     ) -> Peer {
+        let holders = database.load_holders_table();
+        let owners = database.load_owners_table();
+        let tombstones = database.load_tombstones();
+        let network_secret = database.data_dir().and_then(load_secret);
+        let mut hellos = HashMap::new();
+        let hello = Hello {
+            capacity: config
+                .capacity
+                .map(|c| c.saturating_sub(database.used_space())),
+            ..Hello::local()
+        };
+        hellos.insert(ip_address, hello);
+        let transfers = load_transfers(database.transfer_dir());
+        let routing = if config.routing == RoutingMode::Dht {
+            Some(RoutingTable::new(node_id(&id)))
+        } else {
+            None
+        };
+        let mut peer_names = HashMap::new();
+        peer_names.insert(id.clone(), onw_name.to_string());
+        let mut network_table = HashMap::new();
+        network_table.insert(id.clone(), ip_address);
         Peer {
             name: onw_name.to_string(),
+            id,
             ip_address,
             network_table,
-            database: Database::new(),
-            open_request_table,
+            peer_names,
+            database,
+            open_request_table: RequestTable::default(),
             sender,
-            redundancy_table: HashMap::new(),
+            holders,
+            confirmed_holders: HashMap::new(),
+            replication_factor: config.replication_factor,
+            max_song_size: config.max_song_size,
+            hellos,
+            transfers,
+            chunk_sessions: HashMap::new(),
+            routing,
+            lookups: HashMap::new(),
+            network_secret,
+            owners,
+            tombstones,
+            rebalancer: Rebalancer::default(),
+            departure: None,
+            leaving: HashSet::new(),
+            listings: HashMap::new(),
+            members: HashSet::new(),
+            join_target: None,
+            redeemed_invites: HashMap::new(),
         }
     }
 
@@ -41,42 +100,283 @@ impl Peer {
         &self.database
     }
 
-    pub fn get_network(&self) -> &HashMap<String, SocketAddr> {
+    pub fn get_network(&self) -> &HashMap<PeerId, SocketAddr> {
         &self.network_table
     }
 
-    pub fn process_store_request(&mut self, data: (String, Vec<u8>)) {
-        self.database.data.insert(data.0, data.1);
+    /// Checks if the peer `id` is the local peer or a member of its network
+    pub fn is_member(&self, id: &PeerId) -> bool {
+        *id == self.id || self.network_table.contains_key(id) || self.members.contains(id)
+    }
+
+    /// Returns the display name of the peer `id`, or the start of its ID if it did not tell
+    /// its name
+    pub fn display_name(&self, id: &PeerId) -> String {
+        match self.peer_names.get(id) {
+            Some(name) => name.clone(),
+            None => id.short().to_string(),
+        }
     }
 
-    pub fn find_file(&self, name: &str) -> Option<&Vec<u8>> {
-        self.database.data.get(name)
+    /// Returns the ID of the peer at `addr` if it is in the network table
+    pub fn id_of(&self, addr: &SocketAddr) -> Option<PeerId> {
+        self.network_table
+            .iter()
+            .find(|(_, a)| *a == addr)
+            .map(|(id, _)| id.clone())
+    }
+
+    /// Stores the secret of the network the peer belongs to in the data directory
+    pub(crate) fn set_network_secret(&mut self, secret: Vec<u8>) {
+        if let Some(dir) = self.database.data_dir() {
+            save_secret(dir, &secret);
+        }
+        self.network_secret = Some(secret);
+    }
+
+    /// Creates an invite token for the network that expires after `valid_for`
+    pub fn create_invite(&self, valid_for: Duration) -> Result<String, String> {
+        match &self.network_secret {
+            Some(secret) => Ok(mint_invite(secret, valid_for)),
+            None => Err("The peer is not a member of a network yet".to_string()),
+        }
+    }
+
+    pub fn process_store_request(&mut self, data: (String, Vec<u8>)) -> Result<(), String> {
+        self.database.insert(&data.0, &data.1)
+    }
+
+    pub fn find_file(&self, name: &str) -> Option<Vec<u8>> {
+        self.database.get(name)
     }
 
     pub fn does_file_exist(&self, name: &str) -> bool {
-        match self.database.data.get(name) {
-            Some(_t) => true,
+        self.database.contains(name)
+    }
+
+    pub fn delete_file_from_database(&mut self, song_name: &str) {
+        if let Err(e) = self.database.remove(song_name) {
+            error!("{}", e);
+        }
+    }
+
+    /// Records that the peer at `addr` holds a copy of `song_name` and persists the holders
+    /// table.
+    pub fn add_holder(&mut self, song_name: &str, addr: SocketAddr) {
+        let holders = self.holders.entry(song_name.to_string()).or_default();
+        if !holders.contains(&addr) {
+            holders.push(addr);
+            self.database.save_holders_table(&self.holders);
+        }
+    }
+
+    /// Records that the peer at `addr` confirmed that it stored a copy of `song_name`
+    pub fn confirm_holder(&mut self, song_name: &str, addr: SocketAddr) {
+        self.add_holder(song_name, addr);
+        self.confirmed_holders
+            .entry(song_name.to_string())
+            .or_default()
+            .insert(addr);
+    }
+
+    /// Checks if the peer at `addr` confirmed that it stored a copy of `song_name`
+    pub fn is_confirmed_holder(&self, song_name: &str, addr: &SocketAddr) -> bool {
+        self.confirmed_holders
+            .get(song_name)
+            .is_some_and(|confirmed| confirmed.contains(addr))
+    }
+
+    /// Replaces the holders of `song_name` and persists the holders table.
+    pub fn set_holders(&mut self, song_name: &str, holders: Vec<SocketAddr>) {
+        if let Some(confirmed) = self.confirmed_holders.get_mut(song_name) {
+            confirmed.retain(|addr| holders.contains(addr));
+        }
+        self.holders.insert(song_name.to_string(), holders);
+        self.database.save_holders_table(&self.holders);
+    }
+
+    /// Forgets the holders of `song_name` after it was removed from the network.
+    pub fn remove_song_holders(&mut self, song_name: &str) {
+        self.confirmed_holders.remove(song_name);
+        if self.holders.remove(song_name).is_some() {
+            self.database.save_holders_table(&self.holders);
+        }
+    }
+
+    /// Removes `addr` from the holders of all songs and persists the holders table.
+    /// # Returns
+    /// The songs `addr` was holding
+    pub fn remove_holder(&mut self, addr: &SocketAddr) -> Vec<String> {
+        let mut songs = Vec::new();
+        for confirmed in self.confirmed_holders.values_mut() {
+            confirmed.remove(addr);
+        }
+        for (song, holders) in self.holders.iter_mut() {
+            if let Some(index) = holders.iter().position(|h| h == addr) {
+                holders.remove(index);
+                songs.push(song.clone());
+            }
+        }
+        if !songs.is_empty() {
+            self.database.save_holders_table(&self.holders);
+        }
+        songs
+    }
+
+    /// Keeps the ownership record of a song if it was signed by its owner and belongs to the copy
+    /// with the content hash `hash` the local peer holds or receives. A known record of the same
+    /// copy is only replaced by a newer one of the same owner, the record of another copy is
+    /// replaced along with that copy.
+    pub(crate) fn record_ownership(&mut self, ownership: Ownership, hash: &str) {
+        if let Err(e) = ownership.verify() {
+            error!("Ignored the ownership record of {}: {}", ownership.title, e);
+            return;
+        }
+        if ownership.hash != hash {
+            info!(
+                "Ignored the ownership record of another copy of {}",
+                ownership.title
+            );
+            return;
+        }
+        if let Some(known) = self.owners.get(&ownership.title) {
+            let same_copy = known.hash == ownership.hash;
+            if same_copy && (known.owner != ownership.owner || known.issued > ownership.issued) {
+                return;
+            }
+        }
+        self.owners.insert(ownership.title.clone(), ownership);
+        self.database.save_owners_table(&self.owners);
+    }
+
+    /// Forgets the ownership record of `song_name` after it was removed from the network
+    pub(crate) fn remove_ownership(&mut self, song_name: &str) {
+        if self.owners.remove(song_name).is_some() {
+            self.database.save_owners_table(&self.owners);
+        }
+    }
+
+    /// Checks if the peer `id` may remove `song_name` from the network. Songs without an
+    /// ownership record were uploaded before owners were recorded and may be removed by anyone.
+    pub fn check_removal(&self, song_name: &str, id: &PeerId) -> Result<(), String> {
+        match self.owners.get(song_name) {
+            Some(ownership) if !ownership.may_remove(id) => Err(format!(
+                "{} belongs to {}, who did not allow {} to remove it",
+                song_name,
+                self.display_name(&ownership.owner),
+                self.display_name(id)
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks if the copy of `song_name` with the ownership record `ownership` was removed from
+    /// the network
+    pub fn is_deleted(&self, song_name: &str, ownership: Option<&Ownership>) -> bool {
+        match self.tombstones.get(song_name) {
+            Some(tombstone) => tombstone.covers(ownership),
             None => false,
         }
     }
 
-    pub fn add_new_request(&mut self, time: &SystemTime, content: FileInstructions) {
-        self.open_request_table.insert(*time, content);
+    /// Keeps the tombstone of a removed song. A tombstone of the same removal is merged with
+    /// the known one, a newer removal replaces it.
+    pub(crate) fn record_tombstone(&mut self, tombstone: Tombstone) {
+        let changed = match self.tombstones.get_mut(&tombstone.title) {
+            Some(known) if known.deleted > tombstone.deleted => false,
+            Some(known) if known.deleted == tombstone.deleted => known.merge(&tombstone),
+            _ => {
+                self.tombstones.insert(tombstone.title.clone(), tombstone);
+                true
+            }
+        };
+        if changed {
+            self.save_tombstones();
+        }
     }
 
-    pub fn delete_handled_request(&mut self, time: &SystemTime) {
-        self.open_request_table.remove(time);
+    pub(crate) fn save_tombstones(&self) {
+        self.database.save_tombstones(&self.tombstones);
     }
 
-    pub fn delete_file_from_database(&mut self, song_name: &str) {
-        self.database.data.remove(song_name);
+    /// Drops the tombstones that all live holders acknowledged
+    pub(crate) fn collect_tombstones(&mut self) {
+        let live = self.get_all_socketaddr_from_peers();
+        let count = self.tombstones.len();
+        self.tombstones.retain(|_, t| !t.is_settled(&live));
+        if self.tombstones.len() != count {
+            self.save_tombstones();
+        }
+    }
+
+    /// The hash ring over all peers of the network table
+    pub(crate) fn ring(&self) -> HashRing {
+        HashRing::new(self.network_table.values())
+    }
+
+    /// Returns the `replication_factor` peers that are responsible for `song_name` on the hash
+    /// ring. Every peer computes the same peers from its network table.
+    pub fn responsible_peers(&self, song_name: &str) -> Vec<SocketAddr> {
+        self.ring().responsible(song_name, self.replication_factor)
+    }
+
+    /// Picks up to `count` other peers that do not hold `song_name` yet and do not leave the
+    /// network, the responsible peers first and then their successors on the hash ring
+    pub fn replication_targets(&self, song_name: &str, count: usize) -> Vec<SocketAddr> {
+        let holders = self.holders.get(song_name);
+        self.ring()
+            .responsible(song_name, self.network_table.len())
+            .into_iter()
+            .filter(|addr| *addr != self.ip_address && !self.leaving.contains(addr))
+            .filter(|addr| holders.is_none_or(|h| !h.contains(addr)))
+            .take(count)
+            .collect()
+    }
+
+    /// Returns the peers that are asked for `song_name`, its responsible peers and all known
+    /// holders. Every other peer is asked if none of them is known.
+    pub fn lookup_targets(&self, song_name: &str) -> Vec<SocketAddr> {
+        let mut targets = self.responsible_peers(song_name);
+        if let Some(holders) = self.holders.get(song_name) {
+            for holder in holders {
+                if !targets.contains(holder) {
+                    targets.push(*holder);
+                }
+            }
+        }
+        targets.retain(|addr| *addr != self.ip_address);
+        if targets.is_empty() {
+            targets = self
+                .network_table
+                .values()
+                .filter(|addr| **addr != self.ip_address)
+                .cloned()
+                .collect();
+        }
+        targets
+    }
+
+    /// Checks if the local peer and the peer at `addr` both support `capability`. Peers that did
+    /// not advertise a `Hello` are treated as peers without any capabilities.
+    pub fn supports(&self, addr: &SocketAddr, capability: Capability) -> bool {
+        match (self.hellos.get(&self.ip_address), self.hellos.get(addr)) {
+            (Some(own), Some(other)) => own.common_capabilities(other).contains(&capability),
+            _ => false,
+        }
     }
 
     pub fn drop_peer_by_ip(&mut self, addr: &SocketAddr) {
+        self.hellos.remove(addr);
+        self.leaving.remove(addr);
+        self.listings.remove(addr);
+        if let Some(table) = &mut self.routing {
+            table.remove(addr);
+        }
         let tmp = self.network_table.clone();
         let dropped = tmp.iter().filter(|&(_, &v)| v == *addr).map(|(k, _)| k);
         for k in dropped {
             self.network_table.remove_entry(k);
+            self.peer_names.remove(k);
         }
     }
 
@@ -134,6 +434,8 @@ impl Peer {
 /// # Arguments:
 ///
 /// * `own_name` - String that denotes the name of the initial Peer
+/// * `config` - the `Config` of the library, the database and the identity of the peer are
+///   loaded from its data directory unless its storage is `StorageKind::Memory`
 ///
 /// # Returns:
 /// A new `Peer` if successful, error string if failed
@@ -141,20 +443,72 @@ pub fn create_peer(
     onw_name: &str,
     port: &str,
     sender: SyncSender<Notification>,
+    config: &Config,
 ) -> Result<Peer, String> {
     let peer_socket_addr = match get_own_ip_address(port) {
         Ok(val) => val,
         Err(error_message) => return Err(error_message),
     };
-    let mut network_table = HashMap::new();
-    network_table.insert(onw_name.to_string(), peer_socket_addr);
-    let open_request_table = HashMap::new();
-    let peer = Peer::create(
+    let database = Database::open(config)?;
+    // like the songs, the identity and the network secret are only kept if the database is
+    // persisted, a peer that keeps its songs in memory gets a new identity on every start
+    let identity = match database.data_dir() {
+        Some(dir) => Identity::load_or_create(dir)?,
+        None => Identity::generate(),
+    };
+    identity.register(peer_socket_addr);
+    if config.insecure {
+        println!("Encryption is turned off, only use this for debugging");
+        allow_plaintext(peer_socket_addr);
+    }
+    let id = identity.id().clone();
+    println!("Peer ID: {}", id);
+    Ok(Peer::create(
         peer_socket_addr,
+        id,
         onw_name,
-        network_table,
-        open_request_table,
         sender,
-    );
-    Ok(peer)
+        database,
+        config,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::identity;
+    use std::sync::mpsc::sync_channel;
+
+    fn peer() -> Peer {
+        let (sender, _) = sync_channel(16);
+        let (id, addr) = identity(47502);
+        Peer::create(
+            addr,
+            id,
+            "test",
+            sender,
+            Database::new(),
+            &Config::default(),
+        )
+    }
+
+    #[test]
+    fn ownership_follows_the_copy() {
+        let mut peer = peer();
+        let (first, first_addr) = identity(47500);
+        let (second, second_addr) = identity(47501);
+        let owned_by = |peer: &Peer| peer.owners.get("song").map(|o| o.owner.clone());
+
+        let original = Ownership::create("song", "a", first.clone(), Vec::new(), &first_addr);
+        peer.record_ownership(original.unwrap(), "a");
+        let other_owner = Ownership::create("song", "a", second.clone(), Vec::new(), &second_addr);
+        peer.record_ownership(other_owner.unwrap(), "a");
+        assert_eq!(owned_by(&peer), Some(first.clone()));
+
+        let other_copy = Ownership::create("song", "b", second.clone(), Vec::new(), &second_addr);
+        peer.record_ownership(other_copy.clone().unwrap(), "a");
+        assert_eq!(owned_by(&peer), Some(first));
+        peer.record_ownership(other_copy.unwrap(), "b");
+        assert_eq!(owned_by(&peer), Some(second));
+    }
 }
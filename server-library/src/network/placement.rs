@@ -0,0 +1,121 @@
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+
+/// Number of positions every peer gets on the ring, so songs spread evenly even over few peers
+const VIRTUAL_NODES: u32 = 64;
+
+/// Consistent hash ring over the peers of the network. A song belongs to the first peers
+/// clockwise from the position of its title, so a join or leave only moves the songs next to
+/// the positions of that peer.
+pub struct HashRing {
+    nodes: BTreeMap<u64, SocketAddr>,
+    peers: usize,
+}
+
+impl HashRing {
+    /// Places every peer in `peers` on the ring
+    pub fn new<'a, I: IntoIterator<Item = &'a SocketAddr>>(peers: I) -> HashRing {
+        let mut nodes = BTreeMap::new();
+        let mut count = 0;
+        for addr in peers {
+            count += 1;
+            for node in 0..VIRTUAL_NODES {
+                nodes.insert(position(&format!("{}#{}", addr, node)), *addr);
+            }
+        }
+        HashRing {
+            nodes,
+            peers: count,
+        }
+    }
+
+    /// Returns up to `count` distinct peers responsible for `key`, in ring order
+    pub fn responsible(&self, key: &str, count: usize) -> Vec<SocketAddr> {
+        let count = count.min(self.peers);
+        let start = position(key);
+        let mut peers = Vec::new();
+        for (_, addr) in self.nodes.range(start..).chain(self.nodes.range(..start)) {
+            if peers.len() >= count {
+                break;
+            }
+            if !peers.contains(addr) {
+                peers.push(*addr);
+            }
+        }
+        peers
+    }
+}
+
+/// Position of `value` on the ring, the first eight bytes of its SHA-256 hash
+fn position(value: &str) -> u64 {
+    let hash = Sha256::digest(value.as_bytes());
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&hash[..8]);
+    u64::from_be_bytes(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peers(count: u16) -> Vec<SocketAddr> {
+        (0..count)
+            .map(|i| SocketAddr::from(([127, 0, 0, 1], 4000 + i)))
+            .collect()
+    }
+
+    #[test]
+    fn responsible_peers_are_distinct() {
+        let peers = peers(3);
+        let ring = HashRing::new(&peers);
+        let responsible = ring.responsible("song", 2);
+        assert_eq!(responsible.len(), 2);
+        assert_ne!(responsible[0], responsible[1]);
+        assert_eq!(ring.responsible("song", 10).len(), 3);
+        assert!(HashRing::new(&Vec::new()).responsible("song", 2).is_empty());
+    }
+
+    #[test]
+    fn placement_does_not_depend_on_the_order_of_the_peers() {
+        let mut peers = peers(5);
+        let ring = HashRing::new(&peers);
+        peers.reverse();
+        let reversed = HashRing::new(&peers);
+        for i in 0..100 {
+            let key = format!("song{}", i);
+            assert_eq!(ring.responsible(&key, 3), reversed.responsible(&key, 3));
+        }
+    }
+
+    #[test]
+    fn join_only_moves_songs_to_the_new_peer() {
+        let mut peers = peers(4);
+        let before = HashRing::new(&peers);
+        let new = SocketAddr::from(([127, 0, 0, 1], 5000));
+        peers.push(new);
+        let after = HashRing::new(&peers);
+        let mut moved = 0;
+        for i in 0..1000 {
+            let key = format!("song{}", i);
+            let (old, current) = (before.responsible(&key, 1), after.responsible(&key, 1));
+            if old != current {
+                assert_eq!(current, vec![new]);
+                moved += 1;
+            }
+        }
+        assert!(moved > 0 && moved < 400, "{} of 1000 songs moved", moved);
+    }
+
+    #[test]
+    fn songs_spread_over_all_peers() {
+        let peers = peers(4);
+        let ring = HashRing::new(&peers);
+        for peer in &peers {
+            let owned = (0..1000)
+                .filter(|i| ring.responsible(&format!("song{}", i), 1) == vec![*peer])
+                .count();
+            assert!(owned > 100, "{:?} owns {} of 1000 songs", peer, owned);
+        }
+    }
+}
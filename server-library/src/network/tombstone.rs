@@ -0,0 +1,312 @@
+use crate::interface::{Notification, Peer};
+use crate::network::codec::fit_in_frame;
+use crate::network::identity::{sign, verify_signed_by, PeerId};
+use crate::network::notification::{tcp_request_with_notification, Content};
+use crate::network::ownership::{unix_millis, Ownership};
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// Time a tombstone is kept for holders that were offline when all other holders acknowledged
+/// it, so they still remove their copy when they come back
+const TOMBSTONE_RETENTION: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Records that a song was removed from the network. Tombstones are passed on between the peers,
+/// so a holder that was offline during the removal deletes its copy when it comes back instead
+/// of spreading it again. The removal is signed by the peer that requested it.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Tombstone {
+    pub title: String,
+    /// Milliseconds since the epoch when the song was removed. Copies of the song with an older
+    /// ownership record are deleted.
+    pub deleted: u64,
+    pub deleted_by: PeerId,
+    signature: Vec<u8>,
+    /// The peers that held a copy when the song was removed
+    pub holders: Vec<SocketAddr>,
+    /// The peers that deleted their copy
+    pub acks: Vec<SocketAddr>,
+}
+
+impl Tombstone {
+    /// Creates the tombstone of the song `title` that `deleted_by` removed, signed with the
+    /// identity of the local peer at `addr`
+    pub fn create(
+        title: &str,
+        deleted_by: PeerId,
+        holders: Vec<SocketAddr>,
+        addr: &SocketAddr,
+    ) -> Result<Tombstone, String> {
+        let mut tombstone = Tombstone {
+            title: title.to_string(),
+            deleted: unix_millis(),
+            deleted_by,
+            signature: Vec::new(),
+            holders,
+            acks: Vec::new(),
+        };
+        let (_, signature) = sign(addr, &tombstone.signed_bytes()?)?;
+        tombstone.signature = signature;
+        Ok(tombstone)
+    }
+
+    fn signed_bytes(&self) -> Result<Vec<u8>, String> {
+        let claims = (&self.title, self.deleted, &self.deleted_by);
+        match bincode::serialize(&claims) {
+            Ok(b) => Ok(b),
+            Err(e) => Err(format!(
+                "Could not serialize the tombstone of {}: {:?}",
+                self.title, e
+            )),
+        }
+    }
+
+    /// Checks that the removal was signed by the peer that requested it
+    pub fn verify(&self) -> Result<(), String> {
+        verify_signed_by(&self.deleted_by, &self.signed_bytes()?, &self.signature)
+    }
+
+    /// Checks if the copy of the song with the ownership record `ownership` was removed. Copies
+    /// without a record were stored before owners were recorded and are removed as well.
+    pub fn covers(&self, ownership: Option<&Ownership>) -> bool {
+        ownership.is_none_or(|o| o.issued <= self.deleted)
+    }
+
+    /// Records that the peer at `addr` deleted its copy
+    /// # Returns
+    /// `true` if the peer had not acknowledged the tombstone before
+    pub fn acknowledge(&mut self, addr: SocketAddr) -> bool {
+        if self.acks.contains(&addr) {
+            return false;
+        }
+        self.acks.push(addr);
+        true
+    }
+
+    /// Takes over the holders and acknowledgements another peer knows of
+    /// # Returns
+    /// `true` if anything new was learned
+    pub fn merge(&mut self, other: &Tombstone) -> bool {
+        let mut changed = false;
+        for holder in &other.holders {
+            if !self.holders.contains(holder) {
+                self.holders.push(*holder);
+                changed = true;
+            }
+        }
+        for ack in &other.acks {
+            changed |= self.acknowledge(*ack);
+        }
+        changed
+    }
+
+    /// Checks if the tombstone can be dropped. All holders in `live` have to have acknowledged
+    /// it, holders that are offline are waited for until `TOMBSTONE_RETENTION` is over.
+    pub fn is_settled(&self, live: &[SocketAddr]) -> bool {
+        let pending: Vec<&SocketAddr> = self
+            .holders
+            .iter()
+            .filter(|h| !self.acks.contains(h))
+            .collect();
+        if pending.iter().any(|h| live.contains(h)) {
+            return false;
+        }
+        let age = unix_millis().saturating_sub(self.deleted);
+        pending.is_empty() || age > TOMBSTONE_RETENTION.as_millis() as u64
+    }
+}
+
+/// Removes the local copy of the song of `tombstone` if it was removed by a peer that may do
+/// that, and keeps the tombstone
+/// # Returns
+/// `true` if a local copy was deleted, the reason on `Err` if the removal was rejected
+pub fn apply_tombstone(mut tombstone: Tombstone, peer: &mut Peer) -> Result<bool, String> {
+    tombstone.verify()?;
+    peer.check_removal(&tombstone.title, &tombstone.deleted_by)?;
+    let title = tombstone.title.clone();
+    let mut deleted = false;
+    if tombstone.covers(peer.owners.get(&title)) {
+        if peer.does_file_exist(&title) {
+            println!("Remove file {} from database", &title);
+            peer.delete_file_from_database(&title);
+            deleted = true;
+        }
+        peer.remove_song_holders(&title);
+        peer.remove_ownership(&title);
+        tombstone.acknowledge(peer.ip_address);
+    }
+    peer.record_tombstone(tombstone);
+    Ok(deleted)
+}
+
+/// Handles the tombstones another peer passed on
+/// # Returns
+/// The songs whose local copy was deleted
+pub fn tombstones_received(tombstones: Vec<Tombstone>, peer: &mut Peer) -> Vec<String> {
+    let live = peer.get_all_socketaddr_from_peers();
+    let mut deleted = Vec::new();
+    for tombstone in tombstones {
+        // a tombstone that was dropped here already is not taken up again
+        if !peer.tombstones.contains_key(&tombstone.title) && tombstone.is_settled(&live) {
+            continue;
+        }
+        let title = tombstone.title.clone();
+        match apply_tombstone(tombstone, peer) {
+            Ok(true) => deleted.push(title),
+            Ok(false) => {}
+            Err(e) => error!("Ignored the tombstone of {}: {}", title, e),
+        }
+    }
+    deleted
+}
+
+/// Records that the peer at `sender` deleted its copy of `title`
+pub fn tombstone_ack(title: &str, deleted: u64, sender: SocketAddr, peer: &mut Peer) {
+    let acknowledged = match peer.tombstones.get_mut(title) {
+        Some(tombstone) if tombstone.deleted == deleted => tombstone.acknowledge(sender),
+        _ => false,
+    };
+    if acknowledged {
+        peer.save_tombstones();
+    }
+}
+
+/// Drops the settled tombstones and passes as many of the others as fit into a frame on to a
+/// random peer
+pub fn gossip_tombstones(peer: &mut Peer) {
+    peer.collect_tombstones();
+    if peer.tombstones.is_empty() {
+        return;
+    }
+    let own = peer.ip_address;
+    let others: Vec<SocketAddr> = peer
+        .network_table
+        .values()
+        .filter(|addr| **addr != own)
+        .cloned()
+        .collect();
+    if let Some(target) = others.choose(&mut rand::thread_rng()) {
+        let mut tombstones: Vec<Tombstone> = peer.tombstones.values().cloned().collect();
+        tombstones.shuffle(&mut rand::thread_rng());
+        send_tombstones(*target, own, fit_in_frame(tombstones));
+    }
+}
+
+/// Checks if a copy of `title` with the ownership record `ownership` that the peer at `sender`
+/// wants to store on the local peer was removed from the network. The sender is told about the
+/// removal, so it deletes its copy as well.
+pub fn reject_deleted_copy(
+    title: &str,
+    ownership: Option<&Ownership>,
+    sender: SocketAddr,
+    peer: &Peer,
+) -> bool {
+    let tombstone = match peer.tombstones.get(title) {
+        Some(t) if t.covers(ownership) => t.clone(),
+        _ => return false,
+    };
+    info!(
+        "Refused a copy of the removed song {} from {:?}",
+        title, sender
+    );
+    send_tombstones(sender, peer.ip_address, vec![tombstone]);
+    true
+}
+
+fn send_tombstones(target: SocketAddr, from: SocketAddr, tombstones: Vec<Tombstone>) {
+    let not = Notification {
+        content: Content::Tombstones { tombstones },
+        from,
+        signer: None,
+    };
+    tcp_request_with_notification(target, not);
+}
+
+/// Tells the peer that removed `title` that the local peer deleted its copy
+pub fn send_tombstone_ack(target: SocketAddr, from: SocketAddr, title: &str, deleted: u64) {
+    let not = Notification {
+        content: Content::TombstoneAck {
+            title: title.to_string(),
+            deleted,
+        },
+        from,
+        signer: None,
+    };
+    tcp_request_with_notification(target, not);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::identity;
+
+    fn holder(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn tombstone(port: u16, holders: Vec<SocketAddr>) -> Tombstone {
+        let (id, addr) = identity(port);
+        Tombstone::create("song", id, holders, &addr).unwrap()
+    }
+
+    #[test]
+    fn removal_is_signed_by_its_peer() {
+        let tombstone = tombstone(47201, Vec::new());
+        assert!(tombstone.verify().is_ok());
+        let mut other_song = tombstone.clone();
+        other_song.title = "other".to_string();
+        assert!(other_song.verify().is_err());
+        let mut other_peer = tombstone;
+        other_peer.deleted_by = identity(47202).0;
+        assert!(other_peer.verify().is_err());
+    }
+
+    #[test]
+    fn settled_once_every_live_holder_acknowledged() {
+        let (a, b) = (holder(1), holder(2));
+        let mut tombstone = tombstone(47203, vec![a, b]);
+        assert!(!tombstone.is_settled(&[a, b]));
+        assert!(tombstone.acknowledge(a));
+        assert!(!tombstone.acknowledge(a));
+        assert!(!tombstone.is_settled(&[a, b]));
+        assert!(tombstone.acknowledge(b));
+        assert!(tombstone.is_settled(&[a, b]));
+    }
+
+    #[test]
+    fn offline_holders_are_waited_for_until_the_retention_is_over() {
+        let (a, b) = (holder(1), holder(2));
+        let mut tombstone = tombstone(47204, vec![a, b]);
+        tombstone.acknowledge(a);
+        assert!(!tombstone.is_settled(&[a]));
+        tombstone.deleted = unix_millis() - TOMBSTONE_RETENTION.as_millis() as u64 - 1;
+        assert!(tombstone.is_settled(&[a]));
+        assert!(!tombstone.is_settled(&[a, b]));
+    }
+
+    #[test]
+    fn merge_learns_holders_and_acks() {
+        let (a, b, c) = (holder(1), holder(2), holder(3));
+        let mut known = tombstone(47205, vec![a, b]);
+        let mut other = known.clone();
+        other.holders.push(c);
+        other.acknowledge(b);
+        assert!(known.merge(&other));
+        assert!(!known.merge(&other));
+        assert_eq!(known.holders, vec![a, b, c]);
+        assert_eq!(known.acks, vec![b]);
+    }
+
+    #[test]
+    fn newer_upload_is_not_covered() {
+        let tombstone = tombstone(47206, Vec::new());
+        assert!(tombstone.covers(None));
+        let (owner, addr) = identity(47207);
+        let mut ownership = Ownership::create("song", "hash", owner, Vec::new(), &addr).unwrap();
+        ownership.issued = tombstone.deleted;
+        assert!(tombstone.covers(Some(&ownership)));
+        ownership.issued = tombstone.deleted + 1;
+        assert!(!tombstone.covers(Some(&ownership)));
+    }
+}
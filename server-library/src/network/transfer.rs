@@ -0,0 +1,579 @@
+use crate::database::{hash_file, Database};
+use crate::interface::{Notification, Peer};
+use crate::network::notification::{tcp_request_with_notification, Content};
+use crate::network::secure::Session;
+use crate::utils::FileInstructions;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Size of the chunks a file is split into for a transfer
+pub const CHUNK_SIZE: u64 = 256 * 1024;
+/// Number of chunks a receiver requests at the same time
+const WINDOW: usize = 4;
+/// Time after which a requested chunk that did not arrive is requested again
+const CHUNK_TIMEOUT: Duration = Duration::from_secs(10);
+/// Number of timeouts in a row after which a transfer is given up
+const MAX_RETRIES: u32 = 10;
+
+/// Describes a file that is offered for a transfer. Every chunk is verified against its hash.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub struct Manifest {
+    pub size: u64,
+    /// Content hash of the whole file, checked once all chunks arrived
+    pub hash: String,
+    pub chunk_hashes: Vec<String>,
+}
+
+/// Receives the chunks of a streamed transfer in order instead of a file, e.g. the buffer of the
+/// player. Chunks are only requested while the target has room for them.
+pub trait StreamTarget: Send + Sync {
+    /// Number of bytes the target can take
+    fn free_space(&self) -> u64;
+    /// Appends the next chunk
+    fn push(&self, data: &[u8]);
+    /// Called after the last chunk was pushed
+    fn finish(&self);
+    /// `true` if the target is not interested in more data, the transfer is given up then
+    fn is_closed(&self) -> bool;
+}
+
+/// The reason a file is transferred
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub enum TransferKind {
+    /// Answer to a `GetFile` request
+    Response(FileInstructions),
+    /// Redundant copy of a song of the peer at `from`
+    Redundant { from: String },
+}
+
+/// State of a file the local peer receives. The state is written next to the partial file, so
+/// an interrupted transfer continues with the missing chunks after a restart. Streamed transfers
+/// write to their `StreamTarget` instead and are not persisted.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct IncomingTransfer {
+    pub key: String,
+    pub kind: TransferKind,
+    pub source: SocketAddr,
+    pub manifest: Manifest,
+    received: Vec<bool>,
+    #[serde(skip)]
+    requested: HashMap<u64, Instant>,
+    #[serde(skip)]
+    retries: u32,
+    #[serde(skip)]
+    stream: Option<Arc<dyn StreamTarget>>,
+    /// Chunks of a stream that arrived before their predecessors
+    #[serde(skip)]
+    pending: BTreeMap<u64, Vec<u8>>,
+    /// The connection all chunk requests are sent over
+    #[serde(skip)]
+    session: Session,
+}
+
+impl IncomingTransfer {
+    fn is_complete(&self) -> bool {
+        self.received.iter().all(|r| *r)
+    }
+}
+
+/// Returns the hex encoded SHA-256 hash of `data`
+pub fn chunk_hash(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+fn chunk_count(size: u64) -> u64 {
+    size.div_ceil(CHUNK_SIZE)
+}
+
+/// Checks that the chunk hashes of `manifest` cover exactly its size and that the size is at
+/// most `limit`, so a peer can not make the receiver allocate more than it will ever get.
+fn check_manifest(manifest: &Manifest, limit: u64) -> Result<(), String> {
+    if manifest.size > limit {
+        return Err(format!(
+            "the song has {} bytes, at most {} are accepted",
+            manifest.size, limit
+        ));
+    }
+    if chunk_count(manifest.size) != manifest.chunk_hashes.len() as u64 {
+        return Err(format!(
+            "{} chunk hashes do not match a size of {} bytes",
+            manifest.chunk_hashes.len(),
+            manifest.size
+        ));
+    }
+    Ok(())
+}
+
+/// Keys can contain characters that are not allowed in file names, so the files of a transfer
+/// are named after the hash of the key.
+fn part_path(dir: &Path, key: &str) -> PathBuf {
+    dir.join(format!("{}.part", chunk_hash(key.as_bytes())))
+}
+
+fn state_path(dir: &Path, key: &str) -> PathBuf {
+    dir.join(format!("{}.json", chunk_hash(key.as_bytes())))
+}
+
+/// Creates the `Manifest` of the song `key`. The song is read chunk by chunk, so it never has
+/// to be in memory as a whole.
+pub fn create_manifest(database: &Database, key: &str) -> Option<Manifest> {
+    let size = database.size(key)?;
+    let hash = database.hash_of(key)?;
+    let mut chunk_hashes = Vec::new();
+    for index in 0..chunk_count(size) {
+        let chunk = database.get_range(key, index * CHUNK_SIZE, CHUNK_SIZE)?;
+        chunk_hashes.push(chunk_hash(&chunk));
+    }
+    Some(Manifest {
+        size,
+        hash,
+        chunk_hashes,
+    })
+}
+
+/// Offers the song `key` to the peer at `target`, which then requests the chunks it needs.
+/// # Parameters:
+/// - `target` - the peer that should receive the song
+/// - `peer` - the local `Peer`
+/// - `key` - the name of the song
+/// - `kind` - the reason for the transfer
+pub fn offer_file(target: SocketAddr, peer: &Peer, key: &str, kind: TransferKind) {
+    let database = peer.database.clone();
+    let from = peer.ip_address;
+    let ownership = peer.owners.get(key).cloned();
+    let key = key.to_string();
+    if let Err(e) = thread::Builder::new()
+        .name("offer_file_thread".to_string())
+        .spawn(move || {
+            let manifest = match create_manifest(&database, &key) {
+                Some(m) => m,
+                None => {
+                    error!("Could not read {} to offer it to {:?}", key, target);
+                    return;
+                }
+            };
+            let not = Notification {
+                content: Content::FileOffer {
+                    key,
+                    kind,
+                    manifest,
+                    ownership,
+                },
+                from,
+                signer: None,
+            };
+            tcp_request_with_notification(target, not);
+        })
+    {
+        error!("Could not spawn offer_file_thread: {:?}", e);
+    }
+}
+
+/// Handles a `FileOffer`. A transfer of the same file that was interrupted is resumed, otherwise
+/// a new transfer is started. Offers whose manifest does not match its size or exceeds the
+/// configured limit are rejected.
+/// # Parameters:
+/// - `stream` - `Some` if the chunks should be streamed to a `StreamTarget` instead of a file
+/// # Returns
+/// The finished transfer if there was nothing left to request, see `finish_if_complete`
+pub fn file_offer(
+    key: String,
+    kind: TransferKind,
+    manifest: Manifest,
+    stream: Option<Arc<dyn StreamTarget>>,
+    sender: SocketAddr,
+    peer: &mut Peer,
+) -> Option<(IncomingTransfer, Option<PathBuf>)> {
+    if let Err(e) = check_manifest(&manifest, peer.max_song_size) {
+        error!("Rejected the offer of {} from {:?}: {}", key, sender, e);
+        return None;
+    }
+    let dir = peer.database.transfer_dir().to_path_buf();
+    let own = peer.ip_address;
+    let resumable = match peer.transfers.get(&key) {
+        Some(t) => t.manifest == manifest && t.stream.is_none() && stream.is_none(),
+        None => false,
+    };
+    if resumable {
+        info!("Resuming the transfer of {}", key);
+    } else {
+        if let Some(old) = peer.transfers.get(&key).and_then(|t| t.stream.clone()) {
+            old.finish();
+        }
+        if stream.is_none() {
+            if let Err(e) = create_part_file(&dir, &key, manifest.size) {
+                error!("Could not start the transfer of {}: {}", key, e);
+                return None;
+            }
+        }
+        let chunks = manifest.chunk_hashes.len();
+        peer.transfers.insert(
+            key.clone(),
+            IncomingTransfer {
+                key: key.clone(),
+                kind: kind.clone(),
+                source: sender,
+                manifest,
+                received: vec![false; chunks],
+                requested: HashMap::new(),
+                retries: 0,
+                stream,
+                pending: BTreeMap::new(),
+                session: Session::default(),
+            },
+        );
+    }
+    if let Some(transfer) = peer.transfers.get_mut(&key) {
+        transfer.kind = kind;
+        transfer.source = sender;
+        transfer.requested.clear();
+        transfer.retries = 0;
+        if transfer.stream.is_none() {
+            save_transfer(&dir, transfer);
+        }
+        request_chunks(transfer, own);
+    }
+    finish_if_complete(peer, &key)
+}
+
+fn create_part_file(dir: &Path, key: &str, size: u64) -> Result<(), String> {
+    if let Err(e) = fs::create_dir_all(dir) {
+        return Err(format!("Could not create {:?}: {:?}", dir, e));
+    }
+    let file = match OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(part_path(dir, key))
+    {
+        Ok(f) => f,
+        Err(e) => return Err(format!("Could not create the partial file: {:?}", e)),
+    };
+    match file.set_len(size) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Could not allocate the partial file: {:?}", e)),
+    }
+}
+
+/// Requests missing chunks from the source of `transfer` until `WINDOW` chunks are in flight.
+/// Streams only get as many chunks as their target has room for.
+fn request_chunks(transfer: &mut IncomingTransfer, own: SocketAddr) {
+    let limit = match &transfer.stream {
+        Some(stream) => ((stream.free_space() / CHUNK_SIZE) as usize).min(WINDOW),
+        None => WINDOW,
+    };
+    let missing: Vec<u64> = (0..transfer.received.len() as u64)
+        .filter(|i| {
+            !transfer.received[*i as usize]
+                && !transfer.requested.contains_key(i)
+                && !transfer.pending.contains_key(i)
+        })
+        .collect();
+    for index in missing {
+        if transfer.requested.len() + transfer.pending.len() >= limit {
+            break;
+        }
+        transfer.requested.insert(index, Instant::now());
+        let not = Notification {
+            content: Content::GetChunk {
+                key: transfer.key.clone(),
+                index,
+            },
+            from: own,
+            signer: None,
+        };
+        if let Err(e) = transfer.session.send(transfer.source, &not) {
+            eprintln!("{}", e);
+        }
+    }
+}
+
+/// Sends chunk `index` of the song `key` to the peer at `sender`. All chunks of the song go to
+/// `sender` over the same connection.
+pub fn get_chunk(key: String, index: u64, sender: SocketAddr, peer: &mut Peer) {
+    let database = peer.database.clone();
+    let from = peer.ip_address;
+    let session = peer
+        .chunk_sessions
+        .entry((sender, key.clone()))
+        .or_default()
+        .clone();
+    if let Err(e) = thread::Builder::new()
+        .name("send_chunk_thread".to_string())
+        .spawn(move || {
+            let data = match database.get_range(&key, index * CHUNK_SIZE, CHUNK_SIZE) {
+                Some(d) => d,
+                None => {
+                    error!(
+                        "Chunk {} of {} requested by {:?} is not available",
+                        index, key, sender
+                    );
+                    return;
+                }
+            };
+            let not = Notification {
+                content: Content::Chunk { key, index, data },
+                from,
+                signer: None,
+            };
+            if let Err(e) = session.send(sender, &not) {
+                eprintln!("{}", e);
+            }
+        })
+    {
+        error!("Could not spawn send_chunk_thread: {:?}", e);
+    }
+}
+
+/// Handles a received chunk. The chunk is checked against the hash in the `Manifest` and written
+/// to the partial file or the stream, afterwards the next missing chunk is requested.
+/// # Returns
+/// The finished transfer once all chunks arrived, see `finish_if_complete`
+pub fn chunk_received(
+    key: String,
+    index: u64,
+    data: Vec<u8>,
+    sender: SocketAddr,
+    peer: &mut Peer,
+) -> Option<(IncomingTransfer, Option<PathBuf>)> {
+    let dir = peer.database.transfer_dir().to_path_buf();
+    let own = peer.ip_address;
+    let transfer = match peer.transfers.get_mut(&key) {
+        Some(t) => t,
+        None => {
+            info!("Received chunk {} of {} without a transfer", index, key);
+            return None;
+        }
+    };
+    transfer.requested.remove(&index);
+    let valid = match transfer.manifest.chunk_hashes.get(index as usize) {
+        Some(hash) => *hash == chunk_hash(&data),
+        None => false,
+    };
+    if !valid {
+        error!("Chunk {} of {} from {:?} is corrupt", index, key, sender);
+        request_chunks(transfer, own);
+        return None;
+    }
+    transfer.retries = 0;
+    if transfer.stream.is_some() {
+        transfer.pending.insert(index, data);
+        push_pending_chunks(transfer);
+    } else {
+        if let Err(e) = write_chunk(&part_path(&dir, &key), index, &data) {
+            error!("{}", e);
+            request_chunks(transfer, own);
+            return None;
+        }
+        transfer.received[index as usize] = true;
+        save_transfer(&dir, transfer);
+    }
+    request_chunks(transfer, own);
+    finish_if_complete(peer, &key)
+}
+
+/// Pushes the chunks that arrived in order to the stream of `transfer`
+fn push_pending_chunks(transfer: &mut IncomingTransfer) {
+    let stream = match &transfer.stream {
+        Some(s) => s.clone(),
+        None => return,
+    };
+    let mut next = transfer.received.iter().take_while(|r| **r).count() as u64;
+    while let Some(data) = transfer.pending.remove(&next) {
+        stream.push(&data);
+        transfer.received[next as usize] = true;
+        next += 1;
+    }
+}
+
+fn write_chunk(path: &Path, index: u64, data: &[u8]) -> Result<(), String> {
+    let mut file = match OpenOptions::new().write(true).open(path) {
+        Ok(f) => f,
+        Err(e) => return Err(format!("Could not open {:?}: {:?}", path, e)),
+    };
+    if let Err(e) = file.seek(SeekFrom::Start(index * CHUNK_SIZE)) {
+        return Err(format!("Could not seek in {:?}: {:?}", path, e));
+    }
+    match file.write_all(data) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!(
+            "Could not write chunk {} to {:?}: {:?}",
+            index, path, e
+        )),
+    }
+}
+
+/// Removes the transfer of `key` if all of its chunks arrived
+/// # Returns
+/// The finished transfer and the path of the received file, which is `None` for a stream. A
+/// file that does not match its content hash is deleted and not returned.
+fn finish_if_complete(peer: &mut Peer, key: &str) -> Option<(IncomingTransfer, Option<PathBuf>)> {
+    let complete = match peer.transfers.get(key) {
+        Some(t) => t.is_complete(),
+        None => false,
+    };
+    if !complete {
+        return None;
+    }
+    let transfer = peer.transfers.remove(key)?;
+    if let Some(stream) = &transfer.stream {
+        stream.finish();
+        return Some((transfer, None));
+    }
+    let dir = peer.database.transfer_dir();
+    if let Err(e) = fs::remove_file(state_path(dir, key)) {
+        error!("Could not delete the transfer state of {}: {:?}", key, e);
+    }
+    let path = part_path(dir, key);
+    match hash_file(&path) {
+        Ok(hash) if hash == transfer.manifest.hash => Some((transfer, Some(path))),
+        Ok(hash) => {
+            error!(
+                "{} does not match its content hash, expected {} but got {}",
+                key, transfer.manifest.hash, hash
+            );
+            let _ = fs::remove_file(&path);
+            None
+        }
+        Err(e) => {
+            error!("{}", e);
+            None
+        }
+    }
+}
+
+/// Requests chunks again that did not arrive in time and requests more chunks for streams that
+/// made room. Transfers without any progress after `MAX_RETRIES` attempts are given up. The
+/// connections of songs that are no longer sent are closed.
+pub fn retry_stalled_transfers(peer: &mut Peer) {
+    peer.chunk_sessions.retain(|_, session| !session.is_idle());
+    let own = peer.ip_address;
+    let mut aborted = Vec::new();
+    for (key, transfer) in peer.transfers.iter_mut() {
+        if transfer.stream.as_ref().is_some_and(|s| s.is_closed()) {
+            aborted.push(key.clone());
+            continue;
+        }
+        let stalled: Vec<u64> = transfer
+            .requested
+            .iter()
+            .filter(|(_, requested)| requested.elapsed() > CHUNK_TIMEOUT)
+            .map(|(index, _)| *index)
+            .collect();
+        if !stalled.is_empty() {
+            transfer.retries += 1;
+            for index in stalled {
+                transfer.requested.remove(&index);
+            }
+        }
+        if transfer.retries > MAX_RETRIES {
+            aborted.push(key.clone());
+            continue;
+        }
+        request_chunks(transfer, own);
+    }
+    for key in aborted {
+        error!("Giving up the transfer of {}", key);
+        abort_transfer(peer, &key);
+    }
+}
+
+/// Removes the transfer of `key` and deletes its files
+pub fn abort_transfer(peer: &mut Peer, key: &str) {
+    if let Some(stream) = peer.transfers.remove(key).and_then(|t| t.stream) {
+        stream.finish();
+    }
+    let dir = peer.database.transfer_dir();
+    let _ = fs::remove_file(part_path(dir, key));
+    let _ = fs::remove_file(state_path(dir, key));
+}
+
+/// Saves the state of all unfinished transfers, so they are continued on the next start. Streams
+/// are not persisted and end.
+pub fn save_transfers(peer: &Peer) {
+    let dir = peer.database.transfer_dir();
+    for transfer in peer.transfers.values() {
+        match &transfer.stream {
+            Some(stream) => stream.finish(),
+            None => save_transfer(dir, transfer),
+        }
+    }
+}
+
+fn save_transfer(dir: &Path, transfer: &IncomingTransfer) {
+    match serde_json::to_string(transfer) {
+        Ok(json) => {
+            if let Err(e) = fs::write(state_path(dir, &transfer.key), json) {
+                error!(
+                    "Could not save the transfer state of {}: {:?}",
+                    transfer.key, e
+                );
+            }
+        }
+        Err(e) => error!("Could not serialize the transfer state: {:?}", e),
+    }
+}
+
+/// Loads the unfinished transfers from `dir`. They are continued on the next maintenance run.
+pub fn load_transfers(dir: &Path) -> HashMap<String, IncomingTransfer> {
+    let mut transfers = HashMap::new();
+    let entries = match fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(_e) => return transfers,
+    };
+    for entry in entries.filter_map(|e| e.ok()) {
+        let path = entry.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("json") {
+            continue;
+        }
+        let transfer: IncomingTransfer = match fs::read_to_string(&path)
+            .ok()
+            .and_then(|c| serde_json::from_str(&c).ok())
+        {
+            Some(t) => t,
+            None => {
+                error!("Could not load the transfer state {:?}", path);
+                continue;
+            }
+        };
+        info!("Found unfinished transfer of {}", transfer.key);
+        transfers.insert(transfer.key.clone(), transfer);
+    }
+    transfers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest(size: u64, chunks: usize) -> Manifest {
+        Manifest {
+            size,
+            hash: String::new(),
+            chunk_hashes: vec![String::new(); chunks],
+        }
+    }
+
+    #[test]
+    fn manifest_must_cover_its_size() {
+        assert!(check_manifest(&manifest(0, 0), CHUNK_SIZE).is_ok());
+        assert!(check_manifest(&manifest(CHUNK_SIZE, 1), CHUNK_SIZE).is_ok());
+        assert!(check_manifest(&manifest(CHUNK_SIZE + 1, 2), 2 * CHUNK_SIZE).is_ok());
+        assert!(check_manifest(&manifest(CHUNK_SIZE + 1, 1), 2 * CHUNK_SIZE).is_err());
+        assert!(check_manifest(&manifest(1, 2), CHUNK_SIZE).is_err());
+    }
+
+    #[test]
+    fn manifest_above_the_limit_is_rejected() {
+        assert!(check_manifest(&manifest(CHUNK_SIZE, 1), CHUNK_SIZE - 1).is_err());
+        assert!(check_manifest(&manifest(u64::MAX, 0), CHUNK_SIZE).is_err());
+    }
+}
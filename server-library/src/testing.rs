@@ -0,0 +1,49 @@
+use crate::network::identity::{Identity, PeerId};
+use std::env;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Number of temporary directories created by this test run, makes their names unique
+static DIRS: AtomicUsize = AtomicUsize::new(0);
+
+/// A new empty directory below the temporary directory of the system. It is removed with
+/// everything inside when it is dropped.
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new(name: &str) -> TempDir {
+        let path = env::temp_dir().join(format!(
+            "meff-{}-test-{}-{}",
+            name,
+            process::id(),
+            DIRS.fetch_add(1, Ordering::SeqCst)
+        ));
+        fs::create_dir_all(&path).unwrap();
+        TempDir { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Registers a new identity for the local address with `port`. Every test uses its own ports,
+/// the identities of all tests share one registry.
+pub fn identity(port: u16) -> (PeerId, SocketAddr) {
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    let dir = TempDir::new("identity");
+    let identity = Identity::load_or_create(dir.path()).unwrap();
+    identity.register(addr);
+    (identity.id().clone(), addr)
+}
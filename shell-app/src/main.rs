@@ -2,8 +2,11 @@ extern crate meff;
 use crate::shell::spawn_shell;
 use crate::util::Application;
 use clap::{App, Arg};
-use meff::interface::{Peer, start};
+use meff::interface::{start, PeerHandle};
+use meff::utils::{Config, RoutingMode, DEFAULT_DATA_DIR, DEFAULT_REPLICATION_FACTOR};
 use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::mpsc::{sync_channel, Receiver};
 use std::sync::{Arc, Mutex};
 
 #[macro_use]
@@ -39,9 +42,70 @@ fn main() {
                 .required(false)
                 .index(3),
         )
+        .arg(
+            Arg::with_name("data-dir")
+                .short("d")
+                .long("data-dir")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("replication")
+                .short("r")
+                .long("replication")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("dht")
+                .long("dht")
+                .takes_value(false)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("invite")
+                .long("invite")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("insecure")
+                .long("insecure")
+                .takes_value(false)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("capacity")
+                .long("capacity")
+                .takes_value(true)
+                .required(false),
+        )
         .get_matches();
     let name = matches.value_of("own-name").unwrap_or("Fridolin");
     let port = matches.value_of("port").unwrap_or("34521");
+    let config = Config {
+        data_dir: match matches.value_of("data-dir") {
+            Some(dir) => PathBuf::from(dir),
+            None => PathBuf::from(DEFAULT_DATA_DIR).join(port),
+        },
+        replication_factor: match matches.value_of("replication") {
+            Some(factor) => factor.parse().unwrap_or(DEFAULT_REPLICATION_FACTOR),
+            None => DEFAULT_REPLICATION_FACTOR,
+        },
+        routing: if matches.is_present("dht") {
+            RoutingMode::Dht
+        } else {
+            RoutingMode::FullMesh
+        },
+        insecure: matches.is_present("insecure"),
+        invite: matches.value_of("invite").map(|token| token.to_string()),
+        // given in megabytes
+        capacity: matches
+            .value_of("capacity")
+            .and_then(|mb| mb.parse::<u64>().ok())
+            .map(|mb| mb * 1024 * 1024),
+        ..Config::default()
+    };
     if matches.is_present("ip-address") {
         // TODO: Join existing p2p network on given ip address
         let addr;
@@ -60,35 +124,62 @@ fn main() {
                 return;
             }
         }
-        let appl = Application { is_playing: Arc::new(Mutex::new(false)) };
+        let (left, left_receiver) = sync_channel(1);
+        let appl = Application {
+            is_playing: Arc::new(Mutex::new(false)),
+            left,
+        };
         let appl_rc = Arc::new(Mutex::new(appl.clone()));
-        let peer = match start(Box::new(appl), name.to_string(), port.to_string(), Some(addr)) {
+        let handle = match start(
+            Box::new(appl),
+            name.to_string(),
+            port.to_string(),
+            Some(addr),
+            config,
+        ) {
             Ok(p) => p,
             Err(e) => {
                 println!("{}", e);
                 return;
             } // error!("Could not join network {:?}", e);
         };
-        startup(peer, appl_rc);
+        startup(handle, appl_rc, left_receiver);
     } else {
-        let appl = Application { is_playing: Arc::new(Mutex::new(false)) };
+        let (left, left_receiver) = sync_channel(1);
+        let appl = Application {
+            is_playing: Arc::new(Mutex::new(false)),
+            left,
+        };
         let appl_rc = Arc::new(Mutex::new(appl.clone()));
-        let peer = match start(Box::new(appl), name.to_string(), port.to_string(), None ) {
+        let handle = match start(
+            Box::new(appl),
+            name.to_string(),
+            port.to_string(),
+            None,
+            config,
+        ) {
             Ok(p) => p,
             Err(e) => {
                 println!("{}", e);
                 return;
             } // error!("Could not join network {:?}", e);
         };
-        startup(peer, appl_rc);
+        startup(handle, appl_rc, left_receiver);
     }
 }
 
-fn startup(peer: Arc<Mutex<Peer>>, model: Arc<Mutex<Application>>) {
-    match spawn_shell(peer, model) {
+/// Runs the shell until the peer left the network and shuts the peer down afterwards
+fn startup(handle: PeerHandle, model: Arc<Mutex<Application>>, left: Receiver<()>) {
+    match spawn_shell(handle.peer(), model) {
         Ok(_) => {}
         Err(_) => {
             eprintln!("Failed to spawn shell");
+            return;
         }
     };
+    if left.recv().is_ok() {
+        if let Err(e) = handle.shutdown() {
+            eprintln!("Failed to shut the peer down: {}", e);
+        }
+    }
 }
@@ -1,7 +1,12 @@
 use prettytable::format;
 extern crate colored;
+use crate::util::Application;
 use colored::*;
-use meff::interface::{Peer, upload_music, music_request, delete_peer, music_control};
+use meff::interface::MusicState::{CONTINUE, PAUSE, PLAY, STOP};
+use meff::interface::{
+    create_invite, delegate_removal, delete_peer, music_control, music_request, search,
+    upload_music, Peer, SongMetadata,
+};
 use meff::utils::FileInstructions::{GET, REMOVE};
 use std::borrow::BorrowMut;
 use std::convert::TryFrom;
@@ -9,19 +14,24 @@ use std::error::Error;
 use std::io::stdin;
 use std::sync::{Arc, Mutex};
 use std::thread;
-use crate::util::Application;
-use meff::interface::MusicState::{PAUSE, STOP, CONTINUE, PLAY};
+use std::time::Duration;
+
+/// Validity of an invite token if no other is given
+const DEFAULT_INVITE_MINUTES: u64 = 60;
 
-pub fn spawn_shell(arc: Arc<Mutex<Peer>>, model: Arc<Mutex<Application>>) -> Result<(), Box<dyn Error>> {
+pub fn spawn_shell(
+    arc: Arc<Mutex<Peer>>,
+    model: Arc<Mutex<Application>>,
+) -> Result<(), Box<dyn Error>> {
     let arc_clone = arc.clone();
     let arc_clone2 = arc.clone();
     let peer = match arc.lock() {
         Ok(p) => p,
-        Err(e) => e.into_inner()
+        Err(e) => e.into_inner(),
     };
 
     drop(peer);
-    let handle = match thread::Builder::new()
+    match thread::Builder::new()
         .name("Interaction".to_string())
         .spawn(move || loop {
             let peer = match arc_clone.lock() {
@@ -31,14 +41,12 @@ pub fn spawn_shell(arc: Arc<Mutex<Peer>>, model: Arc<Mutex<Application>>) -> Res
             drop(peer);
             handle_user_input(&arc_clone2, &model);
         }) {
-        Ok(h) => h,
+        Ok(_) => Ok(()),
         Err(_) => {
             error!("Failed to spawn thread");
-            return Err(Box::try_from("Failed to spwan thread".to_string()).unwrap());
+            Err(Box::try_from("Failed to spwan thread".to_string()).unwrap())
         }
-    };
-    handle.join().unwrap();
-    Ok(())
+    }
 }
 
 pub fn handle_user_input(arc: &Arc<Mutex<Peer>>, model: &Arc<Mutex<Application>>) {
@@ -113,17 +121,26 @@ pub fn handle_user_input(arc: &Arc<Mutex<Peer>>, model: &Arc<Mutex<Application>>
                 if instructions.len() == 2 {
                     music_control(Some(instructions[1].to_string()), &mut peer_clone, PLAY);
                 } else if *model_clone.is_playing.lock().unwrap() {
-                        music_control(None, &mut peer_clone, CONTINUE);
-                    } else {
-                        println!("File name is missing. For more information type help.\n");
-                    }
+                    music_control(None, &mut peer_clone, CONTINUE);
+                } else {
+                    println!("File name is missing. For more information type help.\n");
                 }
+            }
             Some(&"remove") => {
                 if instructions.len() == 2 {
                     music_request(&mut peer_clone, instructions[1], REMOVE);
                 } else {
                     println!(
-                        "You need to specify name of mp3 file. For more information type help.\n"
+                        "You need to specify name of song file. For more information type help.\n"
+                    );
+                }
+            }
+            Some(&"delegate") => {
+                if instructions.len() == 3 {
+                    delegate_removal(&mut peer_clone, instructions[1], instructions[2]);
+                } else {
+                    println!(
+                        "You need to specify name of song file and ID of peer. For more information type help.\n"
                     );
                 }
             }
@@ -133,20 +150,38 @@ pub fn handle_user_input(arc: &Arc<Mutex<Peer>>, model: &Arc<Mutex<Application>>
             Some(&"stop") => {
                 music_control(None, &mut peer_clone, STOP);
             }
+            Some(&"search") => {
+                let query = instructions[1..].join(" ");
+                print_search_results(&arc, &query);
+            }
+            Some(&"invite") => {
+                let minutes = match instructions.get(1) {
+                    Some(m) => m.parse::<u64>().unwrap_or(DEFAULT_INVITE_MINUTES),
+                    None => DEFAULT_INVITE_MINUTES,
+                };
+                match create_invite(&peer_clone, Duration::from_secs(minutes * 60)) {
+                    Ok(token) => {
+                        println!("Invite token, valid for {} minutes:\n{}\n", minutes, token)
+                    }
+                    Err(e) => println!("Could not create an invite: {}\n", e),
+                }
+            }
             _ => println!("No valid instructions. Try help!\n"),
         }
     }
 }
 
-
 pub fn show_help_instructions() {
     let info = "\nHelp Menu:\n\n\
                 Use following instructions: \n\n\
                 status - show current state of peer\n\
-                push [mp3 name] [direction to mp3] - add mp3 to database\n\
-                get [mp3 name] - get mp3 file from database\n\
-                remove [mp3 name] - deletes mp3 file from database\n\
-                play [mp3 name] - plays the audio of mp3 file\n\
+                push [song name] [path to song] - add an MP3, FLAC, Ogg Vorbis, WAV or AAC song to database\n\
+                get [song name] - get song file from database\n\
+                search [query] - search the songs of all peers by title, artist or album\n\
+                remove [song name] - deletes song file from database\n\
+                delegate [song name] [peer id] - allow another peer to remove your song file\n\
+                play [song name] - plays the audio of song file\n\
+                invite [minutes] - create an invite token for a friend\n\
                 exit - exit network and leave program\n\n
                 ";
     print!("{}", info);
@@ -159,11 +194,18 @@ fn print_peer_status(arc: &Arc<Mutex<Peer>>) {
     };
     let peer_clone = peer.clone();
     drop(peer);
-    let nwt = peer_clone.network_table;
-    let mut other_peers = table!(["Name".italic().yellow(), "SocketAddr".italic().yellow()]);
+    let mut other_peers = table!([
+        "Name".italic().yellow(),
+        "ID".italic().yellow(),
+        "SocketAddr".italic().yellow()
+    ]);
 
-    for (name, addr) in nwt {
-        other_peers.add_row(row![name, addr.to_string()]);
+    for (id, addr) in peer_clone.get_network() {
+        other_peers.add_row(row![
+            peer_clone.display_name(id),
+            id.short(),
+            addr.to_string()
+        ]);
     }
     other_peers.set_format(*format::consts::FORMAT_BORDERS_ONLY);
     println!(
@@ -186,10 +228,25 @@ fn print_local_db_status(arc: &Arc<Mutex<Peer>>) {
     };
     let peer_clone = peer.clone();
     drop(peer);
-    let db = peer_clone.get_db().get_data();
-    let mut local_data = table!(["Key".italic().green(), "File Info".italic().green()]);
-    for (k, v) in db {
-        local_data.add_row(row![k, v.len()]);
+    let db = peer_clone.get_db();
+    let mut local_data = table!([
+        "Key".italic().green(),
+        "Artist".italic().green(),
+        "Duration".italic().green(),
+        "Format".italic().green(),
+        "File Info".italic().green()
+    ]);
+    for k in db.keys() {
+        let size = db.size(&k).unwrap_or(0);
+        let tags = db.metadata(&k).unwrap_or_default();
+        let format = tags.format.map(|f| f.extension().to_uppercase());
+        local_data.add_row(row![
+            k,
+            or_dash(tags.artist),
+            format_duration(tags.duration),
+            or_dash(format),
+            size
+        ]);
     }
     local_data.set_format(*format::consts::FORMAT_BORDERS_ONLY);
     print!(
@@ -201,12 +258,22 @@ fn print_local_db_status(arc: &Arc<Mutex<Peer>>) {
 
 /// Print the name of all files from another peer
 /// # Arguments
-/// * `files` - `Vec<String>` of filenames from another peer
+/// * `files` - filenames from another peer with their tags
 /// * `peer_name` - the name of the peer that holds the files
-pub fn print_external_files(files: Vec<String>, peer_name: String) {
-    let mut table = table!(["Key".italic().green()]);
-    for k in files {
-        table.add_row(row![k]);
+pub fn print_external_files(files: Vec<(String, SongMetadata)>, peer_name: String) {
+    let mut table = table!([
+        "Key".italic().green(),
+        "Artist".italic().green(),
+        "Album".italic().green(),
+        "Duration".italic().green()
+    ]);
+    for (k, tags) in files {
+        table.add_row(row![
+            k,
+            or_dash(tags.artist),
+            or_dash(tags.album),
+            format_duration(tags.duration)
+        ]);
     }
     table.set_format(*format::consts::FORMAT_BORDERS_ONLY);
     let text = format!(
@@ -216,3 +283,56 @@ pub fn print_external_files(files: Vec<String>, peer_name: String) {
     );
     println!("\n\n{}\n{}", text, table);
 }
+
+/// A tag for a table cell, `-` if the song does not have it
+fn or_dash(tag: Option<String>) -> String {
+    tag.unwrap_or_else(|| "-".to_string())
+}
+
+/// A length in seconds as `m:ss` for a table cell
+fn format_duration(duration: Option<u64>) -> String {
+    match duration {
+        Some(d) => format!("{}:{:02}", d / 60, d % 60),
+        None => "-".to_string(),
+    }
+}
+
+/// Print the songs of the network that match a search
+/// # Arguments
+/// * `peer` - the local `Peer`
+/// * `query` - part of the title, artist or album, all songs are printed if it is empty
+fn print_search_results(arc: &Arc<Mutex<Peer>>, query: &str) {
+    let peer = match arc.lock() {
+        Ok(p) => p,
+        Err(e) => e.into_inner(),
+    };
+    let entries = search(&peer, query);
+    drop(peer);
+    if entries.is_empty() {
+        println!("No songs found for \"{}\".\n", query);
+        return;
+    }
+    let mut table = table!([
+        "Title".italic().green(),
+        "Artist".italic().green(),
+        "Album".italic().green(),
+        "Duration".italic().green(),
+        "Holders".italic().green()
+    ]);
+    for entry in entries {
+        let tags = entry.metadata;
+        table.add_row(row![
+            entry.title,
+            or_dash(tags.artist),
+            or_dash(tags.album),
+            format_duration(tags.duration),
+            entry.holders.len()
+        ]);
+    }
+    table.set_format(*format::consts::FORMAT_BORDERS_ONLY);
+    println!(
+        "\n\n{}\n{}",
+        "Songs in the network".to_string().black().on_white(),
+        table
+    );
+}
@@ -1,14 +1,18 @@
 use crate::shell;
-use meff::utils::{AppListener, FileStatus};
+use meff::interface::SongMetadata;
+use meff::utils::{AppListener, FileInstructions, FileStatus, LookupResult};
+use std::sync::mpsc::SyncSender;
 use std::sync::{Arc, Mutex};
 
 #[derive(Clone)]
 pub struct Application {
     pub is_playing: Arc<Mutex<bool>>,
+    /// Tells the main thread that the peer left the network, it shuts the peer down then
+    pub left: SyncSender<()>,
 }
 
 impl AppListener for Application {
-    fn notify_status(&self, files: Vec<String>, name: String) {
+    fn notify_status(&self, files: Vec<(String, SongMetadata)>, name: String) {
         shell::print_external_files(files, name);
     }
     fn local_database_changed(&mut self, name: String, instr: FileStatus) {
@@ -35,4 +39,44 @@ impl AppListener for Application {
     fn player_stopped(&mut self) {
         *self.is_playing.lock().unwrap() = false;
     }
+
+    fn stream_buffering(&mut self, title: String, buffering: bool) {
+        if buffering {
+            println!("Buffering {}...", title);
+        } else {
+            println!("{} continues playing!", title);
+        }
+    }
+
+    fn delete_rejected(&mut self, title: String, reason: String) {
+        println!("Could not remove {}: {}", title, reason);
+    }
+
+    fn leave_progress(&mut self, handed_off: usize, total: usize) {
+        println!("Handed off {} of {} songs", handed_off, total);
+    }
+
+    fn left_network(&mut self, result: Result<(), String>) {
+        match result {
+            Ok(_) => {
+                println!("You left the network.");
+                if self.left.try_send(()).is_err() {
+                    error!("Could not tell the main thread that the network was left");
+                }
+            }
+            Err(e) => println!("Could not leave the network: {}", e),
+        }
+    }
+    fn file_lookup_finished(
+        &mut self,
+        title: String,
+        _instr: FileInstructions,
+        result: LookupResult,
+    ) {
+        match result {
+            LookupResult::FOUND => println!("Found {} in the network.", title),
+            LookupResult::MISSING => println!("{} is not in the network.", title),
+            LookupResult::TIMEOUT => println!("Looking up {} timed out.", title),
+        }
+    }
 }
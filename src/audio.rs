@@ -1,15 +1,29 @@
 use crate::interface::Peer;
+use crate::metadata::{read_format, read_format_file, AudioFormat};
 use crate::network::send_read_request;
 use crate::utils::FileInstructions::PLAY;
 use rodio::Sink;
 use std::fs;
 use std::io::{BufReader, Cursor};
+use std::path::Path;
 use std::string::ToString;
+use std::sync::Arc;
+use std::thread;
+
+mod stream;
+
+pub use stream::StreamBuffer;
+use stream::StreamReader;
 
 pub struct MusicPlayer {
-    sink: Sink,
+    /// Shared with the threads that decode streamed songs and append them once they are ready
+    sink: Arc<Sink>,
     is_playing: bool,
     current_song_name: Option<String>,
+    /// Stream that waits for its prefetch before it is played
+    pending_stream: Option<Arc<StreamBuffer>>,
+    /// Streams that are played or queued in the sink
+    streams: Vec<Arc<StreamBuffer>>,
 }
 
 pub fn create_sink() -> Result<MusicPlayer, String> {
@@ -18,21 +32,52 @@ pub fn create_sink() -> Result<MusicPlayer, String> {
         None => return Err("No output device found".to_string()),
     };
     Ok(MusicPlayer {
-        sink: Sink::new(&device),
+        sink: Arc::new(Sink::new(&device)),
         is_playing: false,
         current_song_name: None,
+        pending_stream: None,
+        streams: Vec::new(),
     })
 }
 
+/// Path in the download folder the song `name` is saved to, with the extension of its format.
+/// Path separators in `name` are replaced, so no song is saved outside of the folder.
+fn download_path(name: &str, format: Result<AudioFormat, String>) -> Result<String, String> {
+    let format = match format {
+        Ok(f) => f,
+        Err(e) => return Err(format!("Could not detect the format of {}: {}", name, e)),
+    };
+    let file_name: String = name
+        .chars()
+        .map(|c| if c == '/' || c == '\\' { '_' } else { c })
+        .collect();
+    Ok(format!("../file/{}.{}", file_name, format.extension()))
+}
+
 pub fn save_music_to_disk(music: Vec<u8>, name: &str) -> Result<(), String> {
     println!("{}", "save_music_to_disk".to_string());
-    let path = format!("../file/{}.mp3", name);
+    let path = download_path(name, read_format(&music))?;
     match fs::write(path, music) {
         Ok(_) => Ok(()),
         Err(_e) => Err("could not save file to disk".to_string()),
     }
 }
 
+/// Moves a downloaded file from `path` into the download folder
+pub fn move_music_to_disk(path: &Path, name: &str) -> Result<(), String> {
+    let target = download_path(name, read_format_file(path))?;
+    if fs::rename(path, &target).is_ok() {
+        return Ok(());
+    }
+    match fs::copy(path, &target) {
+        Ok(_) => {
+            let _ = fs::remove_file(path);
+            Ok(())
+        }
+        Err(_e) => Err("could not save file to disk".to_string()),
+    }
+}
+
 /// plays audio when mp3 is in database otherwise sends request to find file
 /// # Arguments:
 ///
@@ -53,7 +98,7 @@ pub fn play_music(
         return Err("No song name given and no current playing song!".to_string());
     }
 
-    let sound_data = match peer.get_db().data.get(&title) {
+    let sound_data = match peer.get_db().get(&title) {
         Some(data) => data,
         None => {
             send_read_request(peer, title.as_ref(), PLAY);
@@ -61,7 +106,7 @@ pub fn play_music(
         }
     };
 
-    play_music_by_vec(sound_data.clone(), sink, title)
+    play_music_by_vec(sound_data, sink, title)
 }
 
 pub fn pause_current_playing_music(sink: &mut MusicPlayer) -> Result<(), String> {
@@ -73,6 +118,12 @@ pub fn stop_current_playing_music(sink: &mut MusicPlayer) -> Result<(), String>
     sink.sink.stop();
     sink.is_playing = false;
     sink.current_song_name = None;
+    if let Some(stream) = sink.pending_stream.take() {
+        stream.close();
+    }
+    for stream in sink.streams.drain(..) {
+        stream.close();
+    }
     Ok(())
 }
 
@@ -86,13 +137,18 @@ pub fn play_music_by_vec(
     sink: &mut MusicPlayer,
     name: String,
 ) -> Result<(), String> {
-    sink.current_song_name = Some(name);
     let music_a = Cursor::new(music);
     let file = BufReader::new(music_a);
     let source = match rodio::Decoder::new(file) {
         Ok(decoded_source) => decoded_source,
-        Err(_e) => return Err("file could not be decoded. is it mp3?".to_string()),
+        Err(_e) => {
+            return Err(format!(
+                "{} could not be decoded, only MP3, FLAC, Ogg Vorbis and WAV can be played",
+                name
+            ))
+        }
     };
+    sink.current_song_name = Some(name);
     sink.sink.play();
     if sink.is_playing {
         sink.sink.append(source);
@@ -103,3 +159,87 @@ pub fn play_music_by_vec(
     }
     Ok(())
 }
+
+/// Creates the buffer for a song that is streamed from the network. The playback starts in
+/// `start_stream_when_ready` once enough of the song arrived. A stream that is still waiting for
+/// its prefetch is replaced.
+pub fn prepare_stream(
+    sink: &mut MusicPlayer,
+    title: &str,
+    size: u64,
+    peer: &Peer,
+) -> Arc<StreamBuffer> {
+    if let Some(old) = sink.pending_stream.take() {
+        old.close();
+    }
+    let stream = Arc::new(StreamBuffer::new(
+        title,
+        size,
+        peer.sender.clone(),
+        peer.ip_address,
+    ));
+    sink.pending_stream = Some(stream.clone());
+    stream
+}
+
+/// Starts playing the pending stream if its prefetch is complete. Reading the header of a song
+/// can wait for chunks that did not arrive yet, so the decoder is created on its own thread and
+/// appended to the sink from there. The working thread keeps handling the chunks meanwhile.
+/// # Returns
+/// The title of the song if the playback started
+pub fn start_stream_when_ready(sink: &mut MusicPlayer) -> Result<Option<String>, String> {
+    match &sink.pending_stream {
+        Some(stream) if stream.is_ready() => {}
+        _ => return Ok(None),
+    }
+    let stream = match sink.pending_stream.take() {
+        Some(s) => s,
+        None => return Ok(None),
+    };
+    let title = stream.title().to_string();
+    sink.streams.retain(|s| !s.is_done());
+    sink.streams.push(stream.clone());
+    sink.current_song_name = Some(title.clone());
+    if !sink.is_playing {
+        sink.is_playing = true;
+        sink.sink = create_sink()?.sink;
+    }
+    sink.sink.play();
+    let player = sink.sink.clone();
+    let decoded_title = title.clone();
+    if let Err(e) = thread::Builder::new()
+        .name("decoder_thread".to_string())
+        .spawn(
+            move || match rodio::Decoder::new(StreamReader::new(stream.clone())) {
+                Ok(source) => player.append(source),
+                Err(_e) => {
+                    stream.close();
+                    error!(
+                        "{} could not be decoded, only MP3, FLAC, Ogg Vorbis and WAV can be played",
+                        decoded_title
+                    );
+                }
+            },
+        )
+    {
+        return Err(format!(
+            "Decoder Thread could not be spawned: Error: {:?}",
+            e
+        ));
+    }
+    Ok(Some(title))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn download_path_stays_in_the_folder() {
+        let path = download_path("../song", Ok(AudioFormat::MP3)).unwrap();
+        assert_eq!(path, "../file/.._song.mp3");
+        let path = download_path("a\\b/c", Ok(AudioFormat::WAV)).unwrap();
+        assert_eq!(path, "../file/a_b_c.wav");
+        assert!(download_path("song", Err("unknown".to_string())).is_err());
+    }
+}
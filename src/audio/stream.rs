@@ -0,0 +1,290 @@
+use crate::interface::Notification;
+use crate::network::notification::Content;
+use crate::network::transfer::StreamTarget;
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::net::SocketAddr;
+use std::sync::mpsc::SyncSender;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// Capacity of the ring buffer of a stream in bytes
+const STREAM_BUFFER_SIZE: u64 = 2 * 1024 * 1024;
+/// Bytes that need to be buffered before the playback starts or continues after an underrun
+const PREFETCH_SIZE: u64 = 512 * 1024;
+/// Time the player waits for new data before the stream is given up
+const UNDERRUN_TIMEOUT: Duration = Duration::from_secs(30);
+
+struct StreamState {
+    /// The buffered bytes, the first one is at offset `start` of the song
+    data: VecDeque<u8>,
+    start: u64,
+    /// Read position of the player
+    position: u64,
+    /// Size of the whole song
+    size: u64,
+    finished: bool,
+    closed: bool,
+    underrun: bool,
+}
+
+impl StreamState {
+    fn end(&self) -> u64 {
+        self.start + self.data.len() as u64
+    }
+
+    /// Bytes the player can read without waiting
+    fn available(&self) -> u64 {
+        self.end().saturating_sub(self.position)
+    }
+}
+
+/// Bounded buffer between the network and the player of a streamed song. The transfer pushes
+/// chunks in order, the player reads them through a `StreamReader`.
+pub struct StreamBuffer {
+    title: String,
+    state: Mutex<StreamState>,
+    condvar: Condvar,
+    sender: SyncSender<Notification>,
+    own_addr: SocketAddr,
+}
+
+impl StreamBuffer {
+    /// Creates the buffer for the song `title` of `size` bytes. Buffer underruns are reported
+    /// to the working thread through `sender`.
+    pub fn new(
+        title: &str,
+        size: u64,
+        sender: SyncSender<Notification>,
+        own_addr: SocketAddr,
+    ) -> StreamBuffer {
+        StreamBuffer {
+            title: title.to_string(),
+            state: Mutex::new(StreamState {
+                data: VecDeque::new(),
+                start: 0,
+                position: 0,
+                size,
+                finished: false,
+                closed: false,
+                underrun: false,
+            }),
+            condvar: Condvar::new(),
+            sender,
+            own_addr,
+        }
+    }
+
+    pub fn title(&self) -> &str {
+        &self.title
+    }
+
+    fn lock(&self) -> MutexGuard<'_, StreamState> {
+        match self.state.lock() {
+            Ok(s) => s,
+            Err(e) => e.into_inner(),
+        }
+    }
+
+    /// Checks if enough of the song is buffered to start the playback
+    pub fn is_ready(&self) -> bool {
+        let state = self.lock();
+        state.finished || state.available() >= PREFETCH_SIZE
+    }
+
+    /// `true` once the transfer is done and the player has read everything
+    pub fn is_done(&self) -> bool {
+        let state = self.lock();
+        state.closed || (state.finished && state.available() == 0)
+    }
+
+    /// Stops the stream, the player reaches the end of the song and the transfer is given up
+    pub fn close(&self) {
+        self.lock().closed = true;
+        self.condvar.notify_all();
+    }
+
+    fn notify_buffering(&self, buffering: bool) {
+        let not = Notification {
+            content: Content::StreamBuffering {
+                title: self.title.clone(),
+                buffering,
+            },
+            from: self.own_addr,
+            signer: None,
+        };
+        // never block the audio thread, a missed event is only cosmetic
+        if self.sender.try_send(not).is_err() {
+            error!("Could not report the buffer state of {}", self.title);
+        }
+    }
+}
+
+impl StreamTarget for StreamBuffer {
+    fn free_space(&self) -> u64 {
+        STREAM_BUFFER_SIZE.saturating_sub(self.lock().available())
+    }
+
+    fn push(&self, data: &[u8]) {
+        let mut state = self.lock();
+        // drop bytes the player already consumed to make room for the new ones
+        let overflow = (state.data.len() + data.len()).saturating_sub(STREAM_BUFFER_SIZE as usize);
+        let consumed = (state.position.saturating_sub(state.start) as usize).min(state.data.len());
+        let evict = overflow.min(consumed);
+        state.data.drain(..evict);
+        state.start += evict as u64;
+        state.data.extend(data.iter());
+        self.condvar.notify_all();
+    }
+
+    fn finish(&self) {
+        self.lock().finished = true;
+        self.condvar.notify_all();
+    }
+
+    fn is_closed(&self) -> bool {
+        self.lock().closed
+    }
+}
+
+/// Source of the decoder of the player. Reads block while the network is behind.
+pub struct StreamReader {
+    buffer: Arc<StreamBuffer>,
+}
+
+impl StreamReader {
+    pub fn new(buffer: Arc<StreamBuffer>) -> StreamReader {
+        StreamReader { buffer }
+    }
+}
+
+impl Read for StreamReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let buffer = &self.buffer;
+        let mut state = buffer.lock();
+        let mut waiting_since: Option<Instant> = None;
+        loop {
+            if state.closed {
+                return Ok(0);
+            }
+            // after an underrun the player waits for a prefetch again instead of stuttering
+            let needed = if state.underrun { PREFETCH_SIZE } else { 1 };
+            if state.available() >= needed || (state.finished && state.available() > 0) {
+                break;
+            }
+            if state.finished {
+                return Ok(0);
+            }
+            if !state.underrun {
+                state.underrun = true;
+                buffer.notify_buffering(true);
+            }
+            let since = *waiting_since.get_or_insert_with(Instant::now);
+            if since.elapsed() > UNDERRUN_TIMEOUT {
+                error!("No data for {} arrived in time, stopping", buffer.title);
+                state.closed = true;
+                return Ok(0);
+            }
+            state = match buffer.condvar.wait_timeout(state, Duration::from_secs(1)) {
+                Ok((s, _)) => s,
+                Err(e) => e.into_inner().0,
+            };
+        }
+        if state.underrun {
+            state.underrun = false;
+            buffer.notify_buffering(false);
+        }
+        let offset = (state.position - state.start) as usize;
+        let length = buf.len().min(state.available() as usize);
+        let read = copy_from(&state.data, offset, &mut buf[..length]);
+        state.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for StreamReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let mut state = self.buffer.lock();
+        let target = match pos {
+            SeekFrom::Start(p) => i64::try_from(p).ok(),
+            SeekFrom::Current(d) => (state.position as i64).checked_add(d),
+            SeekFrom::End(d) => (state.size as i64).checked_add(d),
+        };
+        let target = match target {
+            Some(t) if t >= 0 && t as u64 <= state.size => t as u64,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "position is outside of the song",
+                ))
+            }
+        };
+        if target < state.start {
+            return Err(io::Error::other("position is no longer buffered"));
+        }
+        // the song arrives in order, the player would wait for everything before the position
+        if target > state.end() {
+            return Err(io::Error::other("position is not buffered yet"));
+        }
+        state.position = target;
+        Ok(state.position)
+    }
+}
+
+/// Copies the bytes of `data` starting at `offset` into `buf`
+fn copy_from(data: &VecDeque<u8>, offset: usize, buf: &mut [u8]) -> usize {
+    let (front, back) = data.as_slices();
+    let mut offset = offset;
+    let mut copied = 0;
+    for part in [front, back].iter() {
+        if copied == buf.len() {
+            break;
+        }
+        if offset >= part.len() {
+            offset -= part.len();
+            continue;
+        }
+        let n = (part.len() - offset).min(buf.len() - copied);
+        buf[copied..copied + n].copy_from_slice(&part[offset..offset + n]);
+        copied += n;
+        offset = 0;
+    }
+    copied
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::sync_channel;
+
+    fn reader(size: u64, buffered: usize) -> StreamReader {
+        let (sender, _) = sync_channel(16);
+        let own = SocketAddr::from(([127, 0, 0, 1], 1));
+        let buffer = Arc::new(StreamBuffer::new("song", size, sender, own));
+        buffer.push(&vec![7u8; buffered]);
+        StreamReader::new(buffer)
+    }
+
+    #[test]
+    fn seeks_stay_in_the_buffered_part() {
+        let mut reader = reader(100, 40);
+        assert_eq!(reader.seek(SeekFrom::Start(40)).unwrap(), 40);
+        assert_eq!(reader.seek(SeekFrom::Current(-30)).unwrap(), 10);
+        let mut buf = [0u8; 8];
+        assert_eq!(reader.read(&mut buf).unwrap(), 8);
+        assert!(reader.seek(SeekFrom::Start(41)).is_err());
+        assert!(reader.seek(SeekFrom::Current(-19)).is_err());
+        assert_eq!(reader.stream_position().unwrap(), 18);
+    }
+
+    #[test]
+    fn seeks_outside_of_the_song_fail() {
+        let mut reader = reader(100, 100);
+        assert_eq!(reader.seek(SeekFrom::End(0)).unwrap(), 100);
+        assert!(reader.seek(SeekFrom::End(1)).is_err());
+        assert!(reader.seek(SeekFrom::Start(u64::MAX)).is_err());
+        assert!(reader.seek(SeekFrom::Current(i64::MIN)).is_err());
+        assert_eq!(reader.stream_position().unwrap(), 100);
+    }
+}
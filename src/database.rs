@@ -1,18 +1,590 @@
-use std::collections::HashMap;
+use crate::metadata::{read_metadata, read_metadata_file, Cover, SongMetadata};
+use crate::network::ownership::Ownership;
+use crate::network::tombstone::Tombstone;
+use crate::utils::{Config, StorageKind};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::{env, fs, io, process};
 
-#[derive(Clone, Default)]
+mod filesystem;
+mod memory;
+mod mmap;
+
+use filesystem::FileBackend;
+use memory::MemoryBackend;
+use mmap::MmapBackend;
+
+/// Name of the file the holders table of the local peer is persisted to
+const HOLDERS_FILE: &str = "holders.json";
+/// Name of the file the ownership records of the songs are persisted to
+const OWNERS_FILE: &str = "owners.json";
+/// Name of the file the tombstones of removed songs are persisted to
+const TOMBSTONE_FILE: &str = "tombstones.json";
+/// Name of the directory inside the data directory that holds unfinished transfers
+const TRANSFER_DIR: &str = "transfers";
+/// Name of the file that maps the titles of the songs onto their content hashes
+const TITLE_FILE: &str = "titles.json";
+/// Name of the file the tags of the songs are persisted to
+const METADATA_FILE: &str = "metadata.json";
+
+/// A storage engine for the songs of the local `Database`. Implementations synchronize
+/// internally, so one backend can be shared between all clones of a `Peer`.
+pub trait StorageBackend: Send + Sync {
+    /// Stores `value` under `key`, an existing value is replaced
+    fn put(&self, key: &str, value: &[u8]) -> Result<(), String>;
+    /// Returns the value stored under `key`
+    fn get(&self, key: &str) -> Option<Vec<u8>>;
+    /// Removes `key`, does nothing if the key does not exist
+    fn delete(&self, key: &str) -> Result<(), String>;
+    /// Returns all stored keys
+    fn list(&self) -> Vec<String>;
+    /// Returns the size in bytes of the value stored under `key`
+    fn size(&self, key: &str) -> Option<u64>;
+
+    /// Returns up to `length` bytes of the value stored under `key`, starting at `offset`
+    fn get_range(&self, key: &str, offset: u64, length: u64) -> Option<Vec<u8>> {
+        let value = self.get(key)?;
+        let start = (offset as usize).min(value.len());
+        let end = (offset.saturating_add(length) as usize).min(value.len());
+        Some(value[start..end].to_vec())
+    }
+
+    /// Moves the file at `path` into the store under `key`
+    fn put_file(&self, key: &str, path: &Path) -> Result<(), String> {
+        let value = match fs::read(path) {
+            Ok(v) => v,
+            Err(e) => return Err(format!("Could not read {:?}: {:?}", path, e)),
+        };
+        self.put(key, &value)?;
+        if let Err(e) = fs::remove_file(path) {
+            error!("Could not delete {:?}: {:?}", path, e);
+        }
+        Ok(())
+    }
+}
+
+/// The songs of the local peer. Every song is stored once under its content hash, the titles
+/// are metadata that map onto the hashes. Songs are looked up by their title.
+#[derive(Clone)]
 pub struct Database {
-    pub data: HashMap<String, Vec<u8>>,
+    backend: Arc<dyn StorageBackend>,
+    titles: Arc<Mutex<HashMap<String, String>>>,
+    /// The tags read from every stored song by its content hash, without the cover art
+    metadata: Arc<Mutex<HashMap<String, SongMetadata>>>,
+    data_dir: Option<PathBuf>,
+    transfer_dir: PathBuf,
+}
+
+impl Default for Database {
+    fn default() -> Database {
+        Database::new()
+    }
 }
 
 impl Database {
+    /// Creates a database that only lives in memory
     pub fn new() -> Database {
+        let transfer_dir =
+            env::temp_dir().join(format!("meff-{}-{}", process::id(), rand::random::<u32>()));
         Database {
-            data: HashMap::new(),
+            backend: Arc::new(MemoryBackend::new()),
+            titles: Arc::new(Mutex::new(HashMap::new())),
+            metadata: Arc::new(Mutex::new(HashMap::new())),
+            data_dir: None,
+            transfer_dir,
+        }
+    }
+
+    /// Opens the database with the storage backend selected in `config`. Persistent backends
+    /// load all songs that were stored in the data directory before.
+    pub fn open(config: &Config) -> Result<Database, String> {
+        let data_dir = config.data_dir.as_path();
+        let backend: Arc<dyn StorageBackend> = match config.storage {
+            StorageKind::Memory => return Ok(Database::new()),
+            StorageKind::Filesystem => Arc::new(FileBackend::open(data_dir)?),
+            StorageKind::MemoryMapped => Arc::new(MmapBackend::open(data_dir)?),
+        };
+        let mut database = Database {
+            backend,
+            titles: Arc::new(Mutex::new(load_titles(data_dir))),
+            metadata: Arc::new(Mutex::new(HashMap::new())),
+            data_dir: Some(data_dir.to_path_buf()),
+            transfer_dir: data_dir.join(TRANSFER_DIR),
+        };
+        database.migrate_title_keys();
+        database.metadata = Arc::new(Mutex::new(database.load_table(METADATA_FILE, "metadata")));
+        database.read_missing_metadata();
+        Ok(database)
+    }
+
+    fn titles(&self) -> MutexGuard<'_, HashMap<String, String>> {
+        match self.titles.lock() {
+            Ok(t) => t,
+            Err(e) => e.into_inner(),
+        }
+    }
+
+    fn metadata_table(&self) -> MutexGuard<'_, HashMap<String, SongMetadata>> {
+        match self.metadata.lock() {
+            Ok(m) => m,
+            Err(e) => e.into_inner(),
+        }
+    }
+
+    /// Returns the tags of the song `title`, without the cover art
+    pub fn metadata(&self, title: &str) -> Option<SongMetadata> {
+        let hash = self.hash_of(title)?;
+        self.metadata_table().get(&hash).cloned()
+    }
+
+    /// Reads the cover art of the song `title` from the stored bytes. It is not kept with the
+    /// other tags to keep the metadata table small.
+    pub fn cover(&self, title: &str) -> Option<Cover> {
+        let value = self.get(title)?;
+        read_metadata(&value).ok()?.cover
+    }
+
+    /// Keeps the tags of the content stored under `hash`. Content that is no audio file, which
+    /// could only be stored before uploads were checked, is kept without tags.
+    fn record_metadata(&self, hash: &str, metadata: Result<SongMetadata, String>) {
+        let metadata = match metadata {
+            Ok(m) => m.without_cover(),
+            Err(e) => {
+                info!("Could not read the tags of {}: {}", hash, e);
+                SongMetadata::default()
+            }
+        };
+        let mut table = self.metadata_table();
+        table.insert(hash.to_string(), metadata);
+        self.save_table(METADATA_FILE, "metadata", &*table);
+    }
+
+    /// Songs that were stored before their tags or their format were read get them read once
+    fn read_missing_metadata(&self) {
+        let hashes: HashSet<String> = self.titles().values().cloned().collect();
+        for hash in hashes {
+            let known = self
+                .metadata_table()
+                .get(&hash)
+                .is_some_and(|m| m.format.is_some());
+            if known {
+                continue;
+            }
+            if let Some(value) = self.backend.get(&hash) {
+                self.record_metadata(&hash, read_metadata(&value));
+            }
+        }
+    }
+
+    /// Stores `value` under its content hash and maps `title` onto it. Content that is already
+    /// stored under another title is not stored a second time.
+    pub fn insert(&self, title: &str, value: &[u8]) -> Result<(), String> {
+        let hash = content_hash(value);
+        if self.backend.size(&hash).is_none() {
+            self.backend.put(&hash, value)?;
+        }
+        if !self.metadata_table().contains_key(&hash) {
+            self.record_metadata(&hash, read_metadata(value));
+        }
+        self.map_title(title, hash)
+    }
+
+    /// Returns the song `title`. The content is checked against its hash, a corrupt song is
+    /// treated as missing.
+    pub fn get(&self, title: &str) -> Option<Vec<u8>> {
+        let hash = self.hash_of(title)?;
+        let value = self.backend.get(&hash)?;
+        if content_hash(&value) != hash {
+            error!("{} is corrupt, its content does not match {}", title, hash);
+            return None;
+        }
+        Some(value)
+    }
+
+    /// Reads the song `title` again and checks it against its content hash
+    /// # Returns
+    /// `None` if there is no song `title`, `Some(false)` if its content is missing or corrupt
+    pub fn verify(&self, title: &str) -> Option<bool> {
+        let hash = self.hash_of(title)?;
+        match self.backend.get(&hash) {
+            Some(value) => Some(content_hash(&value) == hash),
+            None => Some(false),
+        }
+    }
+
+    /// Returns the content hash of the song `title`
+    pub fn hash_of(&self, title: &str) -> Option<String> {
+        self.titles().get(title).cloned()
+    }
+
+    /// Removes `title` from the database. The content is deleted once no title refers to it.
+    pub fn remove(&self, title: &str) -> Result<(), String> {
+        let hash = {
+            let mut titles = self.titles();
+            let hash = match titles.remove(title) {
+                Some(h) => h,
+                None => return Ok(()),
+            };
+            self.save_titles(&titles)?;
+            hash
+        };
+        self.delete_if_unreferenced(&hash)
+    }
+
+    pub fn contains(&self, title: &str) -> bool {
+        match self.hash_of(title) {
+            Some(hash) => self.backend.size(&hash).is_some(),
+            None => false,
+        }
+    }
+
+    /// Returns the titles of all songs in the database
+    pub fn keys(&self) -> Vec<String> {
+        self.titles().keys().cloned().collect()
+    }
+
+    /// Returns the size in bytes of the song `title`
+    pub fn size(&self, title: &str) -> Option<u64> {
+        self.backend.size(&self.hash_of(title)?)
+    }
+
+    /// Returns the bytes all songs take up, content stored under several titles is counted once
+    pub fn used_space(&self) -> u64 {
+        let hashes: HashSet<String> = self.titles().values().cloned().collect();
+        hashes
+            .iter()
+            .filter_map(|hash| self.backend.size(hash))
+            .sum()
+    }
+
+    /// Returns up to `length` bytes of the song `title`, starting at `offset`
+    pub fn get_range(&self, title: &str, offset: u64, length: u64) -> Option<Vec<u8>> {
+        self.backend
+            .get_range(&self.hash_of(title)?, offset, length)
+    }
+
+    /// Moves the file at `path` into the database under the title `title`
+    pub fn import(&self, title: &str, path: &Path) -> Result<(), String> {
+        let hash = hash_file(path)?;
+        if !self.metadata_table().contains_key(&hash) {
+            self.record_metadata(&hash, read_metadata_file(path));
+        }
+        if self.backend.size(&hash).is_some() {
+            if let Err(e) = fs::remove_file(path) {
+                error!("Could not delete {:?}: {:?}", path, e);
+            }
+        } else {
+            self.backend.put_file(&hash, path)?;
+        }
+        self.map_title(title, hash)
+    }
+
+    /// Maps `title` onto `hash`. The content the title referred to before is deleted if no other
+    /// title refers to it.
+    fn map_title(&self, title: &str, hash: String) -> Result<(), String> {
+        let old = {
+            let mut titles = self.titles();
+            let old = titles.insert(title.to_string(), hash);
+            self.save_titles(&titles)?;
+            old
+        };
+        match old {
+            Some(old) => self.delete_if_unreferenced(&old),
+            None => Ok(()),
+        }
+    }
+
+    fn delete_if_unreferenced(&self, hash: &str) -> Result<(), String> {
+        if self.titles().values().any(|h| h == hash) {
+            return Ok(());
+        }
+        {
+            let mut table = self.metadata_table();
+            if table.remove(hash).is_some() {
+                self.save_table(METADATA_FILE, "metadata", &*table);
+            }
+        }
+        self.backend.delete(hash)
+    }
+
+    fn save_titles(&self, titles: &HashMap<String, String>) -> Result<(), String> {
+        let dir = match &self.data_dir {
+            Some(d) => d,
+            None => return Ok(()),
+        };
+        match serde_json::to_string(titles) {
+            Ok(json) => write_atomic(&dir.join(TITLE_FILE), json.as_bytes()),
+            Err(e) => Err(format!("Could not serialize the titles: {:?}", e)),
+        }
+    }
+
+    /// Songs of data directories from before content hashes were used are stored under their
+    /// title. They are moved to their content hash.
+    fn migrate_title_keys(&self) {
+        for key in self.backend.list() {
+            if is_content_hash(&key) {
+                continue;
+            }
+            let value = match self.backend.get(&key) {
+                Some(v) => v,
+                None => continue,
+            };
+            if let Err(e) = self.insert(&key, &value) {
+                error!("Could not move {} to its content hash: {}", key, e);
+                continue;
+            }
+            if let Err(e) = self.backend.delete(&key) {
+                error!("{}", e);
+            }
+            info!("Moved {} to its content hash", key);
+        }
+    }
+
+    /// The directory the database is persisted in, `None` for a database in memory
+    pub fn data_dir(&self) -> Option<&Path> {
+        self.data_dir.as_deref()
+    }
+
+    /// The directory unfinished transfers are written to. Transfers in the data directory
+    /// survive a restart of the peer.
+    pub fn transfer_dir(&self) -> &Path {
+        &self.transfer_dir
+    }
+
+    /// Loads the holders table that was persisted in the data directory. Returns an empty
+    /// table if there is none.
+    pub fn load_holders_table(&self) -> HashMap<String, Vec<SocketAddr>> {
+        self.load_table(HOLDERS_FILE, "holders table")
+    }
+
+    /// Persists the holders table in the data directory
+    pub fn save_holders_table(&self, table: &HashMap<String, Vec<SocketAddr>>) {
+        self.save_table(HOLDERS_FILE, "holders table", table)
+    }
+
+    /// Loads the ownership records of the songs that were persisted in the data directory
+    pub fn load_owners_table(&self) -> HashMap<String, Ownership> {
+        self.load_table(OWNERS_FILE, "owners table")
+    }
+
+    /// Persists the ownership records of the songs in the data directory
+    pub fn save_owners_table(&self, table: &HashMap<String, Ownership>) {
+        self.save_table(OWNERS_FILE, "owners table", table)
+    }
+
+    /// Loads the tombstones of removed songs that were persisted in the data directory
+    pub fn load_tombstones(&self) -> HashMap<String, Tombstone> {
+        self.load_table(TOMBSTONE_FILE, "tombstones")
+    }
+
+    /// Persists the tombstones of removed songs in the data directory
+    pub fn save_tombstones(&self, table: &HashMap<String, Tombstone>) {
+        self.save_table(TOMBSTONE_FILE, "tombstones", table)
+    }
+
+    fn load_table<T: DeserializeOwned + Default>(&self, file: &str, what: &str) -> T {
+        let dir = match &self.data_dir {
+            Some(d) => d,
+            None => return T::default(),
+        };
+        let content = match fs::read_to_string(dir.join(file)) {
+            Ok(c) => c,
+            Err(_e) => return T::default(),
+        };
+        match serde_json::from_str(&content) {
+            Ok(table) => table,
+            Err(e) => {
+                error!("Could not parse {}: {:?}", what, e);
+                T::default()
+            }
+        }
+    }
+
+    fn save_table<T: Serialize>(&self, file: &str, what: &str, table: &T) {
+        let dir = match &self.data_dir {
+            Some(d) => d,
+            None => return,
+        };
+        let result = match serde_json::to_string(table) {
+            Ok(json) => write_atomic(&dir.join(file), json.as_bytes()),
+            Err(e) => Err(format!("Could not serialize {}: {:?}", what, e)),
+        };
+        if let Err(e) = result {
+            error!("{}", e);
+        }
+    }
+}
+
+/// Returns the hex encoded SHA-256 hash of a song, the key its content is stored under
+pub fn content_hash(value: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(value))
+}
+
+/// Returns the content hash of the file at `path` without reading it into memory at once
+pub fn hash_file(path: &Path) -> Result<String, String> {
+    let mut file = match File::open(path) {
+        Ok(f) => f,
+        Err(e) => return Err(format!("Could not open {:?}: {:?}", path, e)),
+    };
+    let mut hasher = Sha256::new();
+    match io::copy(&mut file, &mut hasher) {
+        Ok(_) => Ok(format!("{:x}", hasher.result())),
+        Err(e) => Err(format!("Could not read {:?}: {:?}", path, e)),
+    }
+}
+
+fn is_content_hash(key: &str) -> bool {
+    key.len() == 64 && key.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+fn load_titles(dir: &Path) -> HashMap<String, String> {
+    let content = match fs::read_to_string(dir.join(TITLE_FILE)) {
+        Ok(c) => c,
+        Err(_e) => return HashMap::new(),
+    };
+    match serde_json::from_str(&content) {
+        Ok(titles) => titles,
+        Err(e) => {
+            error!("Could not parse the titles: {:?}", e);
+            HashMap::new()
+        }
+    }
+}
+
+/// Writes `content` to a temporary file and moves it to `path`, so a crash never leaves a half
+/// written file behind.
+fn write_atomic(path: &Path, content: &[u8]) -> Result<(), String> {
+    let tmp = path.with_extension("tmp");
+    if let Err(e) = fs::write(&tmp, content) {
+        return Err(format!("Could not write {:?}: {:?}", tmp, e));
+    }
+    match fs::rename(&tmp, path) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Could not move {:?} to {:?}: {:?}", tmp, path, e)),
+    }
+}
+
+/// Stores, reads and removes songs through `backend`, which must be empty. Used by the tests of
+/// every backend.
+#[cfg(test)]
+fn check_round_trip(backend: &dyn StorageBackend) {
+    assert!(backend.list().is_empty());
+    backend.put("a", b"first song").unwrap();
+    backend.put("b", b"").unwrap();
+    assert_eq!(backend.get("a"), Some(b"first song".to_vec()));
+    assert_eq!(backend.get("b"), Some(Vec::new()));
+    assert_eq!(backend.get("c"), None);
+    assert_eq!(backend.size("a"), Some(10));
+    assert_eq!(backend.size("b"), Some(0));
+    assert_eq!(backend.get_range("a", 6, 4), Some(b"song".to_vec()));
+    assert_eq!(backend.get_range("a", 6, 100), Some(b"song".to_vec()));
+    assert_eq!(backend.get_range("a", 100, 4), Some(Vec::new()));
+    assert_eq!(backend.get_range("b", 0, 4), Some(Vec::new()));
+    assert_eq!(backend.get_range("c", 0, 4), None);
+    let mut keys = backend.list();
+    keys.sort();
+    assert_eq!(keys, vec!["a", "b"]);
+
+    backend.put("a", b"second").unwrap();
+    assert_eq!(backend.get("a"), Some(b"second".to_vec()));
+    assert_eq!(backend.get_range("a", 0, 3), Some(b"sec".to_vec()));
+    assert_eq!(backend.size("a"), Some(6));
+
+    backend.delete("a").unwrap();
+    backend.delete("a").unwrap();
+    assert_eq!(backend.get("a"), None);
+    assert_eq!(backend.size("a"), None);
+    assert_eq!(backend.list(), vec!["b"]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::AudioFormat;
+    use crate::testing::TempDir;
+
+    /// A WAV file of silence whose INFO chunk names it `title`
+    fn wav(title: &str) -> Vec<u8> {
+        let mut info = b"INFOINAM".to_vec();
+        info.extend_from_slice(&(title.len() as u32).to_le_bytes());
+        info.extend_from_slice(title.as_bytes());
+        if title.len() % 2 == 1 {
+            info.push(0);
+        }
+        let samples = vec![128u8; 8000];
+
+        let mut body = b"WAVEfmt ".to_vec();
+        body.extend_from_slice(&16u32.to_le_bytes());
+        body.extend_from_slice(&1u16.to_le_bytes()); // PCM
+        body.extend_from_slice(&1u16.to_le_bytes()); // mono
+        body.extend_from_slice(&8000u32.to_le_bytes()); // sample rate
+        body.extend_from_slice(&8000u32.to_le_bytes()); // byte rate
+        body.extend_from_slice(&1u16.to_le_bytes()); // block align
+        body.extend_from_slice(&8u16.to_le_bytes()); // bits per sample
+        body.extend_from_slice(b"LIST");
+        body.extend_from_slice(&(info.len() as u32).to_le_bytes());
+        body.extend_from_slice(&info);
+        body.extend_from_slice(b"data");
+        body.extend_from_slice(&(samples.len() as u32).to_le_bytes());
+        body.extend_from_slice(&samples);
+
+        let mut wav = b"RIFF".to_vec();
+        wav.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        wav.extend_from_slice(&body);
+        wav
+    }
+
+    #[test]
+    fn reopened_database_keeps_its_songs() {
+        for storage in &[StorageKind::Filesystem, StorageKind::MemoryMapped] {
+            let dir = TempDir::new("database");
+            let config = Config {
+                data_dir: dir.path().to_path_buf(),
+                storage: *storage,
+                ..Config::default()
+            };
+            let song = wav("Song");
+            let holder = SocketAddr::from(([127, 0, 0, 1], 47400));
+            {
+                let database = Database::open(&config).unwrap();
+                database.insert("first", &song).unwrap();
+                database.insert("second", &song).unwrap();
+                let mut holders = HashMap::new();
+                holders.insert("first".to_string(), vec![holder]);
+                database.save_holders_table(&holders);
+            }
+
+            let database = Database::open(&config).unwrap();
+            let mut titles = database.keys();
+            titles.sort();
+            assert_eq!(titles, vec!["first", "second"]);
+            assert_eq!(database.get("first"), Some(song.clone()));
+            assert_eq!(database.hash_of("second"), Some(content_hash(&song)));
+            let metadata = database.metadata("second").unwrap();
+            assert_eq!(metadata.format, Some(AudioFormat::WAV));
+            assert_eq!(metadata.title.as_deref(), Some("Song"));
+            assert_eq!(database.load_holders_table()["first"], vec![holder]);
         }
     }
 
-    pub fn get_data(&self) -> &HashMap<String, Vec<u8>> {
-        &self.data
+    #[test]
+    fn memory_database_writes_nothing() {
+        let dir = TempDir::new("database");
+        let config = Config {
+            data_dir: dir.path().to_path_buf(),
+            storage: StorageKind::Memory,
+            ..Config::default()
+        };
+        let database = Database::open(&config).unwrap();
+        database.insert("song", &wav("Song")).unwrap();
+        database.save_holders_table(&HashMap::new());
+        assert_eq!(database.data_dir(), None);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
     }
 }
@@ -0,0 +1,72 @@
+use crate::database::StorageBackend;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Keeps all songs in a `HashMap`. Nothing survives a restart of the peer.
+#[derive(Default)]
+pub struct MemoryBackend {
+    data: Mutex<HashMap<String, Vec<u8>>>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> MemoryBackend {
+        MemoryBackend {
+            data: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl StorageBackend for MemoryBackend {
+    fn put(&self, key: &str, value: &[u8]) -> Result<(), String> {
+        let mut data = match self.data.lock() {
+            Ok(d) => d,
+            Err(e) => e.into_inner(),
+        };
+        data.insert(key.to_string(), value.to_vec());
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> Option<Vec<u8>> {
+        let data = match self.data.lock() {
+            Ok(d) => d,
+            Err(e) => e.into_inner(),
+        };
+        data.get(key).cloned()
+    }
+
+    fn delete(&self, key: &str) -> Result<(), String> {
+        let mut data = match self.data.lock() {
+            Ok(d) => d,
+            Err(e) => e.into_inner(),
+        };
+        data.remove(key);
+        Ok(())
+    }
+
+    fn list(&self) -> Vec<String> {
+        let data = match self.data.lock() {
+            Ok(d) => d,
+            Err(e) => e.into_inner(),
+        };
+        data.keys().cloned().collect()
+    }
+
+    fn size(&self, key: &str) -> Option<u64> {
+        let data = match self.data.lock() {
+            Ok(d) => d,
+            Err(e) => e.into_inner(),
+        };
+        data.get(key).map(|v| v.len() as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::check_round_trip;
+
+    #[test]
+    fn round_trip() {
+        check_round_trip(&MemoryBackend::new());
+    }
+}
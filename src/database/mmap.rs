@@ -0,0 +1,160 @@
+use crate::database::filesystem::FileBackend;
+use crate::database::StorageBackend;
+use memmap::Mmap;
+use std::fs::File;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Number of songs that stay mapped, the one that was read the longest time ago is unmapped
+/// first
+const MAX_MAPS: usize = 32;
+
+/// Uses the same directory layout as `FileBackend`, but reads parts of the songs through memory
+/// maps. A song whose chunks are read one after another is only mapped once and served from the
+/// page cache.
+pub struct MmapBackend {
+    files: FileBackend,
+    /// The mapped songs, the most recently read one last
+    maps: Mutex<Vec<(String, Arc<Mmap>)>>,
+}
+
+impl MmapBackend {
+    /// Opens the store in `data_dir`, the directory is created if it does not exist yet.
+    pub fn open(data_dir: &Path) -> Result<MmapBackend, String> {
+        Ok(MmapBackend {
+            files: FileBackend::open(data_dir)?,
+            maps: Mutex::new(Vec::new()),
+        })
+    }
+
+    fn lock_maps(&self) -> MutexGuard<'_, Vec<(String, Arc<Mmap>)>> {
+        match self.maps.lock() {
+            Ok(m) => m,
+            Err(e) => e.into_inner(),
+        }
+    }
+
+    /// Drops the map of `key` once its file was replaced or deleted. Dropping it before would
+    /// keep a map that another read created in between, which serves the old song.
+    fn unmap(&self, key: &str) {
+        self.lock_maps().retain(|(k, _)| k != key);
+    }
+
+    /// Returns the memory map of `key`, maps the file if it is not mapped yet.
+    fn map(&self, key: &str) -> Option<Arc<Mmap>> {
+        let mut maps = self.lock_maps();
+        if let Some(i) = maps.iter().position(|(k, _)| k == key) {
+            let entry = maps.remove(i);
+            let map = entry.1.clone();
+            maps.push(entry);
+            return Some(map);
+        }
+        let path = self.files.path_of(key)?;
+        let file = match File::open(&path) {
+            Ok(f) => f,
+            Err(e) => {
+                error!("Could not open {:?}: {:?}", path, e);
+                return None;
+            }
+        };
+        // `FileBackend` never writes into a stored file, it moves new files over it. A map keeps
+        // the file it was created from even after the song was replaced or deleted.
+        let map = match unsafe { Mmap::map(&file) } {
+            Ok(m) => Arc::new(m),
+            Err(e) => {
+                error!("Could not map {:?}: {:?}", path, e);
+                return None;
+            }
+        };
+        if maps.len() >= MAX_MAPS {
+            maps.remove(0);
+        }
+        maps.push((key.to_string(), map.clone()));
+        Some(map)
+    }
+}
+
+impl StorageBackend for MmapBackend {
+    fn put(&self, key: &str, value: &[u8]) -> Result<(), String> {
+        self.files.put(key, value)?;
+        self.unmap(key);
+        Ok(())
+    }
+
+    /// A whole song is read straight from its file, copying it out of a map would not save
+    /// anything
+    fn get(&self, key: &str) -> Option<Vec<u8>> {
+        self.files.get(key)
+    }
+
+    fn delete(&self, key: &str) -> Result<(), String> {
+        self.files.delete(key)?;
+        self.unmap(key);
+        Ok(())
+    }
+
+    fn list(&self) -> Vec<String> {
+        self.files.list()
+    }
+
+    fn size(&self, key: &str) -> Option<u64> {
+        self.files.size(key)
+    }
+
+    fn get_range(&self, key: &str, offset: u64, length: u64) -> Option<Vec<u8>> {
+        // empty files can not be mapped
+        if self.files.size(key)? == 0 {
+            return Some(Vec::new());
+        }
+        let map = self.map(key)?;
+        let start = (offset as usize).min(map.len());
+        let end = (offset.saturating_add(length) as usize).min(map.len());
+        Some(map[start..end].to_vec())
+    }
+
+    fn put_file(&self, key: &str, path: &Path) -> Result<(), String> {
+        self.files.put_file(key, path)?;
+        self.unmap(key);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::check_round_trip;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn round_trip() {
+        let dir = TempDir::new("mmap");
+        check_round_trip(&MmapBackend::open(dir.path()).unwrap());
+    }
+
+    #[test]
+    fn replaced_song_keeps_open_maps_valid() {
+        let dir = TempDir::new("mmap");
+        let backend = MmapBackend::open(dir.path()).unwrap();
+        backend.put("a", b"first").unwrap();
+        let old = backend.map("a").unwrap();
+        backend.put("a", b"second song").unwrap();
+        assert_eq!(&old[..], b"first");
+        assert_eq!(backend.get_range("a", 0, 6), Some(b"second".to_vec()));
+        backend.delete("a").unwrap();
+        assert_eq!(&old[..], b"first");
+    }
+
+    #[test]
+    fn only_recently_read_songs_stay_mapped() {
+        let dir = TempDir::new("mmap");
+        let backend = MmapBackend::open(dir.path()).unwrap();
+        for i in 0..=MAX_MAPS {
+            let key = i.to_string();
+            backend.put(&key, b"song").unwrap();
+            backend.get_range(&key, 0, 1).unwrap();
+        }
+        let maps = backend.lock_maps();
+        assert_eq!(maps.len(), MAX_MAPS);
+        assert!(maps.iter().all(|(key, _)| key != "0"));
+    }
+}
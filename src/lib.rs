@@ -1,5 +1,7 @@
 extern crate clap;
 #[macro_use]
+extern crate lazy_static;
+#[macro_use]
 extern crate log;
 extern crate log4rs;
 
@@ -15,5 +17,9 @@ extern crate log4rs;
 pub(self) mod audio;
 pub(self) mod database;
 pub mod interface;
+mod metadata;
 pub(self) mod network;
 pub mod utils;
+
+#[cfg(test)]
+mod testing;
@@ -1,42 +1,77 @@
 use crate::interface::*;
-use std::io::{ErrorKind, Read};
+use crate::metadata::read_metadata;
+use std::io::ErrorKind;
 use std::net::TcpListener;
 use std::net::{SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc;
-use std::sync::mpsc::{Receiver, SyncSender};
-use std::sync::{Arc, Mutex};
-use std::{fs, io, thread, process};
+use std::sync::mpsc::{Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use std::{fs, io, thread};
 
-mod handshake;
+mod anti_entropy;
+pub(crate) mod catalog;
+mod codec;
+pub(crate) mod departure;
+pub(crate) mod dht;
+pub(crate) mod file_request;
+pub(crate) mod handshake;
+pub(crate) mod identity;
+mod invite;
 mod music_exchange;
 pub(crate) mod notification;
+pub(crate) mod ownership;
 mod peer;
+mod placement;
+pub(crate) mod rebalance;
 mod request;
 mod response;
+pub(crate) mod secure;
+pub(crate) mod tombstone;
+pub(crate) mod transfer;
 
 extern crate get_if_addrs;
 extern crate rand;
 
-use rand::Rng;
-
 use crate::audio::{
-    continue_paused_music, create_sink, pause_current_playing_music, play_music,
-    stop_current_playing_music, MusicPlayer,
+    continue_paused_music, create_sink, pause_current_playing_music, play_music, prepare_stream,
+    start_stream_when_ready, stop_current_playing_music, MusicPlayer,
 };
 
-use crate::utils::FileStatus::{DELETE, DOWNLOAD, NEW};
-use crate::utils::{AppListener, FileInstructions, HEARTBEAT_SLEEP_DURATION};
+use crate::database::Database;
+use crate::utils::FileStatus::DELETE;
+use crate::utils::{
+    AppListener, Config, FileInstructions, ANTI_ENTROPY_SLEEP_DURATION, CATALOG_SLEEP_DURATION,
+    GOSSIP_SLEEP_DURATION, HEARTBEAT_SLEEP_DURATION, MAINTENANCE_SLEEP_DURATION,
+    SCRUB_SLEEP_DURATION,
+};
+use anti_entropy::{merkle_digest, merkle_leaf, offered_copy_wins, start_anti_entropy};
+use catalog::{catalog_received, gossip_catalog};
+use departure::{check_leave_timeout, handoff_confirmed, leave_network, leaving_announced};
+use dht::{expire_lookups, find_node, nodes_received};
+use file_request::{
+    exist_file_response, expire_requests, fetch_completed, file_not_found, offer_requested,
+};
 use handshake::send_table_request;
+use invite::{create_secret, REJOIN_VALIDITY};
 use notification::*;
 use peer::create_peer;
+use rebalance::{continue_rebalancing, replica_stored};
 use request::{
-    change_peer_name, delete_file_request, delete_from_network, dropped_peer, exist_file,
-    exist_file_response, exit_peer, find_file, get_file, get_file_response, order_song_request,
-    push_to_db, redundant_push_to_db, request_for_table, self_status_request, send_network_table,
-    send_network_update_table, status_request,
+    check_dropped_peer, corrupt_file, delegate_removal, delete_file_request, delete_from_network,
+    dropped_peer, exist_file, file_transfer_completed, find_file, get_file, join_rejected,
+    order_song_request, ownership_update, push_to_db, request_for_table, self_status_request,
+    send_network_table, send_network_update_table, status_request,
+};
+use secure::{send_notification, Connection};
+use std::path::{Path, PathBuf};
+use tombstone::{gossip_tombstones, reject_deleted_copy, tombstone_ack, tombstones_received};
+use transfer::{
+    chunk_received, file_offer, get_chunk, offer_file, retry_stalled_transfers, save_transfers,
+    IncomingTransfer, StreamTarget, TransferKind,
 };
-use std::collections::HashMap;
-use std::path::Path;
 
 fn validate_port(port: &str) -> Result<&str, String> {
     if let Err(_e) = port.parse::<u32>() {
@@ -105,27 +140,54 @@ pub fn get_own_ip_address(port: &str) -> Result<SocketAddr, String> {
 /// is to be created
 ///
 /// `app` - listener object of the application that implements the library.
+///
+/// `config` - the `Config` of the library
 /// # Returns
-/// the peer object wrapped in a Mutex
+/// the `PeerHandle` of the running peer
 pub fn startup(
     own_name: &str,
     port: &str,
     ip_address: Option<SocketAddr>,
     app_arc: Arc<Mutex<Box<dyn AppListener + Sync>>>,
-) -> Result<Arc<Mutex<Peer>>, String> {
+    config: Config,
+) -> Result<PeerHandle, String> {
     let (sender, receiver): (SyncSender<Notification>, Receiver<Notification>) =
         mpsc::sync_channel(5);
     let sender_clone_peer = sender.clone();
-    let peer = match create_peer(own_name, port, sender_clone_peer) {
+    let mut peer = match create_peer(own_name, port, sender_clone_peer, &config) {
         Ok(p) => p,
         Err(e) => {
             return Err(e);
         }
     };
+    let invite = match ip_address {
+        Some(_) => match config.invite {
+            Some(token) => {
+                // the invite decides which network the peer belongs to
+                peer.network_secret = None;
+                Some(token)
+            }
+            // former members prove their membership with an invite of their own
+            None => peer.create_invite(REJOIN_VALIDITY).ok(),
+        },
+        None => {
+            if peer.network_secret.is_none() {
+                peer.set_network_secret(create_secret());
+            }
+            None
+        }
+    };
+    peer.join_target = ip_address;
     let own_addr = peer.ip_address;
+    let database = peer.database.clone();
+    let tcp_listener = match TcpListener::bind(&own_addr) {
+        Ok(l) => l,
+        Err(e) => return Err(format!("Could't bind TCP Listener: {:?}", e)),
+    };
+    let stop = StopSignal::default();
+    let mut threads = Vec::new();
 
     let peer_arc = Arc::new(Mutex::new(peer));
-    let peer_arc_clone_listen = peer_arc.clone();
     let peer_arc_clone_return = peer_arc.clone();
     let peer_arc_clone_working = peer_arc.clone();
     let app_arc_working = app_arc.clone();
@@ -136,10 +198,14 @@ pub fn startup(
             return Err(e);
         }
     }));
-    let _working_thread = thread::Builder::new()
+    let stop_working = stop.clone();
+    let working_thread = thread::Builder::new()
         .name("working_thread".to_string())
         .spawn(move || loop {
             let ele = receiver.recv();
+            if stop_working.is_stopped() {
+                return;
+            }
             match ele {
                 Ok(not) => {
                     let mut peer = match peer_arc_clone_working.lock() {
@@ -158,79 +224,259 @@ pub fn startup(
                 }
                 Err(e) => {
                     println!("error {}", e);
+                    return;
                 }
             }
         });
+    match working_thread {
+        Ok(t) => threads.push(t),
+        Err(_e) => return Err("Failed to spawn working thread".to_string()),
+    }
 
-    if let Err(e) = thread::Builder::new()
+    let sender_maintenance = sender.clone();
+    let sender_gossip = sender.clone();
+    let sender_catalog = sender.clone();
+    let sender_anti_entropy = sender.clone();
+    let sender_scrubber = sender.clone();
+    let stop_listen = stop.clone();
+    match thread::Builder::new()
         .name("TCPListener".to_string())
         .spawn(move || {
-            if let Err(e) = listen_tcp(peer_arc_clone_listen, sender) {
+            if let Err(e) = listen_tcp(tcp_listener, own_addr, sender, stop_listen) {
                 println!("Failed to create connection: {:?}", e);
-                process::exit(1);
             };
         }) {
-        println!("{:?}", e);
+        Ok(t) => threads.push(t),
+        Err(e) => println!("{:?}", e),
+    };
+
+    let stop_heartbeat = stop.clone();
+    match thread::Builder::new()
+        .name("Heartbeat".to_string())
+        .spawn(move || {
+            if let Err(e) = start_heartbeat(peer_arc, stop_heartbeat) {
+                eprintln!("Failed to spawn heartbeat, {:?}", e);
+            }
+        }) {
+        Ok(t) => threads.push(t),
+        Err(_e) => return Err("Failed to spawn heartbeat".to_string()),
+    };
+
+    let stop_maintenance = stop.clone();
+    match thread::Builder::new()
+        .name("Maintenance".to_string())
+        .spawn(move || {
+            start_timer(
+                sender_maintenance,
+                own_addr,
+                MAINTENANCE_SLEEP_DURATION,
+                Content::Maintenance,
+                stop_maintenance,
+            )
+        }) {
+        Ok(t) => threads.push(t),
+        Err(_e) => return Err("Failed to spawn maintenance".to_string()),
+    };
+
+    let stop_gossip = stop.clone();
+    match thread::Builder::new()
+        .name("Gossip".to_string())
+        .spawn(move || {
+            start_timer(
+                sender_gossip,
+                own_addr,
+                GOSSIP_SLEEP_DURATION,
+                Content::GossipTombstones,
+                stop_gossip,
+            )
+        }) {
+        Ok(t) => threads.push(t),
+        Err(_e) => return Err("Failed to spawn gossip".to_string()),
+    };
+
+    let stop_catalog = stop.clone();
+    match thread::Builder::new()
+        .name("Catalog".to_string())
+        .spawn(move || {
+            start_timer(
+                sender_catalog,
+                own_addr,
+                CATALOG_SLEEP_DURATION,
+                Content::GossipCatalog,
+                stop_catalog,
+            )
+        }) {
+        Ok(t) => threads.push(t),
+        Err(_e) => return Err("Failed to spawn catalog".to_string()),
+    };
+
+    let stop_anti_entropy = stop.clone();
+    match thread::Builder::new()
+        .name("AntiEntropy".to_string())
+        .spawn(move || {
+            start_timer(
+                sender_anti_entropy,
+                own_addr,
+                ANTI_ENTROPY_SLEEP_DURATION,
+                Content::AntiEntropy,
+                stop_anti_entropy,
+            )
+        }) {
+        Ok(t) => threads.push(t),
+        Err(_e) => return Err("Failed to spawn anti-entropy".to_string()),
+    };
+
+    let stop_scrubber = stop.clone();
+    match thread::Builder::new()
+        .name("Scrubber".to_string())
+        .spawn(move || start_scrubber(database, sender_scrubber, own_addr, stop_scrubber))
+    {
+        Ok(t) => threads.push(t),
+        Err(_e) => return Err("Failed to spawn scrubber".to_string()),
     };
 
-    let _peer_arc_clone_interact = peer_arc.clone();
+    let handle = PeerHandle::new(peer_arc_clone_return, stop, threads);
 
     //send request existing network table
     match ip_address {
         Some(ip) => {
-            send_table_request(ip, own_addr, own_name);
+            if let Err(e) = send_table_request(ip, own_addr, own_name, invite) {
+                if let Err(stop_error) = handle.shutdown() {
+                    error!("{}", stop_error);
+                }
+                return Err(e);
+            }
         }
         None => {
             println!("Ip address is empty");
         }
     }
 
-    if let Err(_e) = thread::Builder::new()
-        .name("Heartbeat".to_string())
-        .spawn(move || {
-            if let Err(e) = start_heartbeat(peer_arc) {
-                eprintln!("Failed to spawn heartbeat, {:?}", e);
-            }
-        })
-    {
-        return Err("Failed to spawn heartbeat".to_string());
-    };
+    Ok(handle)
+}
 
-    Ok(peer_arc_clone_return)
+/// Tells the threads of the library to stop. Threads that wait for their next run are woken up
+/// right away.
+#[derive(Clone, Default)]
+pub(crate) struct StopSignal {
+    stopped: Arc<(Mutex<bool>, Condvar)>,
 }
 
-fn listen_tcp(arc: Arc<Mutex<Peer>>, sender: SyncSender<Notification>) -> Result<(), String> {
+impl StopSignal {
+    pub fn stop(&self) {
+        let (lock, condvar) = &*self.stopped;
+        let mut stopped = match lock.lock() {
+            Ok(s) => s,
+            Err(e) => e.into_inner(),
+        };
+        *stopped = true;
+        condvar.notify_all();
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        let (lock, _) = &*self.stopped;
+        match lock.lock() {
+            Ok(s) => *s,
+            Err(e) => *e.into_inner(),
+        }
+    }
+
+    /// Waits for `duration` unless the threads are told to stop before
+    /// # Returns
+    /// `true` if the threads have to stop
+    pub fn wait(&self, duration: Duration) -> bool {
+        let (lock, condvar) = &*self.stopped;
+        let stopped = match lock.lock() {
+            Ok(s) => s,
+            Err(e) => e.into_inner(),
+        };
+        let result = condvar.wait_timeout_while(stopped, duration, |stopped| !*stopped);
+        match result {
+            Ok((s, _)) => *s,
+            Err(e) => *e.into_inner().0,
+        }
+    }
+}
+
+/// Stops all threads of the peer in `arc` and saves its unfinished transfers
+/// # Returns
+/// The threads that did not stop cleanly as error message on `Err`
+pub(crate) fn shutdown(
+    arc: &Arc<Mutex<Peer>>,
+    stop: &StopSignal,
+    threads: Vec<JoinHandle<()>>,
+) -> Result<(), String> {
+    stop.stop();
     let peer = match arc.lock() {
         Ok(p) => p,
         Err(e) => e.into_inner(),
     };
-    let listen_ip = peer.ip_address;
+    let own_addr = peer.ip_address;
+    let sender = peer.sender.clone();
     drop(peer);
-    let listener = match TcpListener::bind(&listen_ip) {
-        Ok(l) => l,
-        Err(_e) => {
-            println!("Error: {:?}", _e);
-            return Err("Could't bind TCP Listener.".to_string());
+    // wake up the working thread and the TCP listener, both block until something arrives
+    let wake_up = Notification {
+        content: Content::Heartbeat,
+        from: own_addr,
+        signer: None,
+    };
+    let _ = sender.send(wake_up);
+    let _ = TcpStream::connect(own_addr);
+    let mut failed = Vec::new();
+    for thread in threads {
+        let name = thread.thread().name().unwrap_or("unnamed").to_string();
+        if thread.join().is_err() {
+            failed.push(name);
         }
+    }
+    let peer = match arc.lock() {
+        Ok(p) => p,
+        Err(e) => e.into_inner(),
     };
+    save_transfers(&peer);
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("The threads {} panicked", failed.join(", ")))
+    }
+}
+
+/// The most connections that are read at the same time. Further connections are closed until
+/// one of them is done.
+const MAX_CONNECTIONS: usize = 32;
+
+/// Accepts connections and reads each one on its own thread, so a slow or silent client only
+/// holds up its own connection and never the listener
+fn listen_tcp(
+    listener: TcpListener,
+    listen_ip: SocketAddr,
+    sender: SyncSender<Notification>,
+    stop: StopSignal,
+) -> Result<(), String> {
+    let open = Arc::new(AtomicUsize::new(0));
     for stream in listener.incoming() {
-        let mut buf = String::new();
+        if stop.is_stopped() {
+            return Ok(());
+        }
         match stream {
-            Ok(mut s) => {
-                if let Err(_e) = s.read_to_string(&mut buf) {
-                    error!("Could not read the stream to a string.");
-                };
-                let des: Notification = match serde_json::from_str(&buf) {
-                    Ok(val) => val,
-                    Err(e) => {
-                        dbg!(e);
-                        println!("Could not deserialize notification");
-                        continue; // skip this stream
-                    }
-                };
-                if let Err(_e) = sender.send(des) {
-                    error!("Could not send notification through the channel.");
-                };
+            Ok(s) => {
+                if open.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+                    open.fetch_sub(1, Ordering::SeqCst);
+                    error!("Too many open connections, closing {:?}", s.peer_addr());
+                    continue;
+                }
+                let open_clone = open.clone();
+                let sender_clone = sender.clone();
+                if let Err(e) = thread::Builder::new()
+                    .name("connection_thread".to_string())
+                    .spawn(move || {
+                        read_connection(s, listen_ip, sender_clone);
+                        open_clone.fetch_sub(1, Ordering::SeqCst);
+                    })
+                {
+                    open.fetch_sub(1, Ordering::SeqCst);
+                    error!("Connection Thread could not be spawned: Error: {:?}", e);
+                }
             }
             Err(_e) => {
                 println!("could not read stream");
@@ -241,10 +487,45 @@ fn listen_tcp(arc: Arc<Mutex<Peer>>, sender: SyncSender<Notification>) -> Result
     Ok(())
 }
 
+/// Reads the notifications of one connection and hands them to the working thread. Most
+/// connections carry one notification, the ones of a `Session` are read until the other peer
+/// closes them.
+fn read_connection(stream: TcpStream, listen_ip: SocketAddr, sender: SyncSender<Notification>) {
+    let mut connection = match Connection::open(stream, &listen_ip, false) {
+        Ok(c) => c,
+        Err(e) => {
+            error!("{}", e);
+            println!("Could not read notification");
+            return;
+        }
+    };
+    let mut first = true;
+    loop {
+        let des: Notification = match connection.receive() {
+            Ok(val) => val,
+            Err(e) => {
+                // a closed or idle connection ends the same way after its last notification
+                if first {
+                    error!("{}", e);
+                    println!("Could not read notification");
+                }
+                return;
+            }
+        };
+        first = false;
+        if let Err(_e) = sender.send(des) {
+            error!("Could not send notification through the channel.");
+            return;
+        };
+    }
+}
+
 /// starts the heartbeat
-fn start_heartbeat(arc: Arc<Mutex<Peer>>) -> Result<(), String> {
+fn start_heartbeat(arc: Arc<Mutex<Peer>>, stop: StopSignal) -> Result<(), String> {
     loop {
-        thread::sleep(HEARTBEAT_SLEEP_DURATION);
+        if stop.wait(HEARTBEAT_SLEEP_DURATION) {
+            return Ok(());
+        }
         let peer = match arc.lock() {
             Ok(p) => p,
             Err(e) => e.into_inner(),
@@ -263,6 +544,59 @@ fn start_heartbeat(arc: Arc<Mutex<Peer>>) -> Result<(), String> {
     }
 }
 
+/// Regularly sends `content` to the working thread, e.g. to run the maintenance tasks like
+/// retrying stalled transfers.
+fn start_timer(
+    sender: SyncSender<Notification>,
+    own_addr: SocketAddr,
+    interval: Duration,
+    content: Content,
+    stop: StopSignal,
+) {
+    while !stop.wait(interval) {
+        let not = Notification {
+            content: content.clone(),
+            from: own_addr,
+            signer: None,
+        };
+        match sender.try_send(not) {
+            Ok(_) => {}
+            // the working thread is busy, the next run catches up
+            Err(TrySendError::Full(_)) => {}
+            Err(TrySendError::Disconnected(_)) => return,
+        }
+    }
+}
+
+/// Regularly reads all songs of `database` again and reports the ones that do not match their
+/// content hash to the working thread.
+fn start_scrubber(
+    database: Database,
+    sender: SyncSender<Notification>,
+    own_addr: SocketAddr,
+    stop: StopSignal,
+) {
+    while !stop.wait(SCRUB_SLEEP_DURATION) {
+        for key in database.keys() {
+            if stop.is_stopped() {
+                return;
+            }
+            if database.verify(&key) != Some(false) {
+                continue;
+            }
+            error!("Found a corrupt copy of {}", key);
+            let not = Notification {
+                content: Content::CorruptFile { key },
+                from: own_addr,
+                signer: None,
+            };
+            if sender.send(not).is_err() {
+                return;
+            }
+        }
+    }
+}
+
 /// send the heartbeat request to all targets in `targets`
 fn send_heartbeat(targets: &[SocketAddr], peer: &mut Peer) {
     let mut cloned_peer = peer.clone();
@@ -277,11 +611,12 @@ fn send_heartbeat(targets: &[SocketAddr], peer: &mut Peer) {
         let not = Notification {
             content: Content::Heartbeat,
             from: *cloned_peer.get_ip(),
+            signer: None,
         };
-        match serde_json::to_writer(&stream, &not) {
+        match send_notification(stream, &not) {
             Ok(ser) => ser,
             Err(_e) => {
-                println!("Failed to serialize SendRequest {:?}", &not);
+                println!("Failed to send request {:?}", &not);
             }
         };
     }
@@ -295,24 +630,49 @@ fn handle_notification(
 ) {
     //dbg!(&notification);
     let sender = notification.from;
+    let signer = notification.signer;
+    if signer.is_some() && notification.content.is_internal() {
+        error!(
+            "{:?} sent the local notification {:?}",
+            sender, notification.content
+        );
+        return;
+    }
+    // the address of a known peer can not be used by another identity
+    if let (Some(id), Some(known)) = (&signer, peer.id_of(&sender)) {
+        if *id != known {
+            error!(
+                "{} sent a notification as {:?}, which belongs to {}",
+                id, sender, known
+            );
+            return;
+        }
+    }
+    if let Some(id) = &signer {
+        if !notification.content.is_join() && !peer.is_member(id) {
+            error!(
+                "Ignored {:?} of {}, which is not a member of the network",
+                sender, id
+            );
+            return;
+        }
+    }
     match notification.content {
         Content::PushToDB { key, value, .. } => {
             push_to_db(key, value, peer, listener);
         }
-        Content::RedundantPushToDB { key, value, from } => {
-            redundant_push_to_db(key, value, peer, listener, from);
-        }
-        Content::ChangePeerName { value } => {
-            change_peer_name(value, sender, peer);
+        Content::SendNetworkTable { value, secret } => {
+            send_network_table(value, secret, signer, sender, peer);
         }
-        Content::SendNetworkTable { value } => {
-            send_network_table(value, peer);
+        Content::SendNetworkUpdateTable { value, proof } => {
+            send_network_update_table(value, proof, signer, peer);
         }
-        Content::SendNetworkUpdateTable { value } => {
-            send_network_update_table(value, peer);
-        }
-        Content::RequestForTable { value } => {
-            request_for_table(value, sender, peer);
+        Content::RequestForTable {
+            value,
+            hello,
+            invite,
+        } => {
+            request_for_table(value, hello, invite, sender, peer);
         }
         Content::FindFile { song_name, instr } => {
             find_file(instr, song_name, peer, listener);
@@ -321,38 +681,70 @@ fn handle_notification(
             exist_file(song_name, id, sender, peer);
         }
         Content::ExistFileResponse { song_name, id } => {
-            exist_file_response(song_name, id, sender, peer);
+            exist_file_response(song_name, id, sender, peer, listener);
         }
-        Content::GetFile { key, instr } => {
-            get_file(instr, key, sender, peer);
+        Content::FileNotFound { song_name, id } => {
+            file_not_found(song_name, id, sender, peer, listener);
         }
-        Content::GetFileResponse { value, instr, key } => {
-            if get_file_response(&instr, &key, value, peer, sink).is_ok() {
-                match instr {
-                    FileInstructions::PLAY => listener.player_playing(Some(key)),
-                    FileInstructions::GET => {
-                        listener.local_database_changed(key, DOWNLOAD);
-                    }
-                    FileInstructions::ORDER => {
-                        listener.local_database_changed(key, NEW);
-                    }
-                    _ => {}
-                }
+        Content::GetFile { key, instr, id } => {
+            get_file(instr, key, id, sender, peer);
+        }
+        Content::DeleteFileRequest { tombstone } => {
+            let song_name = tombstone.title.clone();
+            if delete_file_request(tombstone, signer, sender, peer) {
+                listener.local_database_changed(song_name, DELETE);
+            }
+        }
+        Content::Tombstones { tombstones } => {
+            for song_name in tombstones_received(tombstones, peer) {
+                listener.local_database_changed(song_name, DELETE);
             }
         }
-        Content::DeleteFileRequest { song_name } => {
-            delete_file_request(&song_name, peer);
-            listener.local_database_changed(song_name, DELETE);
+        Content::TombstoneAck { title, deleted } => {
+            tombstone_ack(&title, deleted, sender, peer);
+        }
+        Content::GossipTombstones => {
+            gossip_tombstones(peer);
+        }
+        Content::Catalog { listings } => {
+            catalog_received(listings, sender, peer);
+        }
+        Content::GossipCatalog => {
+            gossip_catalog(peer);
+        }
+        Content::AntiEntropy => {
+            start_anti_entropy(peer);
+        }
+        Content::MerkleDigest { path, hashes } => {
+            merkle_digest(path, hashes, sender, peer);
+        }
+        Content::MerkleLeaf { path, entries } => {
+            merkle_leaf(path, entries, sender, peer);
+        }
+        Content::DeleteRejected { song_name, reason } => {
+            listener.delete_rejected(song_name, reason);
+        }
+        Content::DelegateRemoval {
+            song_name,
+            delegate,
+        } => {
+            delegate_removal(song_name, delegate, peer);
+        }
+        Content::OwnershipUpdate { ownership } => {
+            ownership_update(ownership, peer);
         }
         Content::Response { .. } => {}
-        Content::ExitPeer { addr } => {
-            exit_peer(addr, peer);
+        Content::ExitPeer { .. } => {
+            leave_network(peer, listener);
+        }
+        Content::Leaving { cancelled } => {
+            leaving_announced(cancelled, sender, peer);
         }
         Content::OrderSongRequest { song_name } => {
             order_song_request(song_name, peer);
         }
-        Content::DeleteFromNetwork { name } => {
-            delete_from_network(name, peer);
+        Content::DeleteFromNetwork { id } => {
+            delete_from_network(id, signer, peer);
         }
         Content::SelfStatusRequest {} => {
             self_status_request(peer);
@@ -388,84 +780,138 @@ fn handle_notification(
             };
         }
         Content::DroppedPeer { addr } => {
+            check_dropped_peer(addr, peer);
+        }
+        Content::PeerUnreachable { addr } => {
             dropped_peer(addr, peer);
         }
         Content::Heartbeat => {}
-    }
-}
-
-pub fn send_write_request(
-    target: SocketAddr,
-    origin: SocketAddr,
-    data: (String, Vec<u8>),
-    redundant: bool,
-    peer: &mut Peer,
-) {
-    let arc_peer = Arc::new(Mutex::new(peer.clone()));
-    if let Err(e) = thread::Builder::new()
-        .name("request_thread".to_string())
-        .spawn(move || {
-            let mut peer_lock = match arc_peer.lock() {
-                Ok(p) => p,
-                Err(e) => e.into_inner(),
+        Content::JoinRejected { reason, hello } => {
+            join_rejected(reason, hello, sender, peer);
+        }
+        Content::FileOffer {
+            key,
+            kind,
+            manifest,
+            ownership,
+        } => {
+            if !offer_requested(&key, &kind, sender, peer) {
+                return;
+            }
+            let stores_song = match &kind {
+                TransferKind::Redundant { .. } => true,
+                TransferKind::Response(instr) => *instr == FileInstructions::ORDER,
             };
-            let stream = match TcpStream::connect(target) {
-                Ok(s) => s,
-                Err(_e) => {
-                    handle_lost_connection(target, &mut peer_lock);
+            if stores_song {
+                if reject_deleted_copy(&key, ownership.as_ref(), sender, peer) {
+                    return;
+                }
+                if !offered_copy_wins(&key, &manifest.hash, ownership.as_ref(), peer) {
+                    info!(
+                        "Keeping the own copy of {} and sending it to {:?}",
+                        key, sender
+                    );
+                    send_write_request(sender, peer.ip_address, key, peer);
                     return;
                 }
+                if let Some(ownership) = ownership {
+                    peer.record_ownership(ownership, &manifest.hash);
+                }
+            }
+            let stream = match kind {
+                TransferKind::Response(FileInstructions::PLAY) => {
+                    let buffer: Arc<dyn StreamTarget> =
+                        prepare_stream(sink, &key, manifest.size, peer);
+                    Some(buffer)
+                }
+                _ => None,
             };
-            if let true = redundant {
-                let not = Notification {
-                    content: Content::RedundantPushToDB {
-                        key: data.0,
-                        value: data.1,
-                        from: origin.to_string(),
-                    },
-                    from: origin,
-                };
-                match serde_json::to_writer(&stream, &not) {
-                    Ok(ser) => ser,
-                    Err(e) => {
-                        error!("Could not serialize {:?}, Error: {:?}", &not, e);
-                        println!("Failed to serialize SendRequest {:?}", &not);
-                    }
-                };
+            let received = file_offer(key, kind, manifest, stream, sender, peer);
+            transfer_finished(received, peer, sink, listener);
+            start_stream_playback(sink, listener);
+        }
+        Content::GetChunk { key, index } => {
+            get_chunk(key, index, sender, peer);
+        }
+        Content::Chunk { key, index, data } => {
+            let received = chunk_received(key, index, data, sender, peer);
+            transfer_finished(received, peer, sink, listener);
+            start_stream_playback(sink, listener);
+        }
+        Content::Maintenance => {
+            retry_stalled_transfers(peer);
+            expire_lookups(peer);
+            continue_rebalancing(peer);
+            check_leave_timeout(peer, listener);
+            expire_requests(peer, listener);
+        }
+        Content::StreamBuffering { title, buffering } => {
+            listener.stream_buffering(title, buffering);
+        }
+        Content::CorruptFile { key } => {
+            corrupt_file(key, peer);
+        }
+        Content::HoldersUpdate { key, holders } => {
+            peer.set_holders(&key, holders);
+        }
+        Content::ReplicaStored { key, hash } => {
+            handoff_confirmed(&key, &hash, sender, peer, listener);
+            if replica_stored(key.clone(), hash, sender, peer) {
+                listener.local_database_changed(key, DELETE);
             }
-        })
-    {
-        error!("Request Thread could not be spawned: Error: {:?}", e);
+        }
+        Content::FindNode {
+            target,
+            lookup,
+            name,
+            proof,
+        } => {
+            if let Some(id) = signer {
+                find_node(target, lookup, name, proof, id, sender, peer);
+            }
+        }
+        Content::Nodes {
+            lookup,
+            name,
+            contacts,
+        } => {
+            if let Some(id) = signer {
+                nodes_received(lookup, name, id, contacts, sender, peer);
+            }
+        }
     }
 }
 
-/// Selects a random `SocketAddr` from the `network_table` that is not equal to `own_ip`. Returns
-/// `None` if there is no other `SocketAddr` in `network_table`.
-fn other_random_target(
-    network_table: &HashMap<String, SocketAddr>,
-    own_ip: &SocketAddr,
-) -> Option<SocketAddr> {
-    if network_table.len() == 1 {
-        return None;
-    }
-    let mut rng = rand::thread_rng();
-    let mut index = rng.gen_range(0, network_table.len());
-    let mut target = match network_table.values().nth(index) {
-        Some(t) => t,
-        None => {
-            return None;
+/// Handles a transfer that received all of its chunks. A stream was played while it was received,
+/// only the request it answered is resolved.
+fn transfer_finished(
+    received: Option<(IncomingTransfer, Option<PathBuf>)>,
+    peer: &mut Peer,
+    sink: &mut MusicPlayer,
+    listener: &mut Box<dyn AppListener + Sync>,
+) {
+    match received {
+        Some((transfer, Some(path))) => {
+            file_transfer_completed(transfer, &path, peer, sink, listener)
         }
-    };
-    while target == own_ip {
-        index = rng.gen_range(0, network_table.len());
-        target = match network_table.values().nth(index) {
-            Some(t) => t,
-            None => {
-                return None;
-            }
-        };
+        Some((transfer, None)) => fetch_completed(&transfer.key, peer, listener),
+        None => {}
+    }
+}
+
+/// Starts the playback of a streamed song as soon as enough of it is buffered
+fn start_stream_playback(sink: &mut MusicPlayer, listener: &mut Box<dyn AppListener + Sync>) {
+    match start_stream_when_ready(sink) {
+        Ok(Some(title)) => listener.player_playing(Some(title)),
+        Ok(None) => {}
+        Err(e) => error!("{}", e),
     }
-    Some(*target)
+}
+
+/// Sends a copy of the song `key` to `target`, which pulls it chunk by chunk
+pub fn send_write_request(target: SocketAddr, origin: SocketAddr, key: String, peer: &mut Peer) {
+    let from = origin.to_string();
+    offer_file(target, peer, &key, TransferKind::Redundant { from });
 }
 
 /// Communicate to the listener that we want to find the location of a given file
@@ -476,6 +922,23 @@ pub fn send_read_request(peer: &mut Peer, name: &str, instr: FileInstructions) {
             song_name: name.to_string(),
         },
         from: peer.ip_address,
+        signer: None,
+    };
+    if let Err(e) = peer.sender.send(not) {
+        error!("Could not send notification {:?}", e);
+    };
+}
+
+/// Communicate to the listener that the peer `delegate` may remove the song `name` of the local
+/// peer from now on
+pub fn send_delegate_request(peer: &mut Peer, name: &str, delegate: &str) {
+    let not = Notification {
+        content: Content::DelegateRemoval {
+            song_name: name.to_string(),
+            delegate: delegate.to_string(),
+        },
+        from: peer.ip_address,
+        signer: None,
     };
     if let Err(e) = peer.sender.send(not) {
         error!("Could not send notification {:?}", e);
@@ -488,6 +951,7 @@ pub fn send_delete_peer_request(peer: &mut Peer) {
             addr: peer.ip_address,
         },
         from: peer.ip_address,
+        signer: None,
     };
     if let Err(e) = peer.sender.send(not) {
         error!("Could not send notification {:?}", e);
@@ -506,19 +970,20 @@ pub fn send_status_request(target: SocketAddr, from: SocketAddr, peer: &mut Peer
     let not = Notification {
         content: Content::StatusRequest {},
         from,
+        signer: None,
     };
 
-    match serde_json::to_writer(&stream, &not) {
+    match send_notification(stream, &not) {
         Ok(ser) => ser,
         Err(_e) => {
-            println!("Failed to serialize SendRequest {:?}", &not);
+            println!("Failed to send request {:?}", &not);
         }
     };
 }
 
 fn send_local_file_status(
     target: SocketAddr,
-    files: Vec<String>,
+    files: Vec<(String, SongMetadata)>,
     from: SocketAddr,
     peer_name: String,
 ) {
@@ -528,6 +993,7 @@ fn send_local_file_status(
             name: peer_name,
         },
         from,
+        signer: None,
     };
 
     tcp_request_with_notification(target, not);
@@ -537,6 +1003,7 @@ pub fn send_play_request(name: Option<String>, peer: &mut Peer, state: MusicStat
     let not = Notification {
         content: Content::PlayAudioRequest { name, state },
         from: peer.ip_address,
+        signer: None,
     };
     if let Err(e) = peer.sender.send(not) {
         error!("Could not send notification {:?}", e);
@@ -570,17 +1037,19 @@ fn send_dropped_peer_notification(target: SocketAddr, dropped_addr: SocketAddr,
     let not = Notification {
         content: Content::DroppedPeer { addr: dropped_addr },
         from: *peer.get_ip(),
+        signer: None,
     };
-    if let Err(_e) = serde_json::to_writer(&stream, &not) {
-        println!("Failed to serialize SendRequest {:?}", &not);
+    if let Err(_e) = send_notification(stream, &not) {
+        println!("Failed to send request {:?}", &not);
     }
 }
 
-/// Function to check file path to mp3 and saves to db afterwards
+/// Function to check file path to a song and saves to db afterwards. Files that are no MP3,
+/// FLAC, Ogg Vorbis or WAV song are rejected, AAC songs can not be played.
 /// # Arguments:
 ///
-/// * `name` - String including mp3 name (key in our database)
-/// * `file_path` - Path to the mp3 file
+/// * `name` - String including song name (key in our database)
+/// * `file_path` - Path to the song file
 /// * `peer` - Peer
 ///
 /// # Returns:
@@ -591,12 +1060,22 @@ pub fn push_music_to_database(
     addr: SocketAddr,
     peer: &mut Peer,
 ) -> Result<(), io::Error> {
-    // get mp3 file
+    // get song file
     let path = Path::new(file_path);
     if path.exists() {
         let read_result = fs::read(path);
         match read_result {
             Ok(content) => {
+                let checked = read_metadata(&content).and_then(|metadata| match metadata.format {
+                    Some(format) if !format.is_playable() => {
+                        Err(format!("{} songs can not be played", format.extension()))
+                    }
+                    _ => Ok(()),
+                });
+                if let Err(e) = checked {
+                    println!("{:?} can not be uploaded. {}", path, e);
+                    return Err(io::Error::new(ErrorKind::InvalidData, e));
+                }
                 let not = Notification {
                     content: Content::PushToDB {
                         key: name.to_string(),
@@ -604,6 +1083,7 @@ pub fn push_music_to_database(
                         from: addr.to_string(),
                     },
                     from: addr,
+                    signer: None,
                 };
                 if let Err(e) = peer.sender.send(not) {
                     error!("Could not send notification {:?}", e);
@@ -0,0 +1,353 @@
+use crate::interface::{Notification, Peer};
+use crate::network::music_exchange::send_file_request;
+use crate::network::notification::{tcp_request_with_notification, Content};
+use crate::network::ownership::Ownership;
+use crate::network::send_write_request;
+use crate::network::tombstone::reject_deleted_copy;
+use crate::utils::FileInstructions;
+use rand::seq::SliceRandom;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+/// Number of children of every inner node of a `MerkleTree`
+const FANOUT: usize = 16;
+/// Number of levels below the root, the tree has `FANOUT ^ DEPTH` leaves
+const DEPTH: usize = 2;
+
+/// Hash of a node of a `MerkleTree`
+pub type NodeHash = [u8; 32];
+
+/// Merkle tree over the songs two peers should both hold. Every song is placed in a leaf by the
+/// hash of its title, so two peers can find the leaves in which they differ by comparing the
+/// hashes from the root down instead of listing all their songs.
+pub struct MerkleTree {
+    /// The titles and content hashes of the songs in each leaf, sorted by title
+    leaves: Vec<Vec<(String, String)>>,
+}
+
+impl MerkleTree {
+    pub fn new(entries: Vec<(String, String)>) -> MerkleTree {
+        let mut leaves = vec![Vec::new(); FANOUT.pow(DEPTH as u32)];
+        for (title, hash) in entries {
+            leaves[leaf_index(&title)].push((title, hash));
+        }
+        for leaf in leaves.iter_mut() {
+            leaf.sort();
+        }
+        MerkleTree { leaves }
+    }
+
+    /// The hashes of the children of the node at `path`, the root has the empty path
+    pub fn children(&self, path: &[u8]) -> Vec<NodeHash> {
+        (0..FANOUT)
+            .map(|i| {
+                let mut child = path.to_vec();
+                child.push(i as u8);
+                self.hash(&child)
+            })
+            .collect()
+    }
+
+    /// The songs in the leaf at `path`
+    pub fn leaf(&self, path: &[u8]) -> Vec<(String, String)> {
+        match self.leaves.get(first_leaf(path)) {
+            Some(leaf) if path.len() == DEPTH => leaf.clone(),
+            _ => Vec::new(),
+        }
+    }
+
+    fn hash(&self, path: &[u8]) -> NodeHash {
+        let mut hasher = Sha256::new();
+        if path.len() >= DEPTH {
+            for (title, hash) in self.leaf(path) {
+                hasher.input(title.as_bytes());
+                hasher.input([0]);
+                hasher.input(hash.as_bytes());
+                hasher.input([0]);
+            }
+        } else {
+            for child in self.children(path) {
+                hasher.input(child);
+            }
+        }
+        let mut node = [0u8; 32];
+        node.copy_from_slice(&hasher.result());
+        node
+    }
+}
+
+/// The leaf a song with `title` is placed in
+fn leaf_index(title: &str) -> usize {
+    let hash = Sha256::digest(title.as_bytes());
+    let mut index = 0;
+    for level in 0..DEPTH {
+        let nibble = if level % 2 == 0 {
+            hash[level / 2] >> 4
+        } else {
+            hash[level / 2] & 0x0f
+        };
+        index = index * FANOUT + nibble as usize;
+    }
+    index
+}
+
+/// The first leaf below the node at `path`
+fn first_leaf(path: &[u8]) -> usize {
+    let mut index = 0;
+    for level in 0..DEPTH {
+        let child = path.get(level).map_or(0, |c| *c as usize);
+        index = index * FANOUT + child.min(FANOUT - 1);
+    }
+    index
+}
+
+/// Builds the tree over the songs of the local peer that the peer at `other` should hold as well,
+/// the songs both of them are responsible for on the hash ring
+fn shared_tree(other: SocketAddr, peer: &Peer) -> MerkleTree {
+    let own = peer.ip_address;
+    let database = peer.get_db();
+    let ring = peer.ring();
+    let entries = database
+        .keys()
+        .into_iter()
+        .filter(|title| {
+            let responsible = ring.responsible(title, peer.replication_factor);
+            responsible.contains(&own) && responsible.contains(&other)
+        })
+        .filter_map(|title| database.hash_of(&title).map(|hash| (title, hash)))
+        .collect();
+    MerkleTree::new(entries)
+}
+
+/// Starts a round of anti-entropy with a random other peer. Both peers compare the songs they
+/// should both hold and exchange the ones only one of them has.
+pub fn start_anti_entropy(peer: &mut Peer) {
+    // in DHT mode the responsible peers of a song are only known after a lookup
+    if peer.routing.is_some() {
+        return;
+    }
+    let own = peer.ip_address;
+    let others: Vec<SocketAddr> = peer
+        .network_table
+        .values()
+        .filter(|addr| **addr != own)
+        .cloned()
+        .collect();
+    if let Some(target) = others.choose(&mut rand::thread_rng()) {
+        let hashes = shared_tree(*target, peer).children(&[]);
+        send_merkle_digest(*target, own, Vec::new(), hashes);
+    }
+}
+
+/// Compares the hashes of the children of the node at `path` that the peer at `sender` computed
+/// with the own ones and descends into the children that differ
+pub fn merkle_digest(path: Vec<u8>, hashes: Vec<NodeHash>, sender: SocketAddr, peer: &mut Peer) {
+    if peer.id_of(&sender).is_none() || path.len() >= DEPTH {
+        return;
+    }
+    let tree = shared_tree(sender, peer);
+    let own = tree.children(&path);
+    if hashes.len() != own.len() {
+        error!(
+            "Received a Merkle digest of {:?} with a different fanout",
+            sender
+        );
+        return;
+    }
+    for (i, (theirs, ours)) in hashes.iter().zip(own.iter()).enumerate() {
+        if theirs == ours {
+            continue;
+        }
+        let mut child = path.clone();
+        child.push(i as u8);
+        if child.len() == DEPTH {
+            let entries = tree.leaf(&child);
+            send_merkle_leaf(sender, peer.ip_address, child, entries);
+        } else {
+            let hashes = tree.children(&child);
+            send_merkle_digest(sender, peer.ip_address, child, hashes);
+        }
+    }
+}
+
+/// Compares the songs in the leaf at `path` of the peer at `sender` with the own ones. Songs only
+/// the sender holds are ordered from it, songs only the local peer holds are sent to it. A song
+/// both hold with different content is offered to the sender as well, which keeps the copy
+/// `offered_copy_wins` picks and sends its own back otherwise.
+pub fn merkle_leaf(
+    path: Vec<u8>,
+    entries: Vec<(String, String)>,
+    sender: SocketAddr,
+    peer: &mut Peer,
+) {
+    if peer.id_of(&sender).is_none() {
+        return;
+    }
+    let own = peer.ip_address;
+    let ours: HashMap<String, String> = shared_tree(sender, peer).leaf(&path).into_iter().collect();
+    let theirs: HashMap<String, String> = entries.into_iter().collect();
+    for (title, hash) in theirs.iter() {
+        match ours.get(title) {
+            Some(own_hash) if own_hash == hash => {
+                peer.add_holder(title, sender);
+                continue;
+            }
+            Some(_) => {
+                info!("{:?} holds another copy of {}", sender, title);
+                send_write_request(sender, own, title.clone(), peer);
+                continue;
+            }
+            None => {}
+        }
+        // the local peer holds it, but does not see itself responsible for it
+        if peer.does_file_exist(title) || reject_deleted_copy(title, None, sender, peer) {
+            continue;
+        }
+        info!("Ordering the missing copy of {} from {:?}", title, sender);
+        peer.add_holder(title, sender);
+        peer.open_request_table.order(title, sender);
+        send_file_request(sender, own, title, FileInstructions::ORDER, None);
+    }
+    for title in ours.keys() {
+        if !theirs.contains_key(title) {
+            info!("Sending the missing copy of {} to {:?}", title, sender);
+            send_write_request(sender, own, title.clone(), peer);
+            peer.add_holder(title, sender);
+        }
+    }
+}
+
+/// Decides which copy of `title` a peer keeps that is offered a copy with the content hash `hash`
+/// and the ownership record `ownership`. The copy with the newer ownership record wins, or the
+/// one with the larger content hash if the records were issued at the same time. Both peers come
+/// to the same result, so their copies converge.
+/// # Returns
+/// `true` if the offered copy replaces the local one or there is no local copy
+pub fn offered_copy_wins(
+    title: &str,
+    hash: &str,
+    ownership: Option<&Ownership>,
+    peer: &Peer,
+) -> bool {
+    let local_hash = match peer.get_db().hash_of(title) {
+        Some(h) => h,
+        None => return true,
+    };
+    if local_hash == hash {
+        return true;
+    }
+    let offered_issued = ownership
+        .filter(|o| o.title == title && o.hash == hash && o.verify().is_ok())
+        .map_or(0, |o| o.issued);
+    let local_issued = peer
+        .owners
+        .get(title)
+        .filter(|o| o.hash == local_hash)
+        .map_or(0, |o| o.issued);
+    (offered_issued, hash) > (local_issued, local_hash.as_str())
+}
+
+fn send_merkle_digest(target: SocketAddr, from: SocketAddr, path: Vec<u8>, hashes: Vec<NodeHash>) {
+    let not = Notification {
+        content: Content::MerkleDigest { path, hashes },
+        from,
+        signer: None,
+    };
+    tcp_request_with_notification(target, not);
+}
+
+fn send_merkle_leaf(
+    target: SocketAddr,
+    from: SocketAddr,
+    path: Vec<u8>,
+    entries: Vec<(String, String)>,
+) {
+    let not = Notification {
+        content: Content::MerkleLeaf { path, entries },
+        from,
+        signer: None,
+    };
+    tcp_request_with_notification(target, not);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries(count: usize) -> Vec<(String, String)> {
+        (0..count)
+            .map(|i| (format!("song{}", i), format!("hash{}", i)))
+            .collect()
+    }
+
+    /// The path of the leaf both trees differ in, found by comparing the hashes from the root down
+    fn differing_leaf(a: &MerkleTree, b: &MerkleTree) -> Option<Vec<u8>> {
+        let mut path = Vec::new();
+        while path.len() < DEPTH {
+            let differing: Vec<usize> = a
+                .children(&path)
+                .iter()
+                .zip(b.children(&path).iter())
+                .enumerate()
+                .filter(|(_, (x, y))| x != y)
+                .map(|(i, _)| i)
+                .collect();
+            match differing.as_slice() {
+                [] => return None,
+                [child] => path.push(*child as u8),
+                _ => panic!("more than one child of {:?} differs", path),
+            }
+        }
+        Some(path)
+    }
+
+    #[test]
+    fn leaf_of_a_song_is_found_by_its_path() {
+        for (title, _) in entries(100) {
+            let index = leaf_index(&title);
+            assert!(index < FANOUT.pow(DEPTH as u32));
+            let path = [(index / FANOUT) as u8, (index % FANOUT) as u8];
+            assert_eq!(first_leaf(&path), index);
+        }
+    }
+
+    #[test]
+    fn same_songs_give_the_same_tree() {
+        let mut reversed = entries(50);
+        reversed.reverse();
+        let a = MerkleTree::new(entries(50));
+        let b = MerkleTree::new(reversed);
+        assert_eq!(a.children(&[]), b.children(&[]));
+        assert_eq!(differing_leaf(&a, &b), None);
+    }
+
+    #[test]
+    fn differing_song_is_found_in_its_leaf() {
+        let mut changed = entries(50);
+        changed[7].1 = "other".to_string();
+        let a = MerkleTree::new(entries(50));
+        let b = MerkleTree::new(changed);
+        let path = differing_leaf(&a, &b).expect("the trees differ");
+        let song = |hash: &str| ("song7".to_string(), hash.to_string());
+        assert!(a.leaf(&path).contains(&song("hash7")));
+        assert!(b.leaf(&path).contains(&song("other")));
+    }
+
+    #[test]
+    fn missing_song_is_found_in_its_leaf() {
+        let a = MerkleTree::new(entries(50));
+        let b = MerkleTree::new(entries(49));
+        let path = differing_leaf(&a, &b).expect("the trees differ");
+        assert!(a.leaf(&path).iter().any(|(title, _)| title == "song49"));
+        assert!(b.leaf(&path).iter().all(|(title, _)| title != "song49"));
+    }
+
+    #[test]
+    fn inner_nodes_have_no_songs() {
+        let tree = MerkleTree::new(entries(50));
+        assert!(tree.leaf(&[]).is_empty());
+        assert!(tree.leaf(&[0]).is_empty());
+        assert_eq!(tree.children(&[]).len(), FANOUT);
+    }
+}
@@ -0,0 +1,431 @@
+use crate::interface::{Notification, Peer};
+use crate::metadata::SongMetadata;
+use crate::network::codec::fit_in_frame;
+use crate::network::identity::{sign, verify_signed_by, PeerId};
+use crate::network::notification::{tcp_request_with_notification, Content};
+use crate::network::ownership::unix_millis;
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// Time after which the listing of a peer that was not refreshed is dropped from the catalog
+const LISTING_TTL: Duration = Duration::from_secs(10 * 60);
+/// How far the version of a listing may be ahead of the local clock. Listings from further in the
+/// future are rejected, they would never expire.
+const MAX_CLOCK_SKEW: Duration = Duration::from_secs(60);
+
+/// A song as a peer lists it in the catalog
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SongInfo {
+    pub title: String,
+    /// The tags of the song, without the cover art
+    pub metadata: SongMetadata,
+}
+
+/// The songs one peer holds. Every peer publishes its own listing with a newer version, so the
+/// newest listing of a peer replaces the older ones wherever it is passed on.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Listing {
+    /// Milliseconds since the epoch when the listing was created
+    pub version: u64,
+    pub songs: Vec<SongInfo>,
+}
+
+/// A listing signed by the peer that created it. Other peers pass it on unchanged, so no peer can
+/// alter the listing of another one or publish a listing in its name.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SignedListing {
+    /// The peer that created the listing
+    pub origin: PeerId,
+    /// The address of `origin`, its songs are listed as held by this address
+    pub addr: SocketAddr,
+    pub listing: Listing,
+    signature: Vec<u8>,
+}
+
+impl SignedListing {
+    /// Signs `listing` of `origin` with the identity of the local peer at `addr`
+    pub fn create(
+        listing: Listing,
+        origin: PeerId,
+        addr: SocketAddr,
+    ) -> Result<SignedListing, String> {
+        let mut signed = SignedListing {
+            origin,
+            addr,
+            listing,
+            signature: Vec::new(),
+        };
+        let (_, signature) = sign(&addr, &signed.signed_bytes()?)?;
+        signed.signature = signature;
+        Ok(signed)
+    }
+
+    fn signed_bytes(&self) -> Result<Vec<u8>, String> {
+        let claims = (
+            &self.origin,
+            self.addr,
+            self.listing.version,
+            &self.listing.songs,
+        );
+        match bincode::serialize(&claims) {
+            Ok(b) => Ok(b),
+            Err(e) => Err(format!(
+                "Could not serialize the listing of {}: {:?}",
+                self.origin, e
+            )),
+        }
+    }
+
+    /// Checks that the listing was signed by the peer that created it
+    pub fn verify(&self) -> Result<(), String> {
+        verify_signed_by(&self.origin, &self.signed_bytes()?, &self.signature)
+    }
+}
+
+/// A song in the network-wide catalog
+#[derive(Clone, Debug, PartialEq)]
+pub struct CatalogEntry {
+    pub title: String,
+    /// The tags of the song, without the cover art
+    pub metadata: SongMetadata,
+    /// The peers that hold a copy of the song
+    pub holders: Vec<SocketAddr>,
+}
+
+/// How well a `CatalogEntry` matches a search query, better matches come first
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+enum Match {
+    Prefix,
+    Substring,
+    Fuzzy,
+}
+
+/// The listing of the songs the local peer holds
+fn own_listing(peer: &Peer) -> Listing {
+    let db = peer.get_db();
+    let songs = db
+        .keys()
+        .into_iter()
+        .map(|title| SongInfo {
+            metadata: db.metadata(&title).unwrap_or_default(),
+            title,
+        })
+        .collect();
+    Listing {
+        version: unix_millis(),
+        songs,
+    }
+}
+
+/// Checks if the listing was refreshed within `LISTING_TTL` and is not ahead of the local clock
+/// by more than `MAX_CLOCK_SKEW`
+fn is_fresh(listing: &Listing) -> bool {
+    let now = unix_millis();
+    listing.version <= now + MAX_CLOCK_SKEW.as_millis() as u64
+        && now.saturating_sub(listing.version) <= LISTING_TTL.as_millis() as u64
+}
+
+/// Checks if the listing of the peer at `addr` belongs to the catalog. In the full mesh only
+/// members of the network are listed, in DHT mode the peers that are not known yet as well.
+fn is_listed(addr: &SocketAddr, peer: &Peer) -> bool {
+    peer.routing.is_some() || peer.network_table.values().any(|v| v == addr)
+}
+
+/// All songs in the network that are not removed, sorted by title. The songs of the local peer
+/// are always up to date, the ones of other peers as of their last listing that reached it.
+pub fn catalog(peer: &Peer) -> Vec<CatalogEntry> {
+    let own = peer.ip_address;
+    let mut entries: BTreeMap<String, CatalogEntry> = BTreeMap::new();
+    let listings = peer
+        .listings
+        .iter()
+        .filter(|(addr, signed)| {
+            **addr != own && is_fresh(&signed.listing) && is_listed(addr, peer)
+        })
+        .map(|(addr, signed)| (*addr, signed.listing.clone()))
+        .chain(std::iter::once((own, own_listing(peer))));
+    for (addr, listing) in listings {
+        for song in listing.songs {
+            if peer.tombstones.contains_key(&song.title) {
+                continue;
+            }
+            let entry = entries
+                .entry(song.title.clone())
+                .or_insert_with(|| CatalogEntry {
+                    title: song.title.clone(),
+                    metadata: SongMetadata::default(),
+                    holders: Vec::new(),
+                });
+            // all copies of a song have the same tags, so the first listing that has any is taken
+            if entry.metadata == SongMetadata::default() {
+                entry.metadata = song.metadata;
+            }
+            entry.holders.push(addr);
+        }
+    }
+    entries.into_values().collect()
+}
+
+/// Searches the catalog for songs whose name, tagged title, artist or album starts with `query`,
+/// contains it or is spelled similar to it. Songs that start with the query come first, then songs that
+/// contain it and then similar ones. An empty query returns the whole catalog.
+pub fn search(peer: &Peer, query: &str) -> Vec<CatalogEntry> {
+    let query = query.trim().to_lowercase();
+    let mut found: Vec<(Match, CatalogEntry)> = catalog(peer)
+        .into_iter()
+        .filter_map(|entry| {
+            let tags = &entry.metadata;
+            let fields = [
+                Some(&entry.title),
+                tags.title.as_ref(),
+                tags.artist.as_ref(),
+                tags.album.as_ref(),
+            ];
+            fields
+                .iter()
+                .filter_map(|field| field.and_then(|f| match_field(&query, f)))
+                .min()
+                .map(|m| (m, entry))
+        })
+        .collect();
+    found.sort_by(|a, b| a.0.cmp(&b.0).then_with(|| a.1.title.cmp(&b.1.title)));
+    found.into_iter().map(|(_, entry)| entry).collect()
+}
+
+/// Matches the lower case `query` against `field`
+fn match_field(query: &str, field: &str) -> Option<Match> {
+    let field = field.to_lowercase();
+    if field.starts_with(query) {
+        return Some(Match::Prefix);
+    }
+    if field.contains(query) {
+        return Some(Match::Substring);
+    }
+    let length = query.chars().count();
+    // short queries would be similar to almost everything
+    if length < 3 {
+        return None;
+    }
+    let allowed = (length / 4).max(1);
+    let prefix: String = field.chars().take(length).collect();
+    let similar = edit_distance(query, &prefix) <= allowed
+        || field
+            .split_whitespace()
+            .any(|word| edit_distance(query, word) <= allowed);
+    if similar {
+        Some(Match::Fuzzy)
+    } else {
+        None
+    }
+}
+
+/// Number of inserted, removed or replaced characters that turn `a` into `b`
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let replace = previous[j] + if ca == *cb { 0 } else { 1 };
+            current[j + 1] = replace.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// Drops the listings that expired and passes the own listing and as many of the ones of the other
+/// peers as fit into a frame on to a random peer
+pub fn gossip_catalog(peer: &mut Peer) {
+    peer.listings.retain(|_, signed| is_fresh(&signed.listing));
+    let own = peer.ip_address;
+    let others: Vec<SocketAddr> = peer
+        .network_table
+        .values()
+        .filter(|addr| **addr != own)
+        .cloned()
+        .collect();
+    let target = match others.choose(&mut rand::thread_rng()) {
+        Some(t) => *t,
+        None => return,
+    };
+    let own_listing = match SignedListing::create(own_listing(peer), peer.id.clone(), own) {
+        Ok(l) => l,
+        Err(e) => {
+            error!("Could not sign the own listing: {}", e);
+            return;
+        }
+    };
+    let mut others: Vec<SignedListing> = peer
+        .listings
+        .values()
+        .filter(|signed| signed.addr != target)
+        .cloned()
+        .collect();
+    others.shuffle(&mut rand::thread_rng());
+    let listings = std::iter::once(own_listing).chain(others).collect();
+    send_catalog(target, own, fit_in_frame(listings));
+}
+
+/// Takes over the listings another peer passed on that are signed by the peers that created
+/// them and newer than the known ones
+pub fn catalog_received(listings: Vec<SignedListing>, sender: SocketAddr, peer: &mut Peer) {
+    if peer.id_of(&sender).is_none() {
+        return;
+    }
+    for signed in listings {
+        if let Err(e) = signed.verify() {
+            error!("Ignored a listing passed on by {:?}: {}", sender, e);
+            continue;
+        }
+        if peer
+            .id_of(&signed.addr)
+            .is_some_and(|id| id != signed.origin)
+        {
+            error!(
+                "Ignored the listing of {} for {:?}, which belongs to another peer",
+                signed.origin, signed.addr
+            );
+            continue;
+        }
+        store_listing(signed, peer);
+    }
+}
+
+/// Keeps `signed` if it is fresh and newer than the known listing of its peer
+fn store_listing(signed: SignedListing, peer: &mut Peer) {
+    let addr = signed.addr;
+    if addr == peer.ip_address || !is_fresh(&signed.listing) || !is_listed(&addr, peer) {
+        return;
+    }
+    let newer = peer
+        .listings
+        .get(&addr)
+        .is_none_or(|known| known.listing.version < signed.listing.version);
+    if newer {
+        peer.listings.insert(addr, signed);
+    }
+}
+
+fn send_catalog(target: SocketAddr, from: SocketAddr, listings: Vec<SignedListing>) {
+    let not = Notification {
+        content: Content::Catalog { listings },
+        from,
+        signer: None,
+    };
+    tcp_request_with_notification(target, not);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::Database;
+    use crate::testing::identity;
+    use crate::utils::Config;
+    use std::sync::mpsc::sync_channel;
+
+    fn listing(title: &str) -> Listing {
+        Listing {
+            version: unix_millis(),
+            songs: vec![SongInfo {
+                title: title.to_string(),
+                metadata: SongMetadata::default(),
+            }],
+        }
+    }
+
+    /// A peer that knows the peers `others` as members of its network
+    fn peer_with(others: &[(PeerId, SocketAddr)]) -> Peer {
+        let (own, addr) = identity(47100);
+        let (sender, _) = sync_channel(16);
+        let mut peer = Peer::create(
+            addr,
+            own,
+            "test",
+            sender,
+            Database::new(),
+            &Config::default(),
+        );
+        for (id, addr) in others {
+            peer.network_table.insert(id.clone(), *addr);
+        }
+        peer
+    }
+
+    #[test]
+    fn edit_distance_counts_single_edits() {
+        assert_eq!(edit_distance("", ""), 0);
+        assert_eq!(edit_distance("song", "song"), 0);
+        assert_eq!(edit_distance("song", "sing"), 1);
+        assert_eq!(edit_distance("song", "songs"), 1);
+        assert_eq!(edit_distance("song", "son"), 1);
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("über", "uber"), 1);
+    }
+
+    #[test]
+    fn matches_are_ranked() {
+        assert_eq!(match_field("yes", "Yesterday"), Some(Match::Prefix));
+        assert_eq!(match_field("day", "Yesterday"), Some(Match::Substring));
+        assert_eq!(match_field("yestreday", "Yesterday"), Some(Match::Fuzzy));
+        assert_eq!(match_field("xy", "Yesterday"), None);
+        assert_eq!(match_field("nothing", "Yesterday"), None);
+    }
+
+    #[test]
+    fn listings_from_the_future_expire() {
+        let mut fresh = listing("song");
+        assert!(is_fresh(&fresh));
+        fresh.version = unix_millis() + MAX_CLOCK_SKEW.as_millis() as u64 / 2;
+        assert!(is_fresh(&fresh));
+        fresh.version = unix_millis() + 2 * MAX_CLOCK_SKEW.as_millis() as u64;
+        assert!(!is_fresh(&fresh));
+        fresh.version = unix_millis() - 2 * LISTING_TTL.as_millis() as u64;
+        assert!(!is_fresh(&fresh));
+    }
+
+    #[test]
+    fn signed_listing_can_not_be_changed() {
+        let (id, addr) = identity(47101);
+        let signed = SignedListing::create(listing("song"), id, addr).unwrap();
+        assert!(signed.verify().is_ok());
+
+        let mut changed = signed.clone();
+        changed.listing.songs[0].title = "other".to_string();
+        assert!(changed.verify().is_err());
+        let mut moved = signed.clone();
+        moved.addr = SocketAddr::from(([127, 0, 0, 1], 1));
+        assert!(moved.verify().is_err());
+        let (other, _) = identity(47102);
+        let mut claimed = signed.clone();
+        claimed.origin = other;
+        assert!(claimed.verify().is_err());
+        let mut unsigned = signed;
+        unsigned.signature = Vec::new();
+        assert!(unsigned.verify().is_err());
+    }
+
+    #[test]
+    fn relayed_listings_are_checked() {
+        let sender = identity(47105);
+        let other = identity(47106);
+        let mut peer = peer_with(&[sender.clone(), other.clone()]);
+        let genuine = SignedListing::create(listing("song"), other.0.clone(), other.1).unwrap();
+        // the sender signs a listing in the name of the address of another peer
+        let forged = SignedListing::create(listing("fake"), sender.0.clone(), other.1).unwrap();
+        catalog_received(vec![forged], sender.1, &mut peer);
+        assert!(!peer.listings.contains_key(&other.1));
+        catalog_received(vec![genuine], sender.1, &mut peer);
+        assert_eq!(peer.listings[&other.1].origin, other.0);
+
+        let mut future = listing("future");
+        future.version += 2 * MAX_CLOCK_SKEW.as_millis() as u64;
+        let ahead = SignedListing::create(future, other.0.clone(), other.1).unwrap();
+        catalog_received(vec![ahead], sender.1, &mut peer);
+        assert_eq!(peer.listings[&other.1].listing.songs[0].title, "song");
+    }
+}
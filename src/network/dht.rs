@@ -0,0 +1,590 @@
+use crate::interface::{Notification, Peer};
+use crate::network::file_request::RequestId;
+use crate::network::identity::PeerId;
+use crate::network::invite::{check_membership, membership_proof};
+use crate::network::music_exchange::read_file_exist;
+use crate::network::notification::{tcp_request_with_notification, Content};
+use crate::network::request::{remove_from, replicate_to};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Maximum number of contacts in one bucket of the routing table
+const K: usize = 20;
+/// Number of peers that are queried at the same time during a lookup
+const ALPHA: usize = 3;
+/// Time after which a queried peer that did not answer is counted as failed
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+/// Number of lookups in a row a contact has to fail before it is dropped from the routing table
+const MAX_FAILURES: u32 = 3;
+
+/// Identifier of a peer or key in the XOR metric of the DHT
+pub type NodeId = [u8; 32];
+
+/// Returns the `NodeId` of the peer `id`
+pub fn node_id(id: &PeerId) -> NodeId {
+    key_id(id.as_str())
+}
+
+/// Returns the `NodeId` a key is stored under
+pub fn key_id(key: &str) -> NodeId {
+    let mut id = [0u8; 32];
+    id.copy_from_slice(&Sha256::digest(key.as_bytes()));
+    id
+}
+
+fn distance(a: &NodeId, b: &NodeId) -> NodeId {
+    let mut d = [0u8; 32];
+    for (byte, (x, y)) in d.iter_mut().zip(a.iter().zip(b.iter())) {
+        *byte = x ^ y;
+    }
+    d
+}
+
+/// A peer in the routing table
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Contact {
+    pub id: PeerId,
+    pub name: String,
+    pub addr: SocketAddr,
+}
+
+/// Kademlia routing table: one bucket of at most `K` contacts per bit of distance to the local
+/// peer. Contacts that are known longer are kept when a bucket is full, so the state of a peer
+/// stays bounded no matter how large the network gets. A contact that did not answer is only
+/// stale at first: it makes room for new contacts in a full bucket and is dropped after
+/// `MAX_FAILURES` failed lookups in a row.
+#[derive(Clone)]
+pub struct RoutingTable {
+    own: NodeId,
+    buckets: Vec<Vec<Contact>>,
+    failures: HashMap<SocketAddr, u32>,
+}
+
+impl RoutingTable {
+    pub fn new(own: NodeId) -> RoutingTable {
+        RoutingTable {
+            own,
+            buckets: vec![Vec::new(); 256],
+            failures: HashMap::new(),
+        }
+    }
+
+    fn bucket_index(&self, id: &NodeId) -> Option<usize> {
+        let d = distance(&self.own, id);
+        let mut zeros = 0;
+        for byte in d.iter() {
+            if *byte == 0 {
+                zeros += 8;
+            } else {
+                zeros += byte.leading_zeros() as usize;
+                break;
+            }
+        }
+        if zeros == 256 {
+            None
+        } else {
+            Some(255 - zeros)
+        }
+    }
+
+    /// Adds `contact` or marks it as recently seen. A full bucket makes room by dropping its
+    /// stale contact with the most failures.
+    /// # Returns
+    /// `false` if the bucket of the contact is full
+    pub fn update(&mut self, contact: Contact) -> bool {
+        let index = match self.bucket_index(&node_id(&contact.id)) {
+            Some(i) => i,
+            None => return false,
+        };
+        self.failures.remove(&contact.addr);
+        let bucket = &mut self.buckets[index];
+        if let Some(position) = bucket.iter().position(|c| c.id == contact.id) {
+            bucket.remove(position);
+            bucket.push(contact);
+            return true;
+        }
+        if bucket.len() >= K {
+            let failures = &self.failures;
+            let stale = bucket
+                .iter()
+                .enumerate()
+                .filter_map(|(i, c)| failures.get(&c.addr).map(|f| (*f, i)))
+                .max();
+            match stale {
+                Some((_, position)) => {
+                    let dropped = bucket.remove(position);
+                    self.failures.remove(&dropped.addr);
+                }
+                None => return false,
+            }
+        }
+        self.buckets[index].push(contact);
+        true
+    }
+
+    pub fn remove(&mut self, addr: &SocketAddr) {
+        for bucket in self.buckets.iter_mut() {
+            bucket.retain(|c| c.addr != *addr);
+        }
+        self.failures.remove(addr);
+    }
+
+    /// Counts a lookup the contact at `addr` did not answer in time
+    /// # Returns
+    /// `true` if the contact failed `MAX_FAILURES` times in a row and was dropped
+    pub fn mark_failed(&mut self, addr: &SocketAddr) -> bool {
+        if !self.contains(addr) {
+            return false;
+        }
+        let failures = self.failures.entry(*addr).or_insert(0);
+        *failures += 1;
+        if *failures < MAX_FAILURES {
+            return false;
+        }
+        self.remove(addr);
+        true
+    }
+
+    pub fn contains(&self, addr: &SocketAddr) -> bool {
+        self.buckets
+            .iter()
+            .any(|b| b.iter().any(|c| c.addr == *addr))
+    }
+
+    pub fn contacts(&self) -> Vec<Contact> {
+        self.buckets.iter().flatten().cloned().collect()
+    }
+
+    /// Returns the `count` known contacts closest to `target`
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<Contact> {
+        let mut contacts = self.contacts();
+        sort_by_distance(&mut contacts, target);
+        contacts.truncate(count);
+        contacts
+    }
+}
+
+fn sort_by_distance(contacts: &mut [Contact], target: &NodeId) {
+    contacts
+        .sort_by(|a, b| distance(&node_id(&a.id), target).cmp(&distance(&node_id(&b.id), target)));
+}
+
+/// What happens once a lookup found the peers closest to its target
+#[derive(Clone, Debug)]
+pub enum LookupPurpose {
+    /// Fills the routing table after joining the network
+    Bootstrap,
+    /// Asks the closest peers for the song, `id` is the id of the open request
+    FindFile { song_name: String, id: RequestId },
+    /// Sends copies of the local song to the closest peers
+    Replicate { key: String },
+    /// Sends the tombstone of the removed song to the closest peers
+    Remove { song_name: String },
+}
+
+/// State of an iterative lookup
+#[derive(Clone)]
+pub struct Lookup {
+    target: NodeId,
+    purpose: LookupPurpose,
+    /// The closest contacts found so far, sorted by distance to `target`
+    shortlist: Vec<Contact>,
+    queried: HashSet<SocketAddr>,
+    in_flight: HashMap<SocketAddr, Instant>,
+}
+
+/// Starts an iterative lookup of the peers closest to `target`
+pub fn start_lookup(peer: &mut Peer, target: NodeId, purpose: LookupPurpose) {
+    let shortlist = match &peer.routing {
+        Some(table) => table.closest(&target, K),
+        None => return,
+    };
+    let id = rand::random::<u64>();
+    peer.lookups.insert(
+        id,
+        Lookup {
+            target,
+            purpose,
+            shortlist,
+            queried: HashSet::new(),
+            in_flight: HashMap::new(),
+        },
+    );
+    query_next(id, peer);
+}
+
+/// Queries the closest contacts of the lookup `id` that were not asked yet, at most `ALPHA` at
+/// a time. The lookup finishes once the `K` closest contacts answered.
+fn query_next(id: u64, peer: &mut Peer) {
+    let own = peer.ip_address;
+    let name = peer.name.clone();
+    let proof = match &peer.network_secret {
+        Some(secret) => membership_proof(secret, peer.id.as_str()),
+        None => String::new(),
+    };
+    let lookup = match peer.lookups.get_mut(&id) {
+        Some(l) => l,
+        None => return,
+    };
+    let candidates: Vec<SocketAddr> = lookup
+        .shortlist
+        .iter()
+        .take(K)
+        .filter(|c| !lookup.queried.contains(&c.addr))
+        .map(|c| c.addr)
+        .collect();
+    for addr in candidates {
+        if lookup.in_flight.len() >= ALPHA {
+            break;
+        }
+        lookup.queried.insert(addr);
+        lookup.in_flight.insert(addr, Instant::now());
+        let not = Notification {
+            content: Content::FindNode {
+                target: lookup.target,
+                lookup: id,
+                name: name.clone(),
+                proof: proof.clone(),
+            },
+            from: own,
+            signer: None,
+        };
+        tcp_request_with_notification(addr, not);
+    }
+    if lookup.in_flight.is_empty() {
+        finish_lookup(id, peer);
+    }
+}
+
+fn finish_lookup(id: u64, peer: &mut Peer) {
+    let lookup = match peer.lookups.remove(&id) {
+        Some(l) => l,
+        None => return,
+    };
+    let own = peer.ip_address;
+    let mut closest = lookup.shortlist;
+    closest.push(Contact {
+        id: peer.id.clone(),
+        name: peer.name.clone(),
+        addr: own,
+    });
+    sort_by_distance(&mut closest, &lookup.target);
+    let closest: Vec<SocketAddr> = closest
+        .iter()
+        .take(peer.replication_factor)
+        .map(|c| c.addr)
+        .filter(|addr| *addr != own)
+        .collect();
+    match lookup.purpose {
+        LookupPurpose::Bootstrap => {
+            info!(
+                "Bootstrap lookup finished, {} contacts known",
+                peer.network_table.len()
+            );
+        }
+        LookupPurpose::FindFile {
+            song_name,
+            id: request,
+        } => {
+            let mut targets = closest;
+            if let Some(holders) = peer.holders.get(&song_name) {
+                for holder in holders {
+                    if *holder != own && !targets.contains(holder) {
+                        targets.push(*holder);
+                    }
+                }
+            }
+            peer.open_request_table.ask(request, &targets);
+            for target in targets {
+                read_file_exist(target, own, &song_name, request);
+            }
+        }
+        LookupPurpose::Replicate { key } => {
+            replicate_to(&key, closest, peer);
+        }
+        LookupPurpose::Remove { song_name } => {
+            remove_from(&song_name, closest, peer);
+        }
+    }
+}
+
+/// Handles a `FindNode` request of the peer `id`: answers with the contacts closest to `target`.
+/// The peer proves with `proof` that it is a member of the network.
+pub fn find_node(
+    target: NodeId,
+    lookup: u64,
+    name: String,
+    proof: String,
+    id: PeerId,
+    sender: SocketAddr,
+    peer: &mut Peer,
+) {
+    let is_member = match &peer.network_secret {
+        Some(secret) => check_membership(secret, id.as_str(), &proof),
+        None => false,
+    };
+    if !is_member {
+        error!(
+            "Ignored the lookup of {}, which is not a member of the network",
+            id
+        );
+        return;
+    }
+    peer.members.insert(id.clone());
+    let contacts = match &mut peer.routing {
+        Some(table) => {
+            table.update(Contact {
+                id,
+                name,
+                addr: sender,
+            });
+            table
+                .closest(&target, K + 1)
+                .into_iter()
+                .filter(|c| c.addr != sender)
+                .take(K)
+                .collect()
+        }
+        None => return,
+    };
+    sync_network_table(peer);
+    let not = Notification {
+        content: Content::Nodes {
+            lookup,
+            name: peer.name.clone(),
+            contacts,
+        },
+        from: peer.ip_address,
+        signer: None,
+    };
+    tcp_request_with_notification(sender, not);
+}
+
+/// Handles the answer of the peer `id` queried by the lookup `lookup`. The contacts a member
+/// answers with are members as well.
+pub fn nodes_received(
+    lookup: u64,
+    name: String,
+    id: PeerId,
+    contacts: Vec<Contact>,
+    sender: SocketAddr,
+    peer: &mut Peer,
+) {
+    let asked = peer
+        .lookups
+        .get(&lookup)
+        .is_some_and(|state| state.in_flight.contains_key(&sender));
+    if !asked {
+        error!("Ignored the contacts of {:?}, which was not asked", sender);
+        return;
+    }
+    for contact in &contacts {
+        peer.members.insert(contact.id.clone());
+    }
+    let own = peer.ip_address;
+    if let Some(table) = &mut peer.routing {
+        table.update(Contact {
+            id,
+            name,
+            addr: sender,
+        });
+        for contact in &contacts {
+            if contact.addr != own {
+                table.update(contact.clone());
+            }
+        }
+    }
+    sync_network_table(peer);
+    if let Some(state) = peer.lookups.get_mut(&lookup) {
+        state.in_flight.remove(&sender);
+        for contact in contacts {
+            if contact.addr != own && !state.shortlist.iter().any(|c| c.addr == contact.addr) {
+                state.shortlist.push(contact);
+            }
+        }
+        let target = state.target;
+        sort_by_distance(&mut state.shortlist, &target);
+        query_next(lookup, peer);
+    }
+}
+
+/// Takes peers that did not answer a lookup in time out of the lookup and continues it. Their
+/// contacts are marked as stale and dropped once they failed `MAX_FAILURES` lookups in a row.
+pub fn expire_lookups(peer: &mut Peer) {
+    let mut expired = Vec::new();
+    for (id, lookup) in peer.lookups.iter_mut() {
+        let late: Vec<SocketAddr> = lookup
+            .in_flight
+            .iter()
+            .filter(|(_, sent)| sent.elapsed() > LOOKUP_TIMEOUT)
+            .map(|(addr, _)| *addr)
+            .collect();
+        for addr in &late {
+            lookup.in_flight.remove(addr);
+            lookup.shortlist.retain(|c| c.addr != *addr);
+        }
+        if !late.is_empty() {
+            expired.push((*id, late));
+        }
+    }
+    for (id, late) in expired {
+        let mut dropped = Vec::new();
+        if let Some(table) = &mut peer.routing {
+            for addr in late {
+                if table.mark_failed(&addr) {
+                    info!("Dropped {:?} after {} failed lookups", addr, MAX_FAILURES);
+                    dropped.push(addr);
+                }
+            }
+        }
+        if !dropped.is_empty() {
+            peer.network_table.retain(|_, addr| !dropped.contains(addr));
+            sync_network_table(peer);
+        }
+        query_next(id, peer);
+    }
+}
+
+/// In DHT mode the network table only holds the contacts of the routing table. Peers that were
+/// added to the network table, e.g. by the join handshake, are offered to the routing table and
+/// dropped again if their bucket is full.
+pub fn sync_network_table(peer: &mut Peer) {
+    let table = match &mut peer.routing {
+        Some(t) => t,
+        None => return,
+    };
+    for (id, addr) in peer.network_table.iter() {
+        if *addr != peer.ip_address && !table.contains(addr) {
+            table.update(Contact {
+                id: id.clone(),
+                name: peer.peer_names.get(id).cloned().unwrap_or_default(),
+                addr: *addr,
+            });
+        }
+    }
+    let mut network_table = HashMap::new();
+    let mut peer_names = HashMap::new();
+    network_table.insert(peer.id.clone(), peer.ip_address);
+    peer_names.insert(peer.id.clone(), peer.name.clone());
+    for contact in table.contacts() {
+        network_table.insert(contact.id.clone(), contact.addr);
+        peer_names.insert(contact.id, contact.name);
+    }
+    peer.network_table = network_table;
+    peer.peer_names = peer_names;
+}
+
+/// Removes the peer at `addr` from the routing table
+pub fn forget_contact(addr: &SocketAddr, peer: &mut Peer) {
+    if let Some(table) = &mut peer.routing {
+        table.remove(addr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+
+    fn contact(n: u16) -> Contact {
+        Contact {
+            id: PeerId::try_from(format!("{:064x}", n)).unwrap(),
+            name: n.to_string(),
+            addr: SocketAddr::from(([127, 0, 0, 1], n)),
+        }
+    }
+
+    /// Returns `count` contacts that all fall into the bucket `index` of `table`
+    fn contacts_in_bucket(table: &RoutingTable, index: usize, count: usize) -> Vec<Contact> {
+        (1..u16::MAX)
+            .map(contact)
+            .filter(|c| table.bucket_index(&node_id(&c.id)) == Some(index))
+            .take(count)
+            .collect()
+    }
+
+    #[test]
+    fn distance_is_xor() {
+        let a = key_id("a");
+        let b = key_id("b");
+        assert_eq!(distance(&a, &a), [0u8; 32]);
+        assert_eq!(distance(&a, &b), distance(&b, &a));
+        assert_eq!(distance(&distance(&a, &b), &b), a);
+    }
+
+    #[test]
+    fn bucket_index_counts_the_shared_prefix() {
+        let table = RoutingTable::new([0u8; 32]);
+        assert_eq!(table.bucket_index(&[0u8; 32]), None);
+        let mut id = [0u8; 32];
+        id[0] = 0x80;
+        assert_eq!(table.bucket_index(&id), Some(255));
+        id[0] = 0x01;
+        assert_eq!(table.bucket_index(&id), Some(248));
+        let mut id = [0u8; 32];
+        id[31] = 0x01;
+        assert_eq!(table.bucket_index(&id), Some(0));
+    }
+
+    #[test]
+    fn closest_contacts_come_first() {
+        let mut table = RoutingTable::new(key_id("own"));
+        for n in 1..50 {
+            table.update(contact(n));
+        }
+        let target = node_id(&contact(7).id);
+        let closest = table.closest(&target, 5);
+        assert_eq!(closest.len(), 5);
+        assert_eq!(closest[0], contact(7));
+        for pair in closest.windows(2) {
+            let first = distance(&node_id(&pair[0].id), &target);
+            let second = distance(&node_id(&pair[1].id), &target);
+            assert!(first <= second);
+        }
+    }
+
+    #[test]
+    fn full_bucket_keeps_known_contacts() {
+        let mut table = RoutingTable::new(key_id("own"));
+        let contacts = contacts_in_bucket(&table, 255, K + 1);
+        for c in &contacts[..K] {
+            assert!(table.update(c.clone()));
+        }
+        assert!(!table.update(contacts[K].clone()));
+        assert!(!table.contains(&contacts[K].addr));
+        assert_eq!(table.contacts().len(), K);
+    }
+
+    #[test]
+    fn contact_is_dropped_after_repeated_failures() {
+        let mut table = RoutingTable::new(key_id("own"));
+        let c = contact(1);
+        table.update(c.clone());
+        for _ in 1..MAX_FAILURES {
+            assert!(!table.mark_failed(&c.addr));
+        }
+        assert!(table.contains(&c.addr));
+        table.update(c.clone());
+        for _ in 1..MAX_FAILURES {
+            assert!(!table.mark_failed(&c.addr));
+        }
+        assert!(table.mark_failed(&c.addr));
+        assert!(!table.contains(&c.addr));
+    }
+
+    #[test]
+    fn stale_contact_makes_room_in_a_full_bucket() {
+        let mut table = RoutingTable::new(key_id("own"));
+        let contacts = contacts_in_bucket(&table, 255, K + 1);
+        for c in &contacts[..K] {
+            table.update(c.clone());
+        }
+        table.mark_failed(&contacts[3].addr);
+        assert!(table.update(contacts[K].clone()));
+        assert!(!table.contains(&contacts[3].addr));
+        assert!(table.contains(&contacts[K].addr));
+    }
+}
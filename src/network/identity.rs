@@ -0,0 +1,266 @@
+use ed25519_dalek::{Keypair, PublicKey, Signature, Signer, Verifier};
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// File in the data directory that holds the keypair of the local peer
+const IDENTITY_FILE: &str = "identity.key";
+
+lazy_static! {
+    /// The keypairs of the local peers by their address. The codec signs every frame with the
+    /// keypair of the address in `Notification::from`.
+    static ref SIGNERS: Mutex<HashMap<SocketAddr, Arc<Keypair>>> = Mutex::new(HashMap::new());
+}
+
+/// Stable identifier of a peer, the hex encoded public key of its keypair. Unlike names and
+/// addresses it can not be taken over by another peer, because every notification is signed
+/// with the secret key that belongs to it. IDs received from other peers are checked to be hex
+/// encoded public keys.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[serde(try_from = "String")]
+pub struct PeerId(String);
+
+/// Length of an ID, two hex characters for every byte of a public key
+const PEER_ID_LENGTH: usize = 64;
+
+impl PeerId {
+    fn from_public_key(key: &PublicKey) -> PeerId {
+        let hex: Vec<String> = key
+            .as_bytes()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        PeerId(hex.concat())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// The public key the ID was made of
+    fn public_key(&self) -> Option<Vec<u8>> {
+        if !self.0.len().is_multiple_of(2) {
+            return None;
+        }
+        (0..self.0.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(self.0.get(i..i + 2)?, 16).ok())
+            .collect()
+    }
+
+    /// The first eight characters of the ID, enough to tell peers apart in a UI
+    pub fn short(&self) -> &str {
+        match self.0.char_indices().nth(8) {
+            Some((end, _)) => &self.0[..end],
+            None => &self.0,
+        }
+    }
+}
+
+impl TryFrom<String> for PeerId {
+    type Error = String;
+
+    fn try_from(id: String) -> Result<PeerId, String> {
+        let is_hex = id
+            .chars()
+            .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c));
+        if id.len() != PEER_ID_LENGTH || !is_hex {
+            return Err(format!("{:?} is not a valid peer ID", id));
+        }
+        Ok(PeerId(id))
+    }
+}
+
+impl fmt::Display for PeerId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// The keypair of the local peer
+#[derive(Clone)]
+pub struct Identity {
+    keypair: Arc<Keypair>,
+    id: PeerId,
+}
+
+impl Identity {
+    /// Creates a new keypair that is not saved anywhere
+    pub fn generate() -> Identity {
+        let keypair = Keypair::generate(&mut OsRng);
+        let id = PeerId::from_public_key(&keypair.public);
+        Identity {
+            keypair: Arc::new(keypair),
+            id,
+        }
+    }
+
+    /// Loads the keypair of the local peer from `data_dir`. A new keypair is created and saved
+    /// there on the first start.
+    pub fn load_or_create(data_dir: &Path) -> Result<Identity, String> {
+        let path = data_dir.join(IDENTITY_FILE);
+        let keypair = if path.exists() {
+            let bytes = match fs::read(&path) {
+                Ok(b) => b,
+                Err(e) => return Err(format!("Could not read {:?}: {:?}", path, e)),
+            };
+            match Keypair::from_bytes(&bytes) {
+                Ok(k) => k,
+                Err(e) => return Err(format!("{:?} is not a valid keypair: {:?}", path, e)),
+            }
+        } else {
+            let keypair = Keypair::generate(&mut OsRng);
+            if let Err(e) = fs::create_dir_all(data_dir) {
+                return Err(format!("Could not create {:?}: {:?}", data_dir, e));
+            }
+            write_private(&path, &keypair.to_bytes()[..])?;
+            keypair
+        };
+        let id = PeerId::from_public_key(&keypair.public);
+        Ok(Identity {
+            keypair: Arc::new(keypair),
+            id,
+        })
+    }
+
+    pub fn id(&self) -> &PeerId {
+        &self.id
+    }
+
+    /// Signs all notifications that are sent from `addr` with this identity from now on
+    pub fn register(&self, addr: SocketAddr) {
+        let mut signers = match SIGNERS.lock() {
+            Ok(s) => s,
+            Err(e) => e.into_inner(),
+        };
+        signers.insert(addr, self.keypair.clone());
+    }
+}
+
+/// Writes the secret `bytes` to the file at `path`. The file is created so that only the owner
+/// may read it, the secret is never readable by others in between.
+pub(crate) fn write_private(path: &Path, bytes: &[u8]) -> Result<(), String> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let written = options
+        .open(path)
+        .and_then(|mut file| file.write_all(bytes));
+    match written {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Could not write {:?}: {:?}", path, e)),
+    }
+}
+
+/// Signs `payload` with the keypair registered for `addr`
+/// # Returns
+/// The public key and the signature
+pub fn sign(addr: &SocketAddr, payload: &[u8]) -> Result<(Vec<u8>, Vec<u8>), String> {
+    let signers = match SIGNERS.lock() {
+        Ok(s) => s,
+        Err(e) => e.into_inner(),
+    };
+    match signers.get(addr) {
+        Some(keypair) => Ok((
+            keypair.public.as_bytes().to_vec(),
+            keypair.sign(payload).to_bytes().to_vec(),
+        )),
+        None => Err(format!("There is no identity registered for {}", addr)),
+    }
+}
+
+/// Checks that `signature` was made for `payload` by the secret key of `public_key`
+/// # Returns
+/// The `PeerId` of the signer
+pub fn verify(public_key: &[u8], payload: &[u8], signature: &[u8]) -> Result<PeerId, String> {
+    let key = match PublicKey::from_bytes(public_key) {
+        Ok(k) => k,
+        Err(e) => return Err(format!("Invalid public key: {:?}", e)),
+    };
+    let signature = match Signature::try_from(signature) {
+        Ok(s) => s,
+        Err(e) => return Err(format!("Invalid signature: {:?}", e)),
+    };
+    match key.verify(payload, &signature) {
+        Ok(_) => Ok(PeerId::from_public_key(&key)),
+        Err(_e) => Err(format!(
+            "Signature does not match the key of {}",
+            PeerId::from_public_key(&key)
+        )),
+    }
+}
+
+/// Checks that `signature` was made for `payload` by the peer `id`
+pub fn verify_signed_by(id: &PeerId, payload: &[u8], signature: &[u8]) -> Result<(), String> {
+    let public_key = match id.public_key() {
+        Some(k) => k,
+        None => return Err(format!("{} is not a valid peer ID", id)),
+    };
+    verify(&public_key, payload, signature).map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn ids_are_checked_when_they_are_read() {
+        let dir = TempDir::new("identity");
+        let id = Identity::load_or_create(dir.path()).unwrap().id().clone();
+        let json = serde_json::to_string(&id).unwrap();
+        assert_eq!(serde_json::from_str::<PeerId>(&json).unwrap(), id);
+        assert_eq!(id.short().len(), 8);
+        assert!(serde_json::from_str::<PeerId>("\"peer\"").is_err());
+        let wide = format!("\"{}\"", "é".repeat(32));
+        assert!(serde_json::from_str::<PeerId>(&wide).is_err());
+    }
+
+    #[test]
+    fn short_id_does_not_split_characters() {
+        let id = PeerId("éééééééééé".to_string());
+        assert_eq!(id.short(), "éééééééé");
+        assert_eq!(PeerId("abc".to_string()).short(), "abc");
+    }
+
+    #[test]
+    fn signatures_are_bound_to_the_key() {
+        let dir = TempDir::new("identity");
+        let identity = Identity::load_or_create(dir.path()).unwrap();
+        let addr: SocketAddr = "127.0.0.1:47300".parse().unwrap();
+        identity.register(addr);
+        let (public_key, signature) = sign(&addr, b"payload").unwrap();
+        assert_eq!(
+            &verify(&public_key, b"payload", &signature).unwrap(),
+            identity.id()
+        );
+        assert!(verify(&public_key, b"other", &signature).is_err());
+        assert!(verify_signed_by(identity.id(), b"payload", &signature).is_ok());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn keypair_is_only_readable_by_the_owner() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = TempDir::new("identity");
+        let identity = Identity::load_or_create(dir.path()).unwrap();
+        let mode = fs::metadata(dir.path().join(IDENTITY_FILE))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let loaded = Identity::load_or_create(dir.path()).unwrap();
+        assert_eq!(loaded.id(), identity.id());
+    }
+}
@@ -0,0 +1,188 @@
+use crate::network::identity::write_private;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// File in the data directory that holds the secret of the network the peer belongs to
+const SECRET_FILE: &str = "network.key";
+const SECRET_SIZE: usize = 32;
+/// Expiry and nonce of a token, both as big endian `u64`
+const CLAIMS_SIZE: usize = 16;
+const MAC_SIZE: usize = 32;
+/// Validity of the token a former member creates for itself when it joins again
+pub const REJOIN_VALIDITY: Duration = Duration::from_secs(60);
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Creates the secret of a new network
+pub fn create_secret() -> Vec<u8> {
+    (0..SECRET_SIZE).map(|_| rand::random::<u8>()).collect()
+}
+
+/// Loads the network secret from `data_dir`. `None` if the peer never was a member of a network.
+pub fn load_secret(data_dir: &Path) -> Option<Vec<u8>> {
+    match fs::read(data_dir.join(SECRET_FILE)) {
+        Ok(secret) if secret.len() == SECRET_SIZE => Some(secret),
+        Ok(_) => {
+            error!("Ignoring the invalid network secret in {:?}", data_dir);
+            None
+        }
+        Err(_e) => None,
+    }
+}
+
+pub fn save_secret(data_dir: &Path, secret: &[u8]) {
+    if let Err(e) = fs::create_dir_all(data_dir) {
+        error!("Could not create {:?}: {:?}", data_dir, e);
+        return;
+    }
+    if let Err(e) = write_private(&data_dir.join(SECRET_FILE), secret) {
+        error!("Could not save the network secret: {}", e);
+    }
+}
+
+fn mac(secret: &[u8], data: &[u8]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_varkey(secret).expect("HMAC accepts keys of any length");
+    mac.input(data);
+    mac
+}
+
+fn unix_time() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs(),
+        Err(_e) => 0,
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    let hex: Vec<String> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    hex.concat()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Creates an invite token for the network of `secret` that expires after `valid_for`. Until
+/// then every peer of the network accepts the token once, see `redeem_invite`.
+pub fn mint_invite(secret: &[u8], valid_for: Duration) -> String {
+    let mut claims = Vec::with_capacity(CLAIMS_SIZE + MAC_SIZE);
+    claims.extend_from_slice(&(unix_time() + valid_for.as_secs()).to_be_bytes());
+    claims.extend_from_slice(&rand::random::<u64>().to_be_bytes());
+    let code = mac(secret, &claims).result().code();
+    claims.extend_from_slice(&code);
+    to_hex(&claims)
+}
+
+/// Checks that `token` was created with `secret` and did not expire yet and records its nonce in
+/// `redeemed`, so the local peer accepts the token only once. The nonces of expired tokens are
+/// dropped from `redeemed`. Peers do not share the nonces, a token that was used with one peer
+/// can still be used with another one until it expires.
+/// # Returns
+/// The reason for the rejection on `Err`
+pub fn redeem_invite(
+    secret: &[u8],
+    token: &str,
+    redeemed: &mut HashMap<u64, u64>,
+) -> Result<(), String> {
+    let (expiry, nonce) = check_invite(secret, token)?;
+    let now = unix_time();
+    redeemed.retain(|_, expires| *expires >= now);
+    if redeemed.insert(nonce, expiry).is_some() {
+        return Err("The invite token was already used".to_string());
+    }
+    Ok(())
+}
+
+/// Checks that `token` was created with `secret` and did not expire yet.
+/// # Returns
+/// The expiry and the nonce of the token, the reason for the rejection on `Err`
+fn check_invite(secret: &[u8], token: &str) -> Result<(u64, u64), String> {
+    let bytes = match from_hex(token.trim()) {
+        Some(b) if b.len() == CLAIMS_SIZE + MAC_SIZE => b,
+        _ => return Err("The invite token is malformed".to_string()),
+    };
+    let (claims, code) = bytes.split_at(CLAIMS_SIZE);
+    if mac(secret, claims).verify(code).is_err() {
+        return Err("The invite token is not valid for this network".to_string());
+    }
+    let mut expiry = [0u8; 8];
+    expiry.copy_from_slice(&claims[..8]);
+    let expiry = u64::from_be_bytes(expiry);
+    if expiry < unix_time() {
+        return Err("The invite token expired".to_string());
+    }
+    let mut nonce = [0u8; 8];
+    nonce.copy_from_slice(&claims[8..]);
+    Ok((expiry, u64::from_be_bytes(nonce)))
+}
+
+/// Proof that the peer `id` knows the secret of the network. Members send it when they
+/// announce themselves to the other peers.
+pub fn membership_proof(secret: &[u8], id: &str) -> String {
+    to_hex(&mac(secret, id.as_bytes()).result().code())
+}
+
+pub fn check_membership(secret: &[u8], id: &str, proof: &str) -> bool {
+    match from_hex(proof) {
+        Some(code) => mac(secret, id.as_bytes()).verify(&code).is_ok(),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn invite_is_accepted_once() {
+        let secret = create_secret();
+        let token = mint_invite(&secret, Duration::from_secs(60));
+        let mut redeemed = HashMap::new();
+        assert!(redeem_invite(&secret, &token, &mut redeemed).is_ok());
+        assert!(redeem_invite(&secret, &token, &mut redeemed).is_err());
+        let other = mint_invite(&secret, Duration::from_secs(60));
+        assert!(redeem_invite(&secret, &other, &mut redeemed).is_ok());
+    }
+
+    #[test]
+    fn invite_of_another_network_is_rejected() {
+        let token = mint_invite(&create_secret(), Duration::from_secs(60));
+        assert!(redeem_invite(&create_secret(), &token, &mut HashMap::new()).is_err());
+        assert!(redeem_invite(&create_secret(), "zz", &mut HashMap::new()).is_err());
+    }
+
+    #[test]
+    fn membership_proof_is_bound_to_the_id() {
+        let secret = create_secret();
+        let proof = membership_proof(&secret, "a");
+        assert!(check_membership(&secret, "a", &proof));
+        assert!(!check_membership(&secret, "b", &proof));
+        assert!(!check_membership(&create_secret(), "a", &proof));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn secret_is_only_readable_by_the_owner() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = TempDir::new("invite");
+        let secret = create_secret();
+        save_secret(dir.path(), &secret);
+        let mode = fs::metadata(dir.path().join(SECRET_FILE))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(load_secret(dir.path()), Some(secret));
+    }
+}
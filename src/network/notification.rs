@@ -1,11 +1,19 @@
 use crate::interface::MusicState;
 use crate::interface::*;
+use crate::network::anti_entropy::NodeHash;
+use crate::network::catalog::SignedListing;
+use crate::network::dht::{Contact, NodeId};
+use crate::network::file_request::RequestId;
+use crate::network::handshake::Hello;
+use crate::network::ownership::Ownership;
 use crate::network::response::Message;
+use crate::network::secure::send_notification;
+use crate::network::tombstone::Tombstone;
+use crate::network::transfer::{Manifest, TransferKind};
 use crate::utils::FileInstructions;
 use serde::{Deserialize, Serialize};
 use std::net::{SocketAddr, TcpStream};
-use std::process;
-use std::time::{Duration, SystemTime};
+use std::time::Duration;
 
 /// The content enum for `Message`s.
 #[derive(Clone, Serialize, Deserialize, Debug)]
@@ -15,26 +23,22 @@ pub enum Content {
         value: Vec<u8>,
         from: String,
     },
-    RedundantPushToDB {
-        key: String,
-        value: Vec<u8>,
-        from: String,
-    },
     Response {
         from: SocketAddr,
         message: Message,
     },
-    ChangePeerName {
-        value: String,
-    },
     SendNetworkTable {
         value: Vec<u8>,
+        secret: Vec<u8>,
     },
     SendNetworkUpdateTable {
         value: Vec<u8>,
+        proof: String,
     },
     RequestForTable {
         value: String,
+        hello: Hello,
+        invite: Option<String>,
     },
     FindFile {
         instr: FileInstructions,
@@ -43,30 +47,26 @@ pub enum Content {
     GetFile {
         instr: FileInstructions,
         key: String,
-    },
-    GetFileResponse {
-        instr: FileInstructions,
-        key: String,
-        value: Vec<u8>,
+        id: Option<RequestId>,
     },
     ExistFile {
         song_name: String,
-        id: SystemTime,
+        id: RequestId,
     },
     ExitPeer {
         addr: SocketAddr,
     },
     DeleteFromNetwork {
-        name: String,
+        id: PeerId,
     },
     ExistFileResponse {
         song_name: String,
-        id: SystemTime,
+        id: RequestId,
     },
     StatusRequest {},
     SelfStatusRequest,
     StatusResponse {
-        files: Vec<String>,
+        files: Vec<(String, SongMetadata)>,
         name: String,
     },
     PlayAudioRequest {
@@ -81,40 +81,154 @@ pub enum Content {
         song_name: String,
     },
     DeleteFileRequest {
+        tombstone: Tombstone,
+    },
+    JoinRejected {
+        reason: String,
+        hello: Hello,
+    },
+    FileOffer {
+        key: String,
+        kind: TransferKind,
+        manifest: Manifest,
+        ownership: Option<Ownership>,
+    },
+    GetChunk {
+        key: String,
+        index: u64,
+    },
+    Chunk {
+        key: String,
+        index: u64,
+        data: Vec<u8>,
+    },
+    Maintenance,
+    StreamBuffering {
+        title: String,
+        buffering: bool,
+    },
+    CorruptFile {
+        key: String,
+    },
+    HoldersUpdate {
+        key: String,
+        holders: Vec<SocketAddr>,
+    },
+    FindNode {
+        target: NodeId,
+        lookup: u64,
+        name: String,
+        proof: String,
+    },
+    Nodes {
+        lookup: u64,
+        name: String,
+        contacts: Vec<Contact>,
+    },
+    DeleteRejected {
+        song_name: String,
+        reason: String,
+    },
+    DelegateRemoval {
+        song_name: String,
+        delegate: String,
+    },
+    OwnershipUpdate {
+        ownership: Ownership,
+    },
+    Tombstones {
+        tombstones: Vec<Tombstone>,
+    },
+    TombstoneAck {
+        title: String,
+        deleted: u64,
+    },
+    GossipTombstones,
+    AntiEntropy,
+    MerkleDigest {
+        path: Vec<u8>,
+        hashes: Vec<NodeHash>,
+    },
+    MerkleLeaf {
+        path: Vec<u8>,
+        entries: Vec<(String, String)>,
+    },
+    ReplicaStored {
+        key: String,
+        hash: String,
+    },
+    Leaving {
+        cancelled: bool,
+    },
+    FileNotFound {
         song_name: String,
+        id: RequestId,
+    },
+    Catalog {
+        listings: Vec<SignedListing>,
+    },
+    GossipCatalog,
+    PeerUnreachable {
+        addr: SocketAddr,
     },
 }
 
+impl Content {
+    /// Checks if `self` is only sent by the local peer to itself, through the channel of the
+    /// peer or by one of its timers. Such a notification received from another peer is dropped.
+    pub fn is_internal(&self) -> bool {
+        matches!(
+            self,
+            Content::PushToDB { .. }
+                | Content::FindFile { .. }
+                | Content::ExitPeer { .. }
+                | Content::SelfStatusRequest
+                | Content::PlayAudioRequest { .. }
+                | Content::DelegateRemoval { .. }
+                | Content::GossipTombstones
+                | Content::GossipCatalog
+                | Content::AntiEntropy
+                | Content::Maintenance
+                | Content::StreamBuffering { .. }
+                | Content::CorruptFile { .. }
+                | Content::PeerUnreachable { .. }
+        )
+    }
+
+    /// Checks if `self` may be sent by a peer that is not known as a member yet. These
+    /// notifications belong to the join handshake or the lookups of the DHT and check the
+    /// sender on their own.
+    pub fn is_join(&self) -> bool {
+        matches!(
+            self,
+            Content::RequestForTable { .. }
+                | Content::SendNetworkTable { .. }
+                | Content::JoinRejected { .. }
+                | Content::SendNetworkUpdateTable { .. }
+                | Content::FindNode { .. }
+        )
+    }
+}
 /// Sends a TCPRequest to the specified target.
 /// # Parameters:
 /// - `target` - The target
 /// - `notification` - The `Notification` that is to be sent to the target
 pub fn tcp_request_with_notification(target: SocketAddr, notification: Notification) {
+    if let Err(e) = try_tcp_request(target, &notification) {
+        eprintln!("{}", e);
+    }
+}
+
+/// Sends `notification` to `target`
+/// # Returns
+/// The reason on `Err` if the notification could not be sent
+pub fn try_tcp_request(target: SocketAddr, notification: &Notification) -> Result<(), String> {
     let stream = match TcpStream::connect_timeout(&target, Duration::new(1, 1)) {
         Ok(s) => s,
-        Err(_e) => {
-            handle_error(notification.content, target);
-            return;
-        }
-    };
-    let not = notification;
-
-    match serde_json::to_writer(&stream, &not) {
-        Ok(ser) => ser,
-        Err(_e) => {
-            println!("Failed to serialize SendRequest {:?}", &not);
-        }
+        Err(_e) => return Err(format!("Failed to connect to {:?}", target)),
     };
-}
-
-fn handle_error(content: Content, target: SocketAddr) {
-    match content {
-        Content::RequestForTable { .. } => {
-            println!("There is no existing network containing this IP {:?}\nPlease check the IP-Address you want to join", target);
-            process::exit(0);
-        }
-        _ => {
-            eprintln!("Failed to connect to {:?}", target);
-        }
+    match send_notification(stream, notification) {
+        Ok(_) => Ok(()),
+        Err(_e) => Err(format!("Failed to send request {:?}", notification)),
     }
 }
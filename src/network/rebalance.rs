@@ -0,0 +1,232 @@
+use crate::interface::{Notification, Peer};
+use crate::network::music_exchange::send_holders_update;
+use crate::network::notification::{tcp_request_with_notification, Content};
+use crate::network::send_write_request;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Number of songs that are moved to other peers at the same time
+pub(crate) const PARALLEL_HANDOFFS: usize = 2;
+/// Time the new holder of a song has to confirm it before the handoff is given up
+const HANDOFF_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// A copy of a song that is moved to a peer that joined the network
+#[derive(Clone, Debug)]
+struct Handoff {
+    title: String,
+    target: SocketAddr,
+    /// `true` if the local peer is not responsible for the song anymore and deletes its copy
+    /// once the target confirmed it
+    drop: bool,
+    started: Option<Instant>,
+}
+
+/// The copies the local peer moves to peers that joined the network. Only a few of them are sent
+/// at the same time, so the transfers do not saturate the links of the peers.
+#[derive(Clone, Default)]
+pub struct Rebalancer {
+    queue: VecDeque<Handoff>,
+    in_flight: Vec<Handoff>,
+}
+
+impl Rebalancer {
+    /// Checks if `title` is queued or sent to `target`
+    fn contains(&self, title: &str, target: SocketAddr) -> bool {
+        self.queue
+            .iter()
+            .chain(self.in_flight.iter())
+            .any(|h| h.title == title && h.target == target)
+    }
+
+    /// Checks if any handoff of `title` is not finished yet
+    fn is_pending(&self, title: &str) -> bool {
+        self.queue
+            .iter()
+            .chain(self.in_flight.iter())
+            .any(|h| h.title == title)
+    }
+
+    fn push(&mut self, title: String, target: SocketAddr, drop: bool) {
+        self.queue.push_back(Handoff {
+            title,
+            target,
+            drop,
+            started: None,
+        });
+    }
+
+    /// Takes the next handoff from the queue if fewer than `PARALLEL_HANDOFFS` are sent
+    fn start_next(&mut self) -> Option<Handoff> {
+        if self.in_flight.len() >= PARALLEL_HANDOFFS {
+            return None;
+        }
+        let mut handoff = self.queue.pop_front()?;
+        handoff.started = Some(Instant::now());
+        self.in_flight.push(handoff.clone());
+        Some(handoff)
+    }
+
+    /// Removes the handoff of `title` to `target`
+    fn finish(&mut self, title: &str, target: SocketAddr) -> Option<Handoff> {
+        let index = self
+            .in_flight
+            .iter()
+            .position(|h| h.title == title && h.target == target)?;
+        Some(self.in_flight.remove(index))
+    }
+
+    /// Removes the handoffs whose target did not confirm them within `HANDOFF_TIMEOUT`
+    fn expire(&mut self) -> Vec<Handoff> {
+        let (expired, in_flight) = self
+            .in_flight
+            .drain(..)
+            .partition(|h| h.started.is_some_and(|s| s.elapsed() > HANDOFF_TIMEOUT));
+        self.in_flight = in_flight;
+        expired
+    }
+}
+
+/// Queues the songs of the local peer that the peer at `newcomer`, which just joined the
+/// network, is now responsible for on the hash ring. A song is handed over by the holder that is
+/// not responsible for it anymore, or copied by the holder with the lowest address if every
+/// holder stays responsible. The newcomer gets at most an equal share of the capacity it
+/// advertised from every other peer.
+pub fn rebalance_to(newcomer: SocketAddr, peer: &mut Peer) {
+    let own = peer.ip_address;
+    // in DHT mode the responsible peers of a song are only known after a lookup
+    if peer.routing.is_some() || newcomer == own {
+        return;
+    }
+    let senders = peer.network_table.len().saturating_sub(1).max(1) as u64;
+    let mut budget = peer
+        .hellos
+        .get(&newcomer)
+        .and_then(|h| h.capacity)
+        .map(|c| c / senders);
+    let mut queued = 0;
+    let mut skipped = 0;
+    for title in peer.get_db().keys() {
+        let responsible = peer.responsible_peers(&title);
+        if !responsible.contains(&newcomer)
+            || peer.rebalancer.contains(&title, newcomer)
+            || peer.is_deleted(&title, peer.owners.get(&title))
+        {
+            continue;
+        }
+        let mut holders: Vec<SocketAddr> = peer
+            .holders
+            .get(&title)
+            .cloned()
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|h| peer.network_table.values().any(|v| v == h))
+            .collect();
+        if holders.contains(&newcomer) {
+            continue;
+        }
+        if !holders.contains(&own) {
+            holders.push(own);
+        }
+        let drop = !responsible.contains(&own);
+        if !drop {
+            let handed_over = holders.iter().any(|h| !responsible.contains(h));
+            if handed_over || holders.iter().min() != Some(&own) {
+                continue;
+            }
+        }
+        let size = peer.get_db().size(&title).unwrap_or(0);
+        if let Some(left) = budget {
+            if size > left {
+                skipped += 1;
+                continue;
+            }
+            budget = Some(left - size);
+        }
+        peer.rebalancer.push(title, newcomer, drop);
+        queued += 1;
+    }
+    if queued > 0 {
+        println!("Moving {} songs to the new peer at {:?}", queued, newcomer);
+    }
+    if skipped > 0 {
+        info!(
+            "{} songs were not moved to {:?}, they exceed its capacity",
+            skipped, newcomer
+        );
+    }
+}
+
+/// Gives up the handoffs whose target did not confirm them in time and sends the next songs
+/// from the queue
+pub fn continue_rebalancing(peer: &mut Peer) {
+    for handoff in peer.rebalancer.expire() {
+        error!(
+            "{:?} did not confirm {} in time, the local copy is kept",
+            handoff.target, handoff.title
+        );
+    }
+    let own = peer.ip_address;
+    while let Some(handoff) = peer.rebalancer.start_next() {
+        // the song could have been removed or the target could have left in the meantime
+        if !peer.does_file_exist(&handoff.title) || peer.id_of(&handoff.target).is_none() {
+            peer.rebalancer.finish(&handoff.title, handoff.target);
+            continue;
+        }
+        info!("Moving {} to {:?}", handoff.title, handoff.target);
+        send_write_request(handoff.target, own, handoff.title, peer);
+    }
+}
+
+/// Records that the peer at `sender` stored a copy of `key` with the content hash `hash`. If the
+/// copy was handed over because the local peer is not responsible for the song anymore, the
+/// local copy is deleted once all responsible peers hold it.
+/// # Returns
+/// `true` if the local copy was deleted
+pub fn replica_stored(key: String, hash: String, sender: SocketAddr, peer: &mut Peer) -> bool {
+    if peer.id_of(&sender).is_none() {
+        return false;
+    }
+    let handoff = peer.rebalancer.finish(&key, sender);
+    if peer.get_db().hash_of(&key).as_ref() != Some(&hash) {
+        return false;
+    }
+    peer.confirm_holder(&key, sender);
+    if !handoff.is_some_and(|h| h.drop) || peer.rebalancer.is_pending(&key) {
+        return false;
+    }
+    let own = peer.ip_address;
+    let responsible = peer.responsible_peers(&key);
+    // a copy that was only sent to a peer does not count until that peer confirmed it
+    let confirmed = responsible
+        .iter()
+        .all(|r| peer.is_confirmed_holder(&key, r));
+    if responsible.contains(&own) || !confirmed {
+        return false;
+    }
+    let holders = peer.holders.get(&key).cloned().unwrap_or_default();
+    println!(
+        "Handed {} over to {:?}, removing the local copy",
+        key, sender
+    );
+    peer.delete_file_from_database(&key);
+    let holders: Vec<SocketAddr> = holders.into_iter().filter(|h| *h != own).collect();
+    peer.set_holders(&key, holders.clone());
+    for holder in &holders {
+        send_holders_update(*holder, own, &key, holders.clone());
+    }
+    true
+}
+
+/// Confirms to the peer at `target` that the local peer stored its copy of `key`
+pub fn send_replica_stored(target: SocketAddr, from: SocketAddr, key: &str, hash: String) {
+    let not = Notification {
+        content: Content::ReplicaStored {
+            key: key.to_string(),
+            hash,
+        },
+        from,
+        signer: None,
+    };
+    tcp_request_with_notification(target, not);
+}
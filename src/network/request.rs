@@ -1,23 +1,42 @@
-use crate::audio::{play_music_by_vec, save_music_to_disk, MusicPlayer};
-use crate::interface::Peer;
+use crate::audio::{move_music_to_disk, play_music_by_vec, save_music_to_disk, MusicPlayer};
+use crate::database::content_hash;
+use crate::interface::{Notification, Peer};
+use crate::metadata::SongMetadata;
+use crate::network::departure::hand_off;
+use crate::network::dht::{
+    forget_contact, key_id, node_id, start_lookup, sync_network_table, LookupPurpose,
+};
+use crate::network::file_request::{fetch_completed, fetch_failed, RequestId};
 use crate::network::handshake::{
-    json_string_to_network_table, send_change_name_request, send_network_table_request,
-    send_table_request, send_table_to_all_peers, update_table_after_delete,
+    json_string_to_network_table, send_join_rejected, send_network_table_request,
+    send_table_to_all_peers, Hello,
 };
+use crate::network::identity::PeerId;
+use crate::network::invite::{check_membership, redeem_invite};
 use crate::network::music_exchange::{
-    delete_redundant_song_request, read_file_exist, send_exist_response, send_file_request,
-    send_get_file_reponse, song_order_request,
+    delete_redundant_song_request, read_file_exist, send_delete_rejected, send_exist_response,
+    send_file_not_found, send_holders_update, send_ownership_update, song_order_request,
 };
+use crate::network::notification::Content;
+use crate::network::ownership::Ownership;
+use crate::network::rebalance::{rebalance_to, send_replica_stored};
+use crate::network::tombstone::{apply_tombstone, send_tombstone_ack, Tombstone};
+use crate::network::transfer::{offer_file, IncomingTransfer, TransferKind};
 use crate::network::{
-    other_random_target, send_local_file_status, send_read_request, send_status_request,
-    send_write_request,
+    send_local_file_status, send_read_request, send_status_request, send_write_request,
 };
 use crate::utils::FileInstructions::{GET, ORDER, PLAY, REMOVE};
-use crate::utils::FileStatus::{DELETE, NEW};
+use crate::utils::FileStatus::{DELETE, DOWNLOAD, NEW};
+use crate::utils::LookupResult::{FOUND, MISSING};
 use crate::utils::{AppListener, FileInstructions};
-use std::net::SocketAddr;
-use std::process;
-use std::time::SystemTime;
+use std::fs;
+use std::net::{SocketAddr, TcpStream};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+/// How long the local peer tries to reach a peer that was reported as dropped
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
 
 pub fn push_to_db(
     key: String,
@@ -25,46 +44,97 @@ pub fn push_to_db(
     peer: &mut Peer,
     listener: &mut Box<dyn AppListener + Sync>,
 ) {
-    if peer.database.data.contains_key(&key) {
+    let hash = content_hash(&value);
+    let key = match peer.get_db().hash_of(&key) {
+        Some(existing) if existing != hash => {
+            let title = format!("{} ({})", key, &hash[..8]);
+            println!(
+                "{} already exists with other content, saving as {}",
+                key, title
+            );
+            title
+        }
+        _ => key,
+    };
+    if peer.get_db().hash_of(&key).as_ref() == Some(&hash) {
         println!("File already exists in your database");
     } else {
-        peer.process_store_request((key.clone(), value.clone()));
+        if let Err(e) = peer.process_store_request((key.clone(), value)) {
+            error!("Could not save {} to the database: {}", key, e);
+            return;
+        }
         println!("Saved file to database");
+        match Ownership::create(&key, &hash, peer.id.clone(), Vec::new(), &peer.ip_address) {
+            Ok(ownership) => peer.record_ownership(ownership, &hash),
+            Err(e) => error!("Could not record the ownership of {}: {}", key, e),
+        }
         let key_clone = key.clone();
         listener.local_database_changed(key_clone, NEW);
+        replicate(&key, peer);
+    }
+}
 
-        let redundant_target = other_random_target(&peer.network_table, peer.get_ip());
-        match redundant_target {
-            Some(target) => {
-                send_write_request(
-                    target,
-                    *peer.get_ip(),
-                    (key.clone(), value),
-                    true,
-                    peer,
-                );
-                match peer.redundancy_table.get_mut(&target) {
-                    Some(p) => p.push(key),
-                    None => {
-                        peer.redundancy_table.insert(target.clone(), vec![key]);
-                    }
-                }
-            }
-            None => println!("Only peer in network. No redundancy possible"),
+/// Sends copies of the local song `key` to its responsible peers, on the hash ring or in DHT mode
+/// the closest peers found by a lookup. The local peer keeps its copy even if it is not
+/// responsible for the song.
+pub fn replicate(key: &str, peer: &mut Peer) {
+    if peer.is_deleted(key, peer.owners.get(key)) {
+        info!("{} was removed from the network, it is not replicated", key);
+        return;
+    }
+    let own = peer.ip_address;
+    peer.add_holder(key, own);
+    if peer.routing.is_some() {
+        let purpose = LookupPurpose::Replicate {
+            key: key.to_string(),
         };
+        start_lookup(peer, key_id(key), purpose);
+        return;
     }
+    let responsible = peer.responsible_peers(key);
+    if responsible.len() < peer.replication_factor {
+        println!(
+            "Not enough peers in the network, {} is held by {} instead of {} peers",
+            key,
+            responsible.len(),
+            peer.replication_factor
+        );
+    }
+    replicate_to(key, responsible, peer);
 }
 
-pub fn redundant_push_to_db(
+/// Sends copies of the local song `key` to the peers in `responsible` that do not hold it yet
+/// and tells all holders about the new set of holders
+pub fn replicate_to(key: &str, responsible: Vec<SocketAddr>, peer: &mut Peer) {
+    let own = peer.ip_address;
+    let holders = peer.holders.get(key).cloned().unwrap_or_default();
+    let targets: Vec<SocketAddr> = responsible
+        .into_iter()
+        .filter(|addr| *addr != own && !holders.contains(addr))
+        .collect();
+    if targets.is_empty() {
+        return;
+    }
+    for target in targets {
+        send_write_request(target, own, key.to_string(), peer);
+        peer.add_holder(key, target);
+    }
+    let holders = peer.holders.get(key).cloned().unwrap_or_default();
+    for holder in &holders {
+        if *holder != own {
+            send_holders_update(*holder, own, key, holders.clone());
+        }
+    }
+}
+
+/// Bookkeeping after a redundant copy of a song of the peer at `from` was stored
+fn redundant_song_stored(
     key: String,
-    value: Vec<u8>,
+    from: String,
     peer: &mut Peer,
     listener: &mut Box<dyn AppListener + Sync>,
-    from: String,
 ) {
     let key_clone = key.clone();
-    let key_redundant_clone = key.clone();
-    peer.process_store_request((key, value));
     listener.local_database_changed(key_clone, NEW);
     let from_address = match from.parse::<SocketAddr>() {
         Ok(a) => a,
@@ -74,25 +144,48 @@ pub fn redundant_push_to_db(
         }
     };
 
-    match peer.redundancy_table.get_mut(&from_address) {
-        Some(p) => p.push(key_redundant_clone),
-        None => {
-            peer.redundancy_table
-                .insert(from_address, vec![key_redundant_clone]);
-        }
+    let own = peer.ip_address;
+    peer.add_holder(&key, from_address);
+    peer.add_holder(&key, own);
+    if let Some(hash) = peer.get_db().hash_of(&key) {
+        send_replica_stored(from_address, own, &key, hash);
+    }
+    // a peer that leaves passes songs it receives on as well
+    if peer.departure.is_some() && !hand_off(&key, peer) {
+        error!("No peer could take over {} from the leaving peer", key);
     }
 }
 
-pub fn change_peer_name(value: String, sender: SocketAddr, peer: &mut Peer) {
-    peer.network_table.remove(&peer.name);
-    peer.name = value;
-    peer.network_table
-        .insert(peer.name.clone(), peer.ip_address);
-    //send request existing network table
-    send_table_request(sender, *peer.get_ip(), &peer.name);
-}
-
-pub fn send_network_table(value: Vec<u8>, peer: &mut Peer) {
+/// Takes over the network table and the secret of the network the local peer was accepted into.
+/// The table is only accepted from the peer the local peer asked to join, which has to list
+/// itself in the table with the ID that signed it.
+pub fn send_network_table(
+    value: Vec<u8>,
+    secret: Vec<u8>,
+    signer: Option<PeerId>,
+    sender: SocketAddr,
+    peer: &mut Peer,
+) {
+    if peer.join_target != Some(sender) {
+        error!(
+            "Ignored a network table from {:?} that was not asked for",
+            sender
+        );
+        return;
+    }
+    // a member only accepts the table of its own network
+    if peer
+        .network_secret
+        .as_ref()
+        .is_some_and(|own| *own != secret)
+    {
+        error!("Ignored the network table of another network");
+        return;
+    }
+    if secret.is_empty() {
+        error!("The network table did not contain the secret of the network");
+        return;
+    }
     let table = match String::from_utf8(value) {
         Ok(val) => val,
         Err(utf) => {
@@ -101,13 +194,58 @@ pub fn send_network_table(value: Vec<u8>, peer: &mut Peer) {
         }
     };
     let network_table = json_string_to_network_table(table);
-    for (key, addr) in network_table {
-        peer.network_table.insert(key, addr);
+    let signed_by_target = network_table
+        .iter()
+        .any(|info| info.addr == sender && Some(&info.id) == signer.as_ref());
+    if !signed_by_target {
+        error!(
+            "The network table was not signed by the peer at {:?}",
+            sender
+        );
+        return;
+    }
+    peer.join_target = None;
+    peer.set_network_secret(secret);
+    for info in network_table {
+        if info.id == peer.id {
+            continue;
+        }
+        peer.members.insert(info.id.clone());
+        if let Some(hello) = info.hello {
+            peer.hellos.insert(info.addr, hello);
+        }
+        peer.peer_names.insert(info.id.clone(), info.name);
+        peer.network_table.insert(info.id, info.addr);
     }
+    sync_network_table(peer);
     send_table_to_all_peers(peer);
+    if peer.routing.is_some() {
+        let own = node_id(&peer.id);
+        start_lookup(peer, own, LookupPurpose::Bootstrap);
+    }
 }
 
-pub fn send_network_update_table(value: Vec<u8>, peer: &mut Peer) {
+/// Adds the peer that announced itself with `value` to the network table and moves its share of
+/// the songs to it. A peer can only announce its own ID and has to prove with `proof` that it
+/// knows the network secret.
+pub fn send_network_update_table(
+    value: Vec<u8>,
+    proof: String,
+    signer: Option<PeerId>,
+    peer: &mut Peer,
+) {
+    let is_member = match (&peer.network_secret, &signer) {
+        (Some(secret), Some(id)) => check_membership(secret, id.as_str(), &proof),
+        _ => false,
+    };
+    let id = match (is_member, signer) {
+        (true, Some(id)) => id,
+        _ => {
+            error!("Ignored the announcement of a peer that is not a member of the network");
+            return;
+        }
+    };
+    peer.members.insert(id.clone());
     let table = match String::from_utf8(value) {
         Ok(val) => val,
         Err(utf) => {
@@ -116,21 +254,52 @@ pub fn send_network_update_table(value: Vec<u8>, peer: &mut Peer) {
         }
     };
     let new_network_peer = json_string_to_network_table(table);
-    for (key, addr) in new_network_peer {
-        let name = key.clone();
-        peer.network_table.insert(key, addr);
-        println!("{} joined the network.", name);
+    let mut joined = Vec::new();
+    for info in new_network_peer {
+        if info.id != id {
+            error!("Ignored the announcement of {} by another peer", info.id);
+            continue;
+        }
+        if let Some(hello) = info.hello {
+            peer.hellos.insert(info.addr, hello);
+        }
+        println!("{} joined the network.", info.name);
+        peer.peer_names.insert(info.id.clone(), info.name);
+        peer.network_table.insert(info.id, info.addr);
+        joined.push(info.addr);
+    }
+    sync_network_table(peer);
+    for addr in joined {
+        rebalance_to(addr, peer);
     }
 }
 
-pub fn request_for_table(value: String, sender: SocketAddr, peer: &mut Peer) {
-    // checks if key is unique, otherwise send change name request
-    if peer.network_table.contains_key(&value) {
-        let name = format!("{}+{}", &value, "1");
-        send_change_name_request(sender, *peer.get_ip(), name.as_ref());
-    } else {
-        send_network_table_request(sender, &peer);
+/// Answers the join request of `value` with the network table if its `Hello` is compatible and
+/// its invite token is valid
+pub fn request_for_table(
+    value: String,
+    hello: Hello,
+    invite: Option<String>,
+    sender: SocketAddr,
+    peer: &mut Peer,
+) {
+    let checked = hello
+        .check_compatible()
+        .and_then(|_| match (&peer.network_secret, invite) {
+            (Some(secret), Some(token)) => {
+                redeem_invite(secret, &token, &mut peer.redeemed_invites)
+            }
+            (Some(_), None) => Err("The network can only be joined with an invite".to_string()),
+            (None, _) => Err("The peer is not a member of a network yet".to_string()),
+        });
+    if let Err(reason) = checked {
+        println!("Rejected {} at {:?}: {}", value, sender, reason);
+        send_join_rejected(sender, *peer.get_ip(), &reason);
+        return;
     }
+    peer.hellos.insert(sender, hello);
+    // names do not need to be unique, the joining peer is known by its ID
+    send_network_table_request(sender, &peer);
 }
 
 pub fn find_file(
@@ -139,137 +308,263 @@ pub fn find_file(
     peer: &mut Peer,
     listener: &mut Box<dyn AppListener + Sync>,
 ) {
-    // @TODO there is no feedback when audio does not exist in "global" database (there is only the existsFile response, when file exists in database? change?
-    // @TODO in this case we need to remove the request?
-    if peer.get_db().get_data().contains_key(&song_name) {
-        if instr == REMOVE {
-            peer.delete_file_from_database(&song_name);
-            let song_clone = song_name.clone();
-            listener.local_database_changed(song_clone, DELETE);
-            println!("Remove file {} from database", &song_name);
-
-            let id = SystemTime::now();
-            peer.add_new_request(&id, instr);
-
-            for (_key, value) in &peer.network_table {
-                if _key != &peer.name {
-                    delete_redundant_song_request(*value, peer.ip_address, &song_name);
-                }
-            }
-        } else if instr == GET {
-            if let Some(file) = peer.get_db().get_data().get(&song_name) {
-                if let Err(e) = save_music_to_disk(file.clone(), &song_name) {
+    if instr == REMOVE {
+        remove_song(song_name, peer, listener);
+        return;
+    }
+    if peer.does_file_exist(&song_name) {
+        if instr == GET {
+            if let Some(file) = peer.find_file(&song_name) {
+                if let Err(e) = save_music_to_disk(file, &song_name) {
                     error!("{}", e);
                 }
             }
         }
-    } else {
-        let id = SystemTime::now();
-        peer.add_new_request(&id, instr);
+        listener.file_lookup_finished(song_name, instr, FOUND);
+        return;
+    }
+    if peer.routing.is_some() {
+        // the peers that are asked are only known once the lookup finished
+        let id = peer.open_request_table.open(&song_name, instr, Vec::new());
+        let target = key_id(&song_name);
+        start_lookup(peer, target, LookupPurpose::FindFile { song_name, id });
+        return;
+    }
+    let targets = peer.lookup_targets(&song_name);
+    if targets.is_empty() {
+        listener.file_lookup_finished(song_name, instr, MISSING);
+        return;
+    }
+    let id = peer
+        .open_request_table
+        .open(&song_name, instr, targets.clone());
+    for target in targets {
+        read_file_exist(target, peer.ip_address, &song_name, id);
+    }
+}
 
-        for (_key, value) in &peer.network_table {
-            if _key != &peer.name {
-                read_file_exist(*value, peer.ip_address, &song_name, id);
-            }
+/// Removes `song_name` from the local database and sends its tombstone to all other peers, in
+/// DHT mode also to the responsible peers a lookup finds. Every holder checks on its own that the
+/// local peer may remove the song, the peers that reject the removal report back.
+fn remove_song(song_name: String, peer: &mut Peer, listener: &mut Box<dyn AppListener + Sync>) {
+    let mut holders = peer.holders.get(&song_name).cloned().unwrap_or_default();
+    for addr in peer.responsible_peers(&song_name) {
+        if !holders.contains(&addr) {
+            holders.push(addr);
+        }
+    }
+    let tombstone = match Tombstone::create(&song_name, peer.id.clone(), holders, &peer.ip_address)
+    {
+        Ok(t) => t,
+        Err(e) => {
+            error!("Could not sign the removal of {}: {}", song_name, e);
+            return;
+        }
+    };
+    match apply_tombstone(tombstone.clone(), peer) {
+        Ok(true) => {
+            let song_clone = song_name.clone();
+            listener.local_database_changed(song_clone, DELETE);
+        }
+        Ok(false) => {}
+        Err(reason) => {
+            listener.delete_rejected(song_name, reason);
+            return;
         }
     }
-}
 
-pub fn get_file(instr: FileInstructions, key: String, sender: SocketAddr, peer: &mut Peer) {
-    match peer.find_file(key.as_ref()) {
-        Some(music) => {
-            send_get_file_reponse(sender, peer.ip_address, key.as_ref(), music.clone(), instr)
+    for (id, addr) in &peer.network_table {
+        if *id != peer.id {
+            delete_redundant_song_request(*addr, peer.ip_address, tombstone.clone());
         }
-        None => {
-            //@TODO error handling}
-            println!("TODO!");
+    }
+    // the routing table does not know every peer, the holders are found by a lookup
+    if peer.routing.is_some() {
+        let target = key_id(&song_name);
+        start_lookup(peer, target, LookupPurpose::Remove { song_name });
+    }
+}
+
+/// Sends the tombstone of the removed song `song_name` to the peers in `responsible` that are not
+/// in the network table and waits for them to acknowledge it as well
+pub fn remove_from(song_name: &str, responsible: Vec<SocketAddr>, peer: &mut Peer) {
+    let own = peer.ip_address;
+    let known: Vec<SocketAddr> = peer.network_table.values().cloned().collect();
+    let tombstone = match peer.tombstones.get_mut(song_name) {
+        Some(t) => t,
+        None => return,
+    };
+    let targets: Vec<SocketAddr> = responsible
+        .into_iter()
+        .filter(|addr| *addr != own && !known.contains(addr))
+        .collect();
+    if targets.is_empty() {
+        return;
+    }
+    for addr in &targets {
+        if !tombstone.holders.contains(addr) {
+            tombstone.holders.push(*addr);
         }
     }
+    let tombstone = tombstone.clone();
+    peer.save_tombstones();
+    for addr in targets {
+        delete_redundant_song_request(addr, own, tombstone.clone());
+    }
 }
 
-pub fn get_file_response(
-    instr: &FileInstructions,
-    key: &str,
-    value: Vec<u8>,
+/// Sends the song `key` to the peer at `sender`. If the song is not stored here, the open request
+/// `id` of the sender is told, so it asks another peer.
+pub fn get_file(
+    instr: FileInstructions,
+    key: String,
+    id: Option<RequestId>,
+    sender: SocketAddr,
     peer: &mut Peer,
-    sink: &mut MusicPlayer,
-) -> Result<(), String> {
+) {
+    if peer.does_file_exist(&key) {
+        offer_file(sender, peer, &key, TransferKind::Response(instr));
+        return;
+    }
+    error!("{:?} requested {}, which is not stored here", sender, key);
+    if let Some(id) = id {
+        send_file_not_found(sender, peer.ip_address, &key, id);
+    }
+}
+
+/// Notifies the listener about a file that was received for a `GetFile` request. An ordered
+/// song makes the local peer one of its holders.
+fn file_response_received(
+    instr: FileInstructions,
+    key: String,
+    peer: &mut Peer,
+    listener: &mut Box<dyn AppListener + Sync>,
+) {
+    fetch_completed(&key, peer, listener);
     match instr {
-        PLAY => {
-            //save to tmp and play audio
-            play_music_by_vec(value, sink, key.to_string())
-        }
+        PLAY => listener.player_playing(Some(key)),
         GET => {
-            if let Err(_e) = save_music_to_disk(value, &key.to_string()) {
-                return Err("Could not save music to disk".to_string());
-            };
-            Ok(())
+            listener.local_database_changed(key, DOWNLOAD);
         }
         ORDER => {
-            peer.process_store_request((key.to_string(), value));
-            Ok(())
+            replicate(&key, peer);
+            listener.local_database_changed(key, NEW);
+        }
+        _ => {}
+    }
+}
+
+/// Handles a chunked transfer after all chunks were received into the file at `path`
+pub fn file_transfer_completed(
+    transfer: IncomingTransfer,
+    path: &Path,
+    peer: &mut Peer,
+    sink: &mut MusicPlayer,
+    listener: &mut Box<dyn AppListener + Sync>,
+) {
+    let key = transfer.key;
+    let source = transfer.source;
+    match transfer.kind {
+        TransferKind::Redundant { from } => {
+            if let Err(e) = peer.database.import(&key, path) {
+                error!("Could not save {} to the database: {}", key, e);
+                return;
+            }
+            redundant_song_stored(key, from, peer, listener);
+        }
+        TransferKind::Response(instr) => {
+            let result = match instr {
+                PLAY => {
+                    let music = fs::read(path);
+                    let _ = fs::remove_file(path);
+                    match music {
+                        Ok(value) => play_music_by_vec(value, sink, key.clone()),
+                        Err(e) => Err(format!("Could not read {:?}: {:?}", path, e)),
+                    }
+                }
+                GET => move_music_to_disk(path, &key),
+                ORDER => peer.database.import(&key, path),
+                _ => Err("Unknown command".to_string()),
+            };
+            match result {
+                Ok(_) => file_response_received(instr, key, peer, listener),
+                Err(e) => {
+                    error!("{}", e);
+                    fetch_failed(&key, source, peer, listener);
+                }
+            }
         }
-        _ => Err("Unknown command".to_string()),
     }
 }
 
-pub fn exist_file(song_name: String, id: SystemTime, sender: SocketAddr, peer: &mut Peer) {
+pub fn exist_file(song_name: String, id: RequestId, sender: SocketAddr, peer: &mut Peer) {
     let exist = peer.does_file_exist(song_name.as_ref());
     if exist {
         send_exist_response(sender, peer.ip_address, song_name.as_ref(), id);
+    } else {
+        send_file_not_found(sender, peer.ip_address, song_name.as_ref(), id);
     }
 }
 
-pub fn exit_peer(addr: SocketAddr, peer: &mut Peer) {
-    if peer.network_table.len() > 1 {
-        for value in peer.network_table.values() {
-            if *value != addr {
-                update_table_after_delete(*value, addr, &peer.name);
-            }
-        }
-        let database = peer.get_db().get_data();
-        let network_table = &peer.network_table;
-        if network_table.len() > 1 {
-            for song in database.keys() {
-                let redundant_target = match other_random_target(network_table, peer.get_ip()) {
-                    Some(r) => r,
-                    None => {
-                        continue;
-                    }
-                };
-                song_order_request(redundant_target, peer.ip_address, song.to_string());
-            }
-        }
+/// Discards a corrupt copy of `key` that the scrubber found and orders the song again from
+/// another peer that holds it
+pub fn corrupt_file(key: String, peer: &mut Peer) {
+    // the song could have been replaced since the scrubber read it
+    if peer.get_db().verify(&key) != Some(false) {
+        return;
     }
-    process::exit(0);
+    peer.delete_file_from_database(&key);
+    println!("Discarded the corrupt copy of {}, fetching it again", key);
+    send_read_request(peer, &key, ORDER);
 }
 
-pub fn delete_from_network(name: String, peer: &mut Peer) {
-    if peer.network_table.contains_key(&name) {
-        peer.network_table.remove(&name);
+/// Removes the peer `id` that left the network. Only the peer itself can announce that.
+pub fn delete_from_network(id: PeerId, signer: Option<PeerId>, peer: &mut Peer) {
+    if signer.as_ref() != Some(&id) {
+        error!("Ignored the removal of {} requested by another peer", id);
+        return;
+    }
+    peer.members.remove(&id);
+    if let Some(addr) = peer.network_table.remove(&id) {
+        let name = peer
+            .peer_names
+            .remove(&id)
+            .unwrap_or_else(|| id.to_string());
+        peer.hellos.remove(&addr);
+        peer.leaving.remove(&addr);
+        peer.listings.remove(&addr);
+        forget_contact(&addr, peer);
         println!("{} left the network.", &name);
+        redistribute_files(addr, peer);
     }
 }
 
-pub fn exist_file_response(song_name: String, id: SystemTime, sender: SocketAddr, peer: &mut Peer) {
-    //Check if peer request is still active. when true remove it
-    let peer_clone = peer.open_request_table.clone();
-    match peer_clone.get(&id) {
-        Some(instr) => {
-            peer.delete_handled_request(&id);
-            send_file_request(sender, peer.ip_address, song_name.as_ref(), instr.clone());
-        }
-        None => {
-            info!("Did not find requested file");
-        }
+/// Reports that the peer the local peer asked to join rejected it
+pub fn join_rejected(reason: String, hello: Hello, sender: SocketAddr, peer: &Peer) {
+    if peer.join_target != Some(sender) {
+        error!(
+            "Ignored a rejection from {:?}, which was not asked to join",
+            sender
+        );
+        return;
     }
+    error!(
+        "Peer at {:?} with protocol version {} rejected the join: {}",
+        sender, hello.version, reason
+    );
+    println!("Could not join the network at {:?}: {}", sender, reason);
 }
 
 pub fn status_request(sender: SocketAddr, peer: &mut Peer) {
-    let mut res: Vec<String> = Vec::new();
-    for k in peer.get_db().data.keys() {
-        res.push(k.to_string());
-    }
+    let db = peer.get_db();
+    let res: Vec<(String, SongMetadata)> = db
+        .keys()
+        .into_iter()
+        .map(|title| {
+            let metadata = db.metadata(&title).unwrap_or_default();
+            (title, metadata)
+        })
+        .collect();
     let peer_name = &peer.name;
     send_local_file_status(sender, res, *peer.get_ip(), peer_name.to_string());
 }
@@ -281,6 +576,39 @@ pub fn self_status_request(peer: &mut Peer) {
     }
 }
 
+/// Checks on its own thread if the peer at `addr`, which another peer reported as dropped, can
+/// still be reached. The local peer only drops it if it can not connect to it either.
+pub fn check_dropped_peer(addr: SocketAddr, peer: &Peer) {
+    if addr == peer.ip_address || !peer.network_table.values().any(|a| *a == addr) {
+        return;
+    }
+    let own = peer.ip_address;
+    let sender = peer.sender.clone();
+    if let Err(e) = thread::Builder::new()
+        .name("probe_thread".to_string())
+        .spawn(move || {
+            if TcpStream::connect_timeout(&addr, PROBE_TIMEOUT).is_ok() {
+                info!(
+                    "Peer at {:?} was reported as dropped but is reachable",
+                    addr
+                );
+                return;
+            }
+            let not = Notification {
+                content: Content::PeerUnreachable { addr },
+                from: own,
+                signer: None,
+            };
+            if let Err(e) = sender.send(not) {
+                error!("Could not send notification {:?}", e);
+            }
+        })
+    {
+        error!("Probe Thread could not be spawned: Error: {:?}", e);
+    }
+}
+
+/// Drops the peer at `addr` that could not be reached and redistributes its songs
 pub fn dropped_peer(addr: SocketAddr, peer: &mut Peer) {
     println!("Peer at {:?} was dropped", addr);
     peer.drop_peer_by_ip(&addr);
@@ -289,9 +617,8 @@ pub fn dropped_peer(addr: SocketAddr, peer: &mut Peer) {
 }
 
 pub fn order_song_request(song_name: String, peer: &mut Peer) {
-    let network_table = &peer.network_table;
-    if peer.get_db().get_data().contains_key(&song_name) {
-        let redundant_target = match other_random_target(network_table, peer.get_ip()) {
+    if peer.does_file_exist(&song_name) {
+        let redundant_target = match peer.replication_targets(&song_name, 1).pop() {
             Some(r) => r,
             None => {
                 error!("Could not find a redundant target");
@@ -304,40 +631,173 @@ pub fn order_song_request(song_name: String, peer: &mut Peer) {
     }
 }
 
-pub fn delete_file_request(song_name: &str, peer: &mut Peer) {
-    if peer.database.data.contains_key(song_name) {
-        println!("Remove file {} from database", &song_name);
-        peer.delete_file_from_database(song_name);
+/// Removes the local copy of the song of `tombstone` if the peer `signer` removed it and may do
+/// that. The removal is acknowledged to the peer at `sender`, a rejected removal is reported back.
+/// # Returns
+/// `true` if the local copy was deleted
+pub fn delete_file_request(
+    tombstone: Tombstone,
+    signer: Option<PeerId>,
+    sender: SocketAddr,
+    peer: &mut Peer,
+) -> bool {
+    let title = tombstone.title.clone();
+    let deleted = tombstone.deleted;
+    let result = if signer.as_ref() == Some(&tombstone.deleted_by) {
+        apply_tombstone(tombstone, peer)
+    } else {
+        Err("The removal was not signed by the peer that requested it".to_string())
+    };
+    match result {
+        Ok(removed) => {
+            send_tombstone_ack(sender, peer.ip_address, &title, deleted);
+            removed
+        }
+        Err(reason) => {
+            println!("Rejected the removal of {}: {}", title, reason);
+            send_delete_rejected(sender, peer.ip_address, &title, &reason);
+            false
+        }
+    }
+}
+
+/// Allows the peer whose ID starts with `delegate` to remove the song `song_name` of the local
+/// peer and sends the new ownership record to the other peers
+pub fn delegate_removal(song_name: String, delegate: String, peer: &mut Peer) {
+    let ownership = match peer.owners.get(&song_name) {
+        Some(o) if o.owner == peer.id => o.clone(),
+        Some(o) => {
+            println!(
+                "{} belongs to {}, only the owner can delegate its removal",
+                song_name,
+                peer.display_name(&o.owner)
+            );
+            return;
+        }
+        None => {
+            println!("There is no ownership record of {}", song_name);
+            return;
+        }
+    };
+    let matches: Vec<PeerId> = peer
+        .network_table
+        .keys()
+        .filter(|id| !delegate.is_empty() && id.as_str().starts_with(delegate.as_str()))
+        .cloned()
+        .collect();
+    let delegate = match matches.as_slice() {
+        [id] => id.clone(),
+        [] => {
+            println!("There is no peer with the ID {} in the network", delegate);
+            return;
+        }
+        _ => {
+            println!("The ID {} matches more than one peer", delegate);
+            return;
+        }
+    };
+    if ownership.may_remove(&delegate) {
+        println!(
+            "{} may already remove {}",
+            peer.display_name(&delegate),
+            song_name
+        );
+        return;
     }
+    let mut delegates = ownership.delegates;
+    delegates.push(delegate.clone());
+    let hash = ownership.hash;
+    let ownership = match Ownership::create(
+        &song_name,
+        &hash,
+        peer.id.clone(),
+        delegates,
+        &peer.ip_address,
+    ) {
+        Ok(o) => o,
+        Err(e) => {
+            error!("Could not sign the ownership of {}: {}", song_name, e);
+            return;
+        }
+    };
+    peer.record_ownership(ownership.clone(), &hash);
+    for (id, addr) in &peer.network_table {
+        if *id != peer.id {
+            send_ownership_update(*addr, peer.ip_address, ownership.clone());
+        }
+    }
+    println!(
+        "{} may now remove {}",
+        peer.display_name(&delegate),
+        song_name
+    );
+}
+
+/// Takes over the new ownership record of a song the local peer holds, if it belongs to the held
+/// copy
+pub fn ownership_update(ownership: Ownership, peer: &mut Peer) {
+    let hash = match peer.get_db().hash_of(&ownership.title) {
+        Some(h) => h,
+        None => match peer.owners.get(&ownership.title) {
+            Some(known) => known.hash.clone(),
+            None => return,
+        },
+    };
+    peer.record_ownership(ownership, &hash);
 }
 
+/// Replaces the copies the peer at `addr` was holding. Of the remaining holders of a song only
+/// the one with the lowest address sends new copies, so a song is not replicated twice.
 pub fn redistribute_files(addr: SocketAddr, peer: &mut Peer) {
-    let mut peer_clone = peer.clone();
-    if peer.network_table.len() > 1 {
-        //let database = peer.get_db().get_data();
-        let redundant_table = &peer.redundancy_table;
-        let network_table = &peer.network_table;
-        let song_list = match redundant_table.get(&addr) {
-            Some(s) => s,
-            None => {
-                return;
-            }
+    let own = peer.ip_address;
+    for song in peer.remove_holder(&addr) {
+        if !peer.does_file_exist(&song) {
+            continue;
+        }
+        peer.add_holder(&song, own);
+        let responsible = match peer.holders.get(&song) {
+            Some(holders) => holders
+                .iter()
+                .filter(|h| **h == own || peer.network_table.values().any(|v| v == *h))
+                .min()
+                .cloned(),
+            None => None,
         };
-        if network_table.len() > 1 {
-            for song in song_list {
-                let redundant_target = match other_random_target(network_table, peer.get_ip()) {
-                    Some(r) => r,
-                    None => {
-                        continue;
-                    }
-                };
-                let file = match peer.find_file(song) {
-                    Some(f) => f,
-                    None => {return;}
-                };
-                send_write_request(redundant_target, peer.ip_address, (song.to_string(), file.clone()),true, &mut peer_clone );
-            }
+        if responsible == Some(own) {
+            replicate(&song, peer);
         }
-        peer.redundancy_table.remove(&addr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::Database;
+    use crate::testing::identity;
+    use crate::utils::Config;
+    use std::sync::mpsc::sync_channel;
+
+    #[test]
+    fn lookup_adds_the_holders_the_table_does_not_know() {
+        let (sender, _) = sync_channel(16);
+        let (id, own) = identity(47600);
+        let mut peer = Peer::create(
+            own,
+            id.clone(),
+            "test",
+            sender,
+            Database::new(),
+            &Config::default(),
+        );
+        let (known_id, known) = identity(47601);
+        let (_, found) = identity(47602);
+        peer.network_table.insert(known_id, known);
+        let tombstone = Tombstone::create("song", id, vec![known], &own).unwrap();
+        peer.tombstones.insert("song".to_string(), tombstone);
+
+        remove_from("song", vec![own, known, found], &mut peer);
+        assert_eq!(peer.tombstones["song"].holders, vec![known, found]);
+        remove_from("missing", vec![found], &mut peer);
+        assert!(!peer.tombstones.contains_key("missing"));
     }
 }
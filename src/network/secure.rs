@@ -0,0 +1,369 @@
+use crate::interface::Notification;
+use crate::network::codec::{read_notification, write_notification};
+use crate::network::identity::{sign, verify, PeerId};
+use serde::{Deserialize, Serialize};
+use snow::{Builder, HandshakeState, TransportState};
+use std::collections::HashSet;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// Noise XX: both peers send their static key encrypted, so neither needs to know the other
+/// in advance and both are authenticated at the end of the handshake
+const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+/// The largest Noise message, including the authentication tag
+const MAX_MESSAGE_SIZE: usize = 65535;
+const TAG_SIZE: usize = 16;
+/// Prefix of the signed static key, so the signature can not be mistaken for a notification
+const BINDING_PREFIX: &[u8] = b"meff-noise-static-key:";
+/// Time a read on a connection may block before the connection is given up
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+/// Time after which a `Session` opens a new connection instead of using its idle one, which
+/// the other side closes after `READ_TIMEOUT`
+const SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Time to wait for the connection to another peer
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+lazy_static! {
+    /// Local addresses that send and accept notifications without encryption
+    static ref PLAINTEXT: Mutex<HashSet<SocketAddr>> = Mutex::new(HashSet::new());
+}
+
+/// Turns the encryption of the connections of the local peer at `addr` off. Only meant for
+/// debugging, encrypted and unencrypted peers can not talk to each other.
+pub fn allow_plaintext(addr: SocketAddr) {
+    let mut plaintext = match PLAINTEXT.lock() {
+        Ok(p) => p,
+        Err(e) => e.into_inner(),
+    };
+    plaintext.insert(addr);
+}
+
+fn is_plaintext(addr: &SocketAddr) -> bool {
+    match PLAINTEXT.lock() {
+        Ok(p) => p.contains(addr),
+        Err(e) => e.into_inner().contains(addr),
+    }
+}
+
+/// Sent in the handshake together with the static key: the identity of the peer and its
+/// signature of the static key
+#[derive(Serialize, Deserialize)]
+struct KeyBinding {
+    public_key: Vec<u8>,
+    signature: Vec<u8>,
+}
+
+/// A connection that is encrypted and authenticated with the Noise protocol. The static key of
+/// each side is signed with its identity, so the remote end is known by its `PeerId`.
+pub struct SecureStream {
+    stream: TcpStream,
+    transport: TransportState,
+    remote: PeerId,
+    /// Decrypted bytes of the last message that were not read yet
+    incoming: Vec<u8>,
+    position: usize,
+}
+
+impl SecureStream {
+    /// Runs the handshake on `stream` as the peer that opened the connection
+    pub fn initiate(mut stream: TcpStream, local: &SocketAddr) -> Result<SecureStream, String> {
+        let (mut handshake, binding) = start_handshake(local, true)?;
+        let mut buf = vec![0u8; MAX_MESSAGE_SIZE];
+        // -> e
+        let len = noise_result(handshake.write_message(&[], &mut buf))?;
+        send_message(&mut stream, &buf[..len])?;
+        // <- e, ee, s, es
+        let message = receive_message(&mut stream)?;
+        let len = noise_result(handshake.read_message(&message, &mut buf))?;
+        let remote = check_binding(&handshake, &buf[..len])?;
+        // -> s, se
+        let len = noise_result(handshake.write_message(&binding, &mut buf))?;
+        send_message(&mut stream, &buf[..len])?;
+        SecureStream::finish(stream, handshake, remote)
+    }
+
+    /// Runs the handshake on `stream` as the peer that accepted the connection
+    pub fn accept(mut stream: TcpStream, local: &SocketAddr) -> Result<SecureStream, String> {
+        let (mut handshake, binding) = start_handshake(local, false)?;
+        let mut buf = vec![0u8; MAX_MESSAGE_SIZE];
+        // -> e
+        let message = receive_message(&mut stream)?;
+        noise_result(handshake.read_message(&message, &mut buf))?;
+        // <- e, ee, s, es
+        let len = noise_result(handshake.write_message(&binding, &mut buf))?;
+        send_message(&mut stream, &buf[..len])?;
+        // -> s, se
+        let message = receive_message(&mut stream)?;
+        let len = noise_result(handshake.read_message(&message, &mut buf))?;
+        let remote = check_binding(&handshake, &buf[..len])?;
+        SecureStream::finish(stream, handshake, remote)
+    }
+
+    fn finish(
+        stream: TcpStream,
+        handshake: HandshakeState,
+        remote: PeerId,
+    ) -> Result<SecureStream, String> {
+        let transport = noise_result(handshake.into_transport_mode())?;
+        Ok(SecureStream {
+            stream,
+            transport,
+            remote,
+            incoming: Vec::new(),
+            position: 0,
+        })
+    }
+
+    /// The identity of the other end of the connection
+    pub fn remote(&self) -> &PeerId {
+        &self.remote
+    }
+}
+
+impl Read for SecureStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position == self.incoming.len() {
+            let message = read_raw_message(&mut self.stream)?;
+            let mut plain = vec![0u8; message.len()];
+            let len = match self.transport.read_message(&message, &mut plain) {
+                Ok(l) => l,
+                Err(e) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("{:?}", e),
+                    ))
+                }
+            };
+            plain.truncate(len);
+            self.incoming = plain;
+            self.position = 0;
+        }
+        let n = buf.len().min(self.incoming.len() - self.position);
+        buf[..n].copy_from_slice(&self.incoming[self.position..self.position + n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
+impl Write for SecureStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut message = vec![0u8; MAX_MESSAGE_SIZE];
+        for part in buf.chunks(MAX_MESSAGE_SIZE - TAG_SIZE) {
+            let len = match self.transport.write_message(part, &mut message) {
+                Ok(l) => l,
+                Err(e) => return Err(io::Error::other(format!("{:?}", e))),
+            };
+            write_raw_message(&mut self.stream, &message[..len])?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+/// Creates the handshake state with a new static key and signs that key with the identity of
+/// the local peer at `local`
+fn start_handshake(
+    local: &SocketAddr,
+    initiator: bool,
+) -> Result<(HandshakeState, Vec<u8>), String> {
+    let params = match NOISE_PARAMS.parse() {
+        Ok(p) => p,
+        Err(e) => return Err(format!("Invalid noise parameters: {:?}", e)),
+    };
+    let builder = Builder::new(params);
+    let keypair = noise_result(builder.generate_keypair())?;
+    let mut signed = BINDING_PREFIX.to_vec();
+    signed.extend_from_slice(&keypair.public);
+    let (public_key, signature) = sign(local, &signed)?;
+    let binding = match bincode::serialize(&KeyBinding {
+        public_key,
+        signature,
+    }) {
+        Ok(b) => b,
+        Err(e) => return Err(format!("Could not serialize the key binding: {:?}", e)),
+    };
+    let builder = builder.local_private_key(&keypair.private);
+    let handshake = if initiator {
+        builder.build_initiator()
+    } else {
+        builder.build_responder()
+    };
+    Ok((noise_result(handshake)?, binding))
+}
+
+/// Checks that the static key of the remote peer was signed by the identity it sent
+/// # Returns
+/// The `PeerId` of the remote peer
+fn check_binding(handshake: &HandshakeState, payload: &[u8]) -> Result<PeerId, String> {
+    let remote_static = match handshake.get_remote_static() {
+        Some(k) => k,
+        None => return Err("The remote peer did not send a static key".to_string()),
+    };
+    let binding: KeyBinding = match bincode::deserialize(payload) {
+        Ok(b) => b,
+        Err(e) => return Err(format!("Invalid key binding: {:?}", e)),
+    };
+    let mut signed = BINDING_PREFIX.to_vec();
+    signed.extend_from_slice(remote_static);
+    verify(&binding.public_key, &signed, &binding.signature)
+}
+
+fn noise_result<T>(result: Result<T, snow::Error>) -> Result<T, String> {
+    match result {
+        Ok(v) => Ok(v),
+        Err(e) => Err(format!("Noise handshake failed: {:?}", e)),
+    }
+}
+
+/// Every Noise message is sent with its length as big endian `u16` in front
+fn write_raw_message(stream: &mut TcpStream, message: &[u8]) -> io::Result<()> {
+    stream.write_all(&(message.len() as u16).to_be_bytes())?;
+    stream.write_all(message)
+}
+
+fn read_raw_message(stream: &mut TcpStream) -> io::Result<Vec<u8>> {
+    let mut length = [0u8; 2];
+    stream.read_exact(&mut length)?;
+    let mut message = vec![0u8; u16::from_be_bytes(length) as usize];
+    stream.read_exact(&mut message)?;
+    Ok(message)
+}
+
+fn send_message(stream: &mut TcpStream, message: &[u8]) -> Result<(), String> {
+    match write_raw_message(stream, message) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("Could not send handshake message: {:?}", e)),
+    }
+}
+
+fn receive_message(stream: &mut TcpStream) -> Result<Vec<u8>, String> {
+    match read_raw_message(stream) {
+        Ok(m) => Ok(m),
+        Err(e) => Err(format!("Could not receive handshake message: {:?}", e)),
+    }
+}
+
+/// A connection between the local peer at `local` and another peer. It is encrypted unless the
+/// local peer runs without encryption.
+pub enum Connection {
+    Plain(TcpStream),
+    Secure(SecureStream),
+}
+
+impl Connection {
+    /// Opens a connection to `target` for the local peer at `local`
+    pub fn connect(target: SocketAddr, local: &SocketAddr) -> Result<Connection, String> {
+        match TcpStream::connect_timeout(&target, CONNECT_TIMEOUT) {
+            Ok(stream) => Connection::open(stream, local, true),
+            Err(_e) => Err(format!("Failed to connect to {:?}", target)),
+        }
+    }
+
+    /// Runs the handshake on `stream`, as the peer that opened it if `initiator` is `true`
+    pub fn open(
+        stream: TcpStream,
+        local: &SocketAddr,
+        initiator: bool,
+    ) -> Result<Connection, String> {
+        if let Err(e) = stream.set_read_timeout(Some(READ_TIMEOUT)) {
+            return Err(format!("Could not set the read timeout: {:?}", e));
+        }
+        if is_plaintext(local) {
+            return Ok(Connection::Plain(stream));
+        }
+        if initiator {
+            SecureStream::initiate(stream, local).map(Connection::Secure)
+        } else {
+            SecureStream::accept(stream, local).map(Connection::Secure)
+        }
+    }
+
+    /// Sends `notification` over the connection
+    pub fn send(&mut self, notification: &Notification) -> Result<(), String> {
+        match self {
+            Connection::Plain(stream) => write_notification(stream, notification),
+            Connection::Secure(stream) => write_notification(stream, notification),
+        }
+    }
+
+    /// Receives the next notification. Notifications over an encrypted connection have to be
+    /// signed by the peer that was authenticated in the handshake.
+    pub fn receive(&mut self) -> Result<Notification, String> {
+        let stream = match self {
+            Connection::Plain(stream) => return read_notification(stream),
+            Connection::Secure(stream) => stream,
+        };
+        let notification = read_notification(stream)?;
+        if notification.signer.as_ref() != Some(stream.remote()) {
+            return Err(format!(
+                "Notification over the connection of {} was signed by another peer",
+                stream.remote()
+            ));
+        }
+        Ok(notification)
+    }
+}
+
+/// Sends `notification` over `stream`, the connection is only used for this notification
+pub fn send_notification(stream: TcpStream, notification: &Notification) -> Result<(), String> {
+    Connection::open(stream, &notification.from, true)?.send(notification)
+}
+
+/// The connection a `Session` currently uses
+struct OpenSession {
+    target: SocketAddr,
+    connection: Connection,
+    last_used: Instant,
+}
+
+/// Carries many notifications to one peer over one connection, e.g. the chunks of a transfer, so
+/// the handshake is only run once. A new connection is opened once the current one failed or was
+/// idle for `SESSION_IDLE_TIMEOUT`. Clones share the connection.
+#[derive(Clone, Default)]
+pub struct Session {
+    open: Arc<Mutex<Option<OpenSession>>>,
+}
+
+impl Session {
+    fn lock(&self) -> MutexGuard<'_, Option<OpenSession>> {
+        match self.open.lock() {
+            Ok(o) => o,
+            Err(e) => e.into_inner(),
+        }
+    }
+
+    /// Sends `notification` to `target`. A connection to another target is closed first.
+    pub fn send(&self, target: SocketAddr, notification: &Notification) -> Result<(), String> {
+        let mut open = self.lock();
+        if let Some(o) = open.as_mut() {
+            if o.target == target
+                && o.last_used.elapsed() < SESSION_IDLE_TIMEOUT
+                && o.connection.send(notification).is_ok()
+            {
+                o.last_used = Instant::now();
+                return Ok(());
+            }
+        }
+        *open = None;
+        let mut connection = Connection::connect(target, &notification.from)?;
+        connection.send(notification)?;
+        *open = Some(OpenSession {
+            target,
+            connection,
+            last_used: Instant::now(),
+        });
+        Ok(())
+    }
+
+    /// Checks if the session has no connection that could still be used
+    pub fn is_idle(&self) -> bool {
+        self.lock()
+            .as_ref()
+            .is_none_or(|o| o.last_used.elapsed() >= SESSION_IDLE_TIMEOUT)
+    }
+}
@@ -1,9 +1,100 @@
+use crate::metadata::SongMetadata;
 use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
 use std::time;
 
 /// The sleep duration for the heartbeat thread.
 pub const HEARTBEAT_SLEEP_DURATION: time::Duration = time::Duration::from_secs(100);
 
+/// The sleep duration for the maintenance thread, which retries stalled transfers.
+pub const MAINTENANCE_SLEEP_DURATION: time::Duration = time::Duration::from_secs(2);
+
+/// The sleep duration for the gossip thread, which passes the tombstones of removed songs on.
+pub const GOSSIP_SLEEP_DURATION: time::Duration = time::Duration::from_secs(30);
+
+/// The sleep duration for the catalog thread, which passes the songs every peer holds on.
+pub const CATALOG_SLEEP_DURATION: time::Duration = time::Duration::from_secs(20);
+
+/// The sleep duration for the anti-entropy thread, which compares the songs of the local peer
+/// with the ones of another peer.
+pub const ANTI_ENTROPY_SLEEP_DURATION: time::Duration = time::Duration::from_secs(60);
+
+/// The sleep duration for the scrubber thread, which re-verifies the stored songs.
+pub const SCRUB_SLEEP_DURATION: time::Duration = time::Duration::from_secs(600);
+
+/// The directory the local database is stored in if no other directory is configured.
+pub const DEFAULT_DATA_DIR: &str = "data";
+
+/// The number of peers that keep a copy of every song if no other factor is configured.
+pub const DEFAULT_REPLICATION_FACTOR: usize = 3;
+
+/// The size in bytes a received song may have at most if no other limit is configured.
+pub const DEFAULT_MAX_SONG_SIZE: u64 = 512 * 1024 * 1024;
+
+/// The storage engine the local database uses for the songs.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum StorageKind {
+    /// Songs are only held in memory and are lost when the peer stops. Nothing is written to the
+    /// data directory, the peer gets a new identity on every start.
+    Memory,
+    /// Every song is stored in its own file in the data directory
+    Filesystem,
+    /// Like `Filesystem`, but songs are read through memory maps
+    MemoryMapped,
+}
+
+/// How peers find each other and the songs in the network.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum RoutingMode {
+    /// Every peer knows every other peer, songs are placed on a consistent hash ring
+    FullMesh,
+    /// Every peer only knows a bounded routing table of peers, songs and peers are found with
+    /// iterative Kademlia lookups. All peers of a network have to use the same mode.
+    Dht,
+}
+
+/// Configuration of the library that is passed to `interface::start`.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Directory in which the songs, the holders table, the identity and the network secret of
+    /// the local peer are persisted. Songs stored there are loaded again on the next start.
+    pub data_dir: PathBuf,
+    /// The storage engine of the local database
+    pub storage: StorageKind,
+    /// Number of peers on the hash ring that keep a copy of every song. Lost copies are replaced
+    /// automatically.
+    pub replication_factor: usize,
+    /// How peers and songs are found in the network
+    pub routing: RoutingMode,
+    /// Turns the encryption of all connections off. Only meant for debugging, a peer without
+    /// encryption can not talk to the peers that use it.
+    pub insecure: bool,
+    /// Invite token that is presented when joining a network. Former members of the network can
+    /// join again without one.
+    pub invite: Option<String>,
+    /// Bytes of songs the local peer stores for the network at most, `None` for no limit. Peers
+    /// that join move songs to the new peer only as long as it has room for them.
+    pub capacity: Option<u64>,
+    /// Size in bytes a song may have at most. Offers of larger songs are rejected before any
+    /// space is allocated for them.
+    pub max_song_size: u64,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            data_dir: PathBuf::from(DEFAULT_DATA_DIR),
+            storage: StorageKind::Filesystem,
+            replication_factor: DEFAULT_REPLICATION_FACTOR,
+            routing: RoutingMode::FullMesh,
+            insecure: false,
+            invite: None,
+            capacity: None,
+            max_song_size: DEFAULT_MAX_SONG_SIZE,
+        }
+    }
+}
+
 /// Enum to communicate file instructions to the library.
 #[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
 pub enum FileInstructions {
@@ -21,14 +112,25 @@ pub enum FileStatus {
     DOWNLOAD,
 }
 
+/// Enum to get the details for the `file_lookup_finished` function in `AppListener`
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+pub enum LookupResult {
+    /// The file is stored locally or was received from a peer
+    FOUND,
+    /// All asked peers answered that they do not have the file or could not send it
+    MISSING,
+    /// Not all asked peers answered before the deadline
+    TIMEOUT,
+}
+
 /// The trait that needs to be implemented for the listener in the application that uses this
 /// library. Its functions are used to communicate network events.
 pub trait AppListener: Send {
     /// Notify the application that a status response was received.
     /// # Parameters
-    /// - `files` the files from the peer that sent the response
+    /// - `files` the files from the peer that sent the response with their tags
     /// - `name` the name of the peer that sent the response
-    fn notify_status(&self, files: Vec<String>, name: String);
+    fn notify_status(&self, files: Vec<(String, SongMetadata)>, name: String);
     /// Notify if a file was changed in the local peer database or was downloaded
     fn local_database_changed(&mut self, name: String, instr: FileStatus);
     /// Notify the application that the player started playing
@@ -37,4 +139,36 @@ pub trait AppListener: Send {
     fn player_playing(&mut self, title: Option<String>);
     /// Notify the application that the player has stopped (no other song in queue)
     fn player_stopped(&mut self);
+    /// Notify the application about a buffer underrun while a song is streamed
+    /// # Parameters
+    /// - `title`: The name of the streamed song
+    /// - `buffering`: `true` if the playback waits for the network, `false` once it continues
+    fn stream_buffering(&mut self, title: String, buffering: bool);
+    /// Notify the application that a peer refused to remove a song
+    /// # Parameters
+    /// - `title`: The name of the song
+    /// - `reason`: Why the removal was refused
+    fn delete_rejected(&mut self, title: String, reason: String);
+    /// Notify the application about the progress of leaving the network
+    /// # Parameters
+    /// - `handed_off`: The number of songs other peers confirmed
+    /// - `total`: The number of songs that are handed off
+    fn leave_progress(&mut self, handed_off: usize, total: usize);
+    /// Notify the application that leaving the network finished. The library does not exit the
+    /// process, the application decides what to do.
+    /// # Parameters
+    /// - `result`: `Err` with the reason if not all songs could be handed off, the peer then
+    ///   stays in the network
+    fn left_network(&mut self, result: Result<(), String>);
+    /// Notify the application about the result of looking a file up in the network
+    /// # Parameters
+    /// - `title`: The name of the song
+    /// - `instr`: What is done with the song once it is found
+    /// - `result`: Whether a peer has the song
+    fn file_lookup_finished(
+        &mut self,
+        title: String,
+        instr: FileInstructions,
+        result: LookupResult,
+    );
 }